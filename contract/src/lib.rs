@@ -1,17 +1,29 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::wee_alloc;
-use near_sdk::{env, ext_contract, near_bindgen, PromiseResult};
-use orderbook::{orders, Failed, OrderIndex, OrderSide, Orderbook, Success};
+use near_sdk::{env, ext_contract, near_bindgen, Promise, PromiseResult};
+use orderbook::{
+    orders, BatchMode, BookSnapshot, DepthPoint, Failed, FeeAsset, FeeSchedule, FillConstraint,
+    Impact, Order, OrderIndex, OrderSide, Orderbook, PartialFillAction, Peg, Quote, Success, Trail,
+    SCHEMA_VERSION,
+};
 
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
 const SINGLE_CALL_GAS: u64 = 20_000_000_000_000; // 2 x 10^14
 const TRANSFER_FROM_NEAR_COST: u128 = 36_500_000_000_000_000_000_000; // 365 x 10^20
+const RESUME_GAS_MARGIN: u64 = 20_000_000_000_000; // 2 x 10^13
 
 #[allow(non_camel_case_types)]
-#[derive(PartialEq, Eq, Debug, Copy, Clone, BorshDeserialize, BorshSerialize)]
+#[derive(
+    PartialEq, Eq, Debug, Copy, Clone, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
+#[serde(crate = "near_sdk::serde")]
 pub enum Asset {
     nBook,
     nDAI,
@@ -31,6 +43,13 @@ fn parse_side(side: &str) -> Option<OrderSide> {
     }
 }
 
+fn side_name(side: OrderSide) -> String {
+    match side {
+        OrderSide::Ask => "Ask".to_string(),
+        OrderSide::Bid => "Bid".to_string(),
+    }
+}
+
 fn get_token_account(side: OrderSide) -> String {
     match side {
         OrderSide::Ask => "nbook.hacker.testnet".to_string(),
@@ -38,10 +57,129 @@ fn get_token_account(side: OrderSide) -> String {
     }
 }
 
+/// Token in which the fee is charged: the one received by the filled side
+fn get_fee_token_account(fee_asset: FeeAsset) -> String {
+    match fee_asset {
+        FeeAsset::OrderAsset => get_token_account(OrderSide::Ask),
+        FeeAsset::PriceAsset => get_token_account(OrderSide::Bid),
+    }
+}
+
 fn get_current_time() -> u64 {
     return env::block_timestamp();
 }
 
+/// Quantity of the new order which is rejected as a whole, e.g. during
+/// trading halt or when retried with the same client order ID.
+///
+/// Rest of an accepted order which isn't stored is reported as unfilled.
+fn rejected_quantity(res: &[Result<Success, Failed>], quantity: u128) -> u128 {
+    if res
+        .iter()
+        .any(|result| matches!(result, Ok(Success::Accepted { .. })))
+    {
+        0
+    } else {
+        quantity
+    }
+}
+
+/// Non-zero token deposits for a batch, asks first
+fn batch_deposits(ask_deposit: u128, bid_deposit: u128) -> Vec<(OrderSide, u128)> {
+    vec![(OrderSide::Ask, ask_deposit), (OrderSide::Bid, bid_deposit)]
+        .into_iter()
+        .filter(|deposit| deposit.1 > 0)
+        .collect()
+}
+
+/// Optional settings of a new limit order.
+///
+/// Client order ID, unique per signer, makes retried submissions safe.
+/// Hidden orders are not displayed in the book. Pegged orders follow the
+/// best prices, `price` is used until the peg reference is available.
+/// Constrained orders are all-or-none or filled by a minimum quantity.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LimitOrderOptions {
+    client_order_id: Option<String>,
+    hidden: Option<bool>,
+    peg: Option<Peg>,
+    constraint: Option<FillConstraint>,
+}
+
+impl LimitOrderOptions {
+    fn apply(&self, order: orders::OrderRequest<Asset>) -> orders::OrderRequest<Asset> {
+        let mut order = order;
+        if let Some(client_order_id) = &self.client_order_id {
+            order = order.with_client_order_id(client_order_id.clone());
+        }
+        if self.hidden == Some(true) {
+            order = order.with_hidden();
+        }
+        if let Some(peg) = self.peg {
+            order = order.with_peg(peg);
+        }
+        if let Some(constraint) = self.constraint {
+            order = order.with_constraint(constraint);
+        }
+        order
+    }
+}
+
+/// Stop-loss leg of a new OCO pair.
+///
+/// It becomes a limit order at `limit_price` once triggered, a market order
+/// if it is not set. A partial fill of one leg cancels the other one unless
+/// `on_partial_fill` says otherwise.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StopLoss {
+    stop: Trail,
+    limit_price: Option<f64>,
+    on_partial_fill: Option<PartialFillAction>,
+}
+
+impl StopLoss {
+    fn apply(&self, order: orders::OrderRequest<Asset>) -> orders::OrderRequest<Asset> {
+        let mut order = order;
+        if let Some(limit_price) = self.limit_price {
+            order = order.with_limit_price(limit_price);
+        }
+        if let Some(on_partial_fill) = self.on_partial_fill {
+            order = order.with_partial_fill_action(on_partial_fill);
+        }
+        order
+    }
+}
+
+/// Single order change inside of a batch
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum BatchOrder {
+    New {
+        price: f64,
+        quantity: u128,
+        side: String,
+        options: Option<LimitOrderOptions>,
+    },
+    Amend {
+        id: u64,
+        price: f64,
+        quantity: u128,
+        side: String,
+    },
+    Cancel {
+        id: u64,
+        side: String,
+    },
+    CancelReplace {
+        id: u64,
+        price: f64,
+        quantity: u128,
+        side: String,
+    },
+}
+
 /// External Fungible token contract
 #[ext_contract(ext_fungible_token)]
 pub trait ExtFunToken {
@@ -51,15 +189,28 @@ pub trait ExtFunToken {
 
 #[ext_contract(ext_this_contract)]
 pub trait ExtSimulation {
-    fn post_transfer(&mut self, price: f64, quantity: u128, side: String);
+    fn post_transfer(
+        &mut self,
+        price: f64,
+        quantity: u128,
+        side: String,
+        options: Option<LimitOrderOptions>,
+    );
+    fn post_batch(&mut self, orders: Vec<BatchOrder>, ask_deposit: u128, bid_deposit: u128);
+    fn post_oco(&mut self, price: f64, quantity: u128, side: String, stop_loss: StopLoss);
+    fn resume_matching(&mut self);
 }
 
+/// Market state. There is no migration between versions, an upgrade
+/// requires a redeploy with fresh state (see README).
 #[near_bindgen]
 #[derive(Default, BorshDeserialize, BorshSerialize)]
 pub struct Market {
     market_order_book: Orderbook<Asset>,
     order_asset: Asset,
     price_asset: Asset,
+    // net of fees and rebates, per token account
+    collected_fees: HashMap<String, i128>,
 }
 
 #[near_bindgen]
@@ -70,10 +221,31 @@ impl Market {
             market_order_book: Orderbook::new(Asset::nBook, Asset::nDAI),
             order_asset: Asset::nBook,
             price_asset: Asset::nDAI,
+            collected_fees: HashMap::new(),
         }
     }
 
-    pub fn new_limit_order(&mut self, price: f64, quantity: u128, side: String) {
+    /// Place limit order once the tokens are escrowed, see `LimitOrderOptions`
+    /// for optional settings.
+    pub fn new_limit_order(
+        &mut self,
+        price: f64,
+        quantity: u128,
+        side: String,
+        options: Option<LimitOrderOptions>,
+    ) {
+        let client_order_id = options
+            .as_ref()
+            .and_then(|options| options.client_order_id.as_ref());
+        if let Some(client_order_id) = client_order_id {
+            assert!(
+                !self
+                    .market_order_book
+                    .is_client_order_id_used(&env::signer_account_id(), client_order_id),
+                "Client order ID is already used."
+            );
+        }
+
         ext_fungible_token::transfer_from(
             env::signer_account_id(),
             env::current_account_id(),
@@ -86,42 +258,332 @@ impl Market {
             price,
             quantity,
             side,
+            options,
+            &env::current_account_id(),
+            0,
+            250000000000000,
+        ));
+    }
+
+    /// Place take-profit limit order at `price` linked with a stop-loss order.
+    ///
+    /// Both legs are escrowed, escrow of the leg cancelled or reduced by a
+    /// fill of the other one is returned. Any fill cancels the other leg
+    /// unless `on_partial_fill` is `ReduceOther`.
+    pub fn new_oco_order(
+        &mut self,
+        price: f64,
+        quantity: u128,
+        side: String,
+        stop: Trail,
+        stop_limit_price: Option<f64>,
+        on_partial_fill: Option<PartialFillAction>,
+    ) {
+        // both legs are escrowed
+        let escrow = quantity
+            .checked_mul(2)
+            .unwrap_or_else(|| env::panic(b"Order quantity is out of range."));
+        ext_fungible_token::transfer_from(
+            env::signer_account_id(),
+            env::current_account_id(),
+            U128(escrow),
+            &get_token_account(parse_side(&side).unwrap()),
+            TRANSFER_FROM_NEAR_COST,
+            SINGLE_CALL_GAS,
+        )
+        .then(ext_this_contract::post_oco(
+            price,
+            quantity,
+            side,
+            StopLoss {
+                stop,
+                limit_price: stop_limit_price,
+                on_partial_fill,
+            },
             &env::current_account_id(),
             0,
             250000000000000,
         ));
     }
 
+    /// Apply new orders, amends and cancels at once, all or nothing.
+    ///
+    /// Tokens are escrowed (or returned) by a single net transfer per token.
+    pub fn batch_orders(&mut self, orders: Vec<BatchOrder>) {
+        let (ask_escrow, bid_escrow) = self
+            .batch_escrow(&orders)
+            .unwrap_or_else(|reason| env::panic(reason.as_bytes()));
+        let ask_deposit = ask_escrow.max(0) as u128;
+        let bid_deposit = bid_escrow.max(0) as u128;
+
+        let mut deposit: Option<Promise> = None;
+        for (side, amount) in batch_deposits(ask_deposit, bid_deposit) {
+            let transfer = ext_fungible_token::transfer_from(
+                env::signer_account_id(),
+                env::current_account_id(),
+                U128(amount),
+                &get_token_account(side),
+                TRANSFER_FROM_NEAR_COST,
+                SINGLE_CALL_GAS,
+            );
+            deposit = Some(match deposit {
+                Some(previous) => previous.and(transfer),
+                None => transfer,
+            });
+        }
+
+        let callback = ext_this_contract::post_batch(
+            orders,
+            ask_deposit,
+            bid_deposit,
+            &env::current_account_id(),
+            0,
+            250000000000000,
+        );
+        if let Some(deposit) = deposit {
+            deposit.then(callback);
+        }
+    }
+
+    /// Replace active order with a new one in a single transaction.
+    ///
+    /// Nothing is changed if the order is already filled.
+    pub fn cancel_replace_order(&mut self, id: u64, price: f64, quantity: u128, side: String) {
+        self.batch_orders(vec![BatchOrder::CancelReplace {
+            id,
+            price,
+            quantity,
+            side,
+        }]);
+    }
+
+    /// Cancel signer's order or stop, its escrow is returned
     pub fn cancel_limit_order(&mut self, id: u64, side: String) -> Vec<Result<Success, Failed>> {
-        let order = orders::limit_order_cancel_request(id, parse_side(&side).unwrap());
+        let side = parse_side(&side).unwrap();
+        let signer = env::signer_account_id();
+        let own_stop =
+            self.market_order_book.trailing_stops().iter().any(|stop| {
+                stop.order_id == id && stop.side == side && stop.order_creator == signer
+            });
+        if !own_stop && !self.market_order_book.is_owned_by(id, side, &signer) {
+            return vec![Err(Failed::OrderNotFound(id))];
+        }
+
+        let order = orders::limit_order_cancel_request(id, side);
+
+        let res = self.market_order_book.process_order(order);
 
-        self.market_order_book.process_order(order)
+        self.refund_cancelled(&res);
+        self.process_orderbook_result(res)
+    }
+
+    pub fn cancel_order_by_client_id(
+        &mut self,
+        client_order_id: String,
+    ) -> Vec<Result<Success, Failed>> {
+        let res = self
+            .market_order_book
+            .cancel_client_order(&env::signer_account_id(), &client_order_id);
+
+        self.refund_cancelled(&res);
+        self.process_orderbook_result(res)
+    }
+
+    /// Amend signer's active order, escrow difference is settled as in a batch
+    pub fn amend_order_by_client_id(
+        &mut self,
+        client_order_id: String,
+        price: f64,
+        quantity: u128,
+    ) {
+        let (id, side) = match self
+            .market_order_book
+            .client_order(&env::signer_account_id(), &client_order_id)
+        {
+            Some(order) => (order.order_id, order.side),
+            None => env::panic(b"No active order with this client order ID."),
+        };
+        self.batch_orders(vec![BatchOrder::Amend {
+            id,
+            price,
+            quantity,
+            side: side_name(side),
+        }]);
+    }
+
+    pub fn get_order_by_client_id(
+        &self,
+        account_id: String,
+        client_order_id: String,
+    ) -> Option<Order<Asset>> {
+        self.market_order_book
+            .client_order(&account_id, &client_order_id)
+            .cloned()
+    }
+
+    /// Cancel all signer's orders, optionally only on one side and within price range.
+    ///
+    /// Released escrow is returned with a single transfer per token.
+    pub fn mass_cancel(
+        &mut self,
+        side: Option<String>,
+        min_price: Option<f64>,
+        max_price: Option<f64>,
+    ) -> Vec<Result<Success, Failed>> {
+        let price_range = match (min_price, max_price) {
+            (None, None) => None,
+            (min_price, max_price) => {
+                Some((min_price.unwrap_or(0.0), max_price.unwrap_or(f64::MAX)))
+            }
+        };
+        let order = orders::mass_cancel_request(
+            env::signer_account_id(),
+            side.map(|side| parse_side(&side).unwrap()),
+            price_range,
+        );
+
+        let res = self.market_order_book.process_order(order);
+
+        self.refund_cancelled(&res);
+        self.process_orderbook_result(res)
+    }
+
+    /// Version of JSON representation of orderbook requests and results
+    pub fn get_schema_version(&self) -> u32 {
+        SCHEMA_VERSION
     }
 
     pub fn get_ask_orders(&self) -> Vec<OrderIndex> {
-        self.market_order_book.ask_queue.clone().idx_queue.unwrap()
+        // the best order goes last
+        let mut orders = self.market_order_book.ask_queue.indices();
+        orders.reverse();
+        orders
     }
 
     pub fn get_bid_orders(&self) -> Vec<OrderIndex> {
-        self.market_order_book.bid_queue.clone().idx_queue.unwrap()
+        // the best order goes last
+        let mut orders = self.market_order_book.bid_queue.indices();
+        orders.reverse();
+        orders
+    }
+
+    /// Aggregated price levels, the best first, and the sequence number of
+    /// the last book delta logged before them
+    pub fn get_book_snapshot(&self, levels: u64) -> BookSnapshot {
+        self.market_order_book.snapshot(levels as usize)
+    }
+
+    /// What the limit order of `account_id` would do if placed now: fills,
+    /// average price, remaining quantity and fees. Nothing is changed.
+    pub fn quote_order(
+        &self,
+        account_id: String,
+        price: f64,
+        quantity: u128,
+        side: String,
+        constraint: Option<FillConstraint>,
+    ) -> Quote {
+        let mut order = orders::new_limit_order_request(
+            self.order_asset,
+            self.price_asset,
+            parse_side(&side).unwrap(),
+            price,
+            quantity,
+            account_id,
+            get_current_time(),
+        );
+        if let Some(constraint) = constraint {
+            order = order.with_constraint(constraint);
+        }
+
+        self.market_order_book.quote(order)
+    }
+
+    /// Cumulative quantity and nDAI notional of the best displayed levels of the side
+    pub fn get_depth_curve(&self, side: String, levels: u64) -> Vec<DepthPoint> {
+        self.market_order_book
+            .depth_curve(parse_side(&side).unwrap(), levels as usize)
+    }
+
+    /// Estimated cost of taking `quantity` nBook on the side (`Bid` buys):
+    /// notional, average price and slippage in basis points
+    pub fn get_market_impact(&self, side: String, quantity: u128) -> Impact {
+        self.market_order_book
+            .market_impact(parse_side(&side).unwrap(), quantity)
+    }
+
+    /// nBook which can be taken on the side (`Ask` sells) before the price
+    /// moves by more than `max_move_pct` percents
+    pub fn get_quantity_within(&self, side: String, max_move_pct: f64) -> u128 {
+        self.market_order_book
+            .qty_within(parse_side(&side).unwrap(), max_move_pct)
     }
 
     pub fn get_current_spread(&self) -> Vec<f64> {
-        if let Some((bid, ask)) = self.market_order_book.clone().current_spread() {
+        if let Some((bid, ask)) = self.market_order_book.current_spread() {
             vec![ask, bid]
         } else {
             vec![0.0, 0.0]
         }
     }
 
-    pub fn post_transfer(&mut self, price: f64, quantity: u128, side: String) {
+    /// Set maker and taker fees in basis points, negative maker fee is a rebate
+    pub fn set_fee_schedule(&mut self, maker_bps: i32, taker_bps: i32) {
+        self._only_owner_predecessor();
+        let fees = FeeSchedule::new(maker_bps, taker_bps)
+            .unwrap_or_else(|error| env::panic(error.to_string().as_bytes()));
+        self.market_order_book.set_fee_schedule(fees);
+    }
+
+    pub fn get_fee_schedule(&self) -> FeeSchedule {
+        self.market_order_book.fee_schedule()
+    }
+
+    /// Fees collected per token account, net of paid rebates
+    pub fn get_collected_fees(&self) -> Vec<(String, i128)> {
+        let mut fees: Vec<(String, i128)> = self
+            .collected_fees
+            .iter()
+            .map(|(token_account, fee)| (token_account.clone(), *fee))
+            .collect();
+        fees.sort();
+        fees
+    }
+
+    pub fn start_auction(&mut self) {
+        self._only_owner_predecessor();
+        self.market_order_book.start_auction();
+    }
+
+    pub fn get_indicative_uncross(&self) -> Vec<f64> {
+        if let Some((price, volume)) = self.market_order_book.indicative_uncross() {
+            vec![price, volume as f64]
+        } else {
+            vec![0.0, 0.0]
+        }
+    }
+
+    pub fn uncross_auction(&mut self) -> Vec<Result<Success, Failed>> {
+        self._only_owner_predecessor();
+        let res = self.market_order_book.uncross();
+
+        self.process_orderbook_result(res)
+    }
+
+    pub fn post_transfer(
+        &mut self,
+        price: f64,
+        quantity: u128,
+        side: String,
+        options: Option<LimitOrderOptions>,
+    ) {
         self._only_owner_predecessor();
         assert_eq!(env::promise_results_count(), 1);
         match env::promise_result(0) {
             PromiseResult::Successful(_) => {
                 env::log(b"Token Transfer Successful.");
 
-                let order = orders::new_limit_order_request(
+                let mut order = orders::new_limit_order_request(
                     self.order_asset,
                     self.price_asset,
                     parse_side(&side).unwrap(),
@@ -130,9 +592,17 @@ impl Market {
                     env::signer_account_id(),
                     get_current_time(),
                 );
+                if let Some(options) = options {
+                    order = options.apply(order);
+                }
 
                 let res = self.market_order_book.process_order(order);
 
+                let rejected = rejected_quantity(&res, quantity);
+                if rejected > 0 {
+                    self.refund(&[(parse_side(&side).unwrap(), rejected)]);
+                }
+
                 self.process_orderbook_result(res)
             }
             PromiseResult::Failed => {
@@ -142,12 +612,317 @@ impl Market {
         };
     }
 
+    pub fn post_oco(
+        &mut self,
+        price: f64,
+        quantity: u128,
+        side: String,
+        stop_loss: StopLoss,
+    ) -> Vec<Result<Success, Failed>> {
+        self._only_owner_predecessor();
+        assert_eq!(env::promise_results_count(), 1);
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => {
+                env::log(b"Token Transfer Successful.");
+
+                let side = parse_side(&side).unwrap();
+                let order = stop_loss.apply(orders::new_oco_request(
+                    self.order_asset,
+                    self.price_asset,
+                    side,
+                    quantity,
+                    price,
+                    stop_loss.stop,
+                    env::signer_account_id(),
+                    get_current_time(),
+                ));
+
+                let res = self.market_order_book.process_order(order);
+
+                let accepted = res
+                    .iter()
+                    .any(|result| matches!(result, Ok(Success::Accepted { .. })));
+                if !accepted {
+                    self.refund(&[(side, 2 * quantity)]);
+                }
+
+                self.process_orderbook_result(res)
+            }
+            PromiseResult::Failed => {
+                env::panic(b"(post_oco) The promise failed. See receipt failures.")
+            }
+            PromiseResult::NotReady => env::panic(b"The promise was not ready."),
+        }
+    }
+
+    pub fn post_batch(
+        &mut self,
+        orders: Vec<BatchOrder>,
+        ask_deposit: u128,
+        bid_deposit: u128,
+    ) -> Vec<Vec<Result<Success, Failed>>> {
+        self._only_owner_predecessor();
+        let deposits = batch_deposits(ask_deposit, bid_deposit);
+        assert_eq!(env::promise_results_count(), deposits.len() as u64);
+
+        let mut deposited = vec![];
+        for (i, deposit) in deposits.iter().enumerate() {
+            match env::promise_result(i as u64) {
+                PromiseResult::Successful(_) => deposited.push(*deposit),
+                PromiseResult::Failed => {}
+                PromiseResult::NotReady => env::panic(b"The promise was not ready."),
+            };
+        }
+
+        if deposited.len() < deposits.len() {
+            env::log(b"(post_batch) Token transfer failed, batch rejected.");
+            self.refund(&deposited);
+            return vec![];
+        }
+
+        // book could change while tokens were transferred
+        let (ask_escrow, bid_escrow) = match self.batch_escrow(&orders) {
+            Ok(escrow) if escrow.0 <= ask_deposit as i128 && escrow.1 <= bid_deposit as i128 => {
+                escrow
+            }
+            _ => {
+                env::log(b"(post_batch) Escrow changed, batch rejected.");
+                self.refund(&deposits);
+                return vec![];
+            }
+        };
+
+        let requests = orders
+            .iter()
+            .map(|order| self.batch_order_request(order))
+            .collect();
+        let res = self
+            .market_order_book
+            .process_batch(requests, BatchMode::AllOrNothing);
+
+        let rejected = res
+            .iter()
+            .flatten()
+            .any(|result| matches!(result, Err(Failed::BatchRejected)));
+        if rejected {
+            self.refund(&deposits);
+        } else {
+            // return released escrow
+            self.refund(&[
+                (OrderSide::Ask, (ask_deposit as i128 - ask_escrow) as u128),
+                (OrderSide::Bid, (bid_deposit as i128 - bid_escrow) as u128),
+            ]);
+        }
+
+        self.log_book_deltas();
+        let mut suspended = false;
+        for result in &res {
+            suspended |= self.settle_results(result);
+        }
+        if suspended {
+            self.resume_later();
+        }
+
+        res
+    }
+
+    pub fn resume_matching(&mut self) -> Vec<Result<Success, Failed>> {
+        self._only_owner_predecessor();
+        let res = self.market_order_book.resume_matching();
+
+        self.log_book_deltas();
+        // other suspended orders may still wait
+        if self.settle_results(&res) || self.market_order_book.pending_orders() > 0 {
+            self.resume_later();
+        }
+
+        res
+    }
+
+    /// Net escrow change per token for a batch as a tuple: (ask, bid)
+    ///
+    /// Fails if batch changes someone else's order or the same order twice,
+    /// uses the same client order ID twice, or the escrow is out of range.
+    fn batch_escrow(&self, orders: &[BatchOrder]) -> Result<(i128, i128), &'static str> {
+        const NOT_OWNER: &str = "Only order creator can change the order, once per batch.";
+        const OUT_OF_RANGE: &str = "Order quantity is out of range.";
+
+        let signer = env::signer_account_id();
+        let mut changed: Vec<(u64, OrderSide)> = vec![];
+        let mut client_order_ids: Vec<&String> = vec![];
+        let mut escrow = (0i128, 0i128);
+
+        for order in orders {
+            let (side, required, target) = match order {
+                BatchOrder::New {
+                    quantity,
+                    side,
+                    options,
+                    ..
+                } => {
+                    let client_order_id = options
+                        .as_ref()
+                        .and_then(|options| options.client_order_id.as_ref());
+                    if let Some(client_order_id) = client_order_id {
+                        if client_order_ids.contains(&client_order_id) {
+                            return Err("Client order ID is used twice in the batch.");
+                        }
+                        client_order_ids.push(client_order_id);
+                    }
+                    (side, *quantity, None)
+                }
+                BatchOrder::Amend {
+                    id, quantity, side, ..
+                } => (side, *quantity, Some(*id)),
+                BatchOrder::Cancel { id, side } => (side, 0, Some(*id)),
+                BatchOrder::CancelReplace {
+                    id, quantity, side, ..
+                } => (side, *quantity, Some(*id)),
+            };
+            let side = parse_side(side).unwrap();
+
+            let mut released = 0;
+            if let Some(id) = target {
+                if changed.contains(&(id, side)) {
+                    return Err(NOT_OWNER);
+                }
+                changed.push((id, side));
+
+                let order_queue = match side {
+                    OrderSide::Ask => &self.market_order_book.ask_queue,
+                    OrderSide::Bid => &self.market_order_book.bid_queue,
+                };
+                if let Some(resting_order) = order_queue.get(id) {
+                    if resting_order.order_creator != signer {
+                        return Err(NOT_OWNER);
+                    }
+                    released = resting_order.qty;
+                }
+            }
+
+            let required = i128::try_from(required).map_err(|_| OUT_OF_RANGE)?;
+            let released = i128::try_from(released).map_err(|_| OUT_OF_RANGE)?;
+            let side_escrow = match side {
+                OrderSide::Ask => &mut escrow.0,
+                OrderSide::Bid => &mut escrow.1,
+            };
+            *side_escrow = side_escrow
+                .checked_add(required - released)
+                .ok_or(OUT_OF_RANGE)?;
+        }
+
+        Ok(escrow)
+    }
+
+    fn batch_order_request(&self, order: &BatchOrder) -> orders::OrderRequest<Asset> {
+        match order {
+            BatchOrder::New {
+                price,
+                quantity,
+                side,
+                options,
+            } => {
+                let order = orders::new_limit_order_request(
+                    self.order_asset,
+                    self.price_asset,
+                    parse_side(side).unwrap(),
+                    *price,
+                    *quantity,
+                    env::signer_account_id(),
+                    get_current_time(),
+                );
+                match options {
+                    Some(options) => options.apply(order),
+                    None => order,
+                }
+            }
+            BatchOrder::Amend {
+                id,
+                price,
+                quantity,
+                side,
+            } => orders::amend_order_request(
+                *id,
+                parse_side(side).unwrap(),
+                *price,
+                *quantity,
+                get_current_time(),
+            ),
+            BatchOrder::Cancel { id, side } => {
+                orders::limit_order_cancel_request(*id, parse_side(side).unwrap())
+            }
+            BatchOrder::CancelReplace {
+                id,
+                price,
+                quantity,
+                side,
+            } => orders::cancel_replace_request(
+                *id,
+                parse_side(side).unwrap(),
+                *price,
+                *quantity,
+                env::signer_account_id(),
+                get_current_time(),
+            ),
+        }
+    }
+
+    /// Return tokens to the signer, single transfer per token
+    fn refund(&mut self, amounts: &[(OrderSide, u128)]) {
+        self.refund_to(env::signer_account_id(), amounts);
+    }
+
+    /// Return escrow of signer's cancelled orders, a single transfer per token
+    fn refund_cancelled(&mut self, res: &[Result<Success, Failed>]) {
+        let (mut ask_released, mut bid_released) = (0, 0);
+        for result in res {
+            if let Ok(Success::Cancelled { side, qty, .. }) = result {
+                match side {
+                    OrderSide::Ask => ask_released += qty,
+                    OrderSide::Bid => bid_released += qty,
+                }
+            }
+        }
+        self.refund(&[
+            (OrderSide::Ask, ask_released),
+            (OrderSide::Bid, bid_released),
+        ]);
+    }
+
+    fn refund_to(&mut self, account_id: String, amounts: &[(OrderSide, u128)]) {
+        for (side, amount) in amounts {
+            if *amount > 0 {
+                ext_fungible_token::transfer(
+                    account_id.clone(),
+                    U128(*amount),
+                    &get_token_account(*side),
+                    TRANSFER_FROM_NEAR_COST,
+                    SINGLE_CALL_GAS,
+                );
+            }
+        }
+    }
+
     fn process_orderbook_result(
         &mut self,
         order: Vec<Result<Success, Failed>>,
     ) -> Vec<Result<Success, Failed>> {
-        for temp_variable in &order {
-            let success = temp_variable.as_ref().unwrap();
+        self.log_book_deltas();
+        if self.settle_results(&order) {
+            self.resume_later();
+        }
+
+        order
+    }
+
+    /// Settle fills and released escrow, returning whether matching was suspended
+    fn settle_results(&mut self, order: &[Result<Success, Failed>]) -> bool {
+        let mut suspended = false;
+        for temp_variable in order {
+            let success = match temp_variable {
+                Ok(success) => success,
+                Err(_) => continue,
+            };
 
             match success {
                 Success::Accepted {
@@ -162,18 +937,12 @@ impl Market {
                     order_type: _,
                     price: _,
                     qty,
+                    fee,
+                    fee_asset,
                     order_creator,
                     ts: _,
                 } => {
-                    let reverse_side = match side {
-                        OrderSide::Ask => OrderSide::Bid,
-                        OrderSide::Bid => OrderSide::Ask,
-                    };
-                    self.transfer(
-                        get_token_account(reverse_side),
-                        order_creator.to_string(),
-                        *qty,
-                    );
+                    self.settle_fill(*side, *qty, *fee, *fee_asset, order_creator);
                 }
                 Success::PartiallyFilled {
                     order_id: _,
@@ -181,19 +950,12 @@ impl Market {
                     order_type: _,
                     price: _,
                     qty,
+                    fee,
+                    fee_asset,
                     order_creator,
                     ts: _,
                 } => {
-                    let reverse_side = match side {
-                        OrderSide::Ask => OrderSide::Bid,
-                        OrderSide::Bid => OrderSide::Ask,
-                    };
-
-                    self.transfer(
-                        get_token_account(reverse_side),
-                        order_creator.to_string(),
-                        *qty,
-                    );
+                    self.settle_fill(*side, *qty, *fee, *fee_asset, order_creator);
                 }
                 Success::Amended {
                     id: _,
@@ -201,11 +963,117 @@ impl Market {
                     qty: _,
                     ts: _,
                 } => {}
-                Success::Cancelled { id: _, ts: _ } => {}
+                Success::Cancelled {
+                    id: _,
+                    side: _,
+                    qty: _,
+                    ts: _,
+                } => {}
+                Success::TradingHalted {
+                    reference_price: _,
+                    price: _,
+                    until: _,
+                    ts: _,
+                } => {}
+                Success::TradingResumed { ts: _ } => {}
+                Success::AuctionUncrossed {
+                    price: _,
+                    qty: _,
+                    ts: _,
+                } => {}
+                Success::MatchingSuspended {
+                    order_id: _,
+                    qty: _,
+                    ts: _,
+                } => {
+                    suspended = true;
+                }
+                Success::Repriced {
+                    id: _,
+                    side: _,
+                    price: _,
+                    ts: _,
+                } => {}
+                Success::StopTriggered {
+                    id: _,
+                    side: _,
+                    order_type: _,
+                    price: _,
+                    ts: _,
+                } => {}
+                Success::LinkedOrderReduced {
+                    id: _,
+                    side,
+                    qty,
+                    remaining: _,
+                    order_creator,
+                    ts: _,
+                } => {
+                    // released escrow of OCO leg, the signer may be someone else
+                    self.refund_to(order_creator.clone(), &[(*side, *qty)]);
+                }
+                Success::Unfilled {
+                    id: _,
+                    side,
+                    qty,
+                    order_creator,
+                    ts: _,
+                } => {
+                    // rest of a triggered stop, the signer may be someone else
+                    self.refund_to(order_creator.clone(), &[(*side, *qty)]);
+                }
             };
         }
 
-        order
+        suspended
+    }
+
+    /// Continue matching in the next receipt with the gas this call doesn't need.
+    ///
+    /// Only one resume is scheduled per call, which schedules the next one
+    /// while suspended orders remain.
+    fn resume_later(&self) {
+        let gas = env::prepaid_gas()
+            .saturating_sub(env::used_gas())
+            .saturating_sub(RESUME_GAS_MARGIN);
+        ext_this_contract::resume_matching(&env::current_account_id(), 0, gas);
+    }
+
+    /// Log price levels changed by the call, for clients keeping a local book
+    fn log_book_deltas(&mut self) {
+        let deltas = self.market_order_book.take_book_deltas();
+        if !deltas.is_empty() {
+            let deltas = near_sdk::serde_json::to_string(&deltas).unwrap();
+            env::log(format!("BOOK_DELTAS {}", deltas).as_bytes());
+        }
+    }
+
+    /// Pay out tokens received by the filled side net of the fee, and account the fee.
+    ///
+    /// Maker rebate is paid in the token of the taker fee, which may be the other one.
+    fn settle_fill(
+        &mut self,
+        side: OrderSide,
+        qty: u128,
+        fee: i128,
+        fee_asset: FeeAsset,
+        order_creator: &str,
+    ) {
+        let fee_token_account = get_fee_token_account(fee_asset);
+        *self
+            .collected_fees
+            .entry(fee_token_account.clone())
+            .or_insert(0) += fee;
+
+        let token_account = get_fee_token_account(FeeAsset::received_by(side));
+        if token_account == fee_token_account {
+            // fee never exceeds the filled quantity
+            let amount = (qty as i128 - fee) as u128;
+            self.transfer(token_account, order_creator.to_string(), amount);
+        } else {
+            self.transfer(token_account, order_creator.to_string(), qty);
+            self.transfer(fee_token_account, order_creator.to_string(), (-fee) as u128);
+        }
     }
 
     fn transfer(&mut self, token_account: String, order_creator: String, amount: u128) {
@@ -246,6 +1114,7 @@ mod tests {
     use super::*;
     use near_sdk::MockedBlockchain;
     use near_sdk::{testing_env, VMContext};
+    use orderbook::{BandAction, PriceBand, ReferencePrice};
 
     fn get_context(input: Vec<u8>, is_view: bool) -> VMContext {
         VMContext {
@@ -268,6 +1137,168 @@ mod tests {
         }
     }
 
+    #[test]
+    fn rejected_new_order_quantity() {
+        let results = |json: &str| -> Vec<Result<Success, Failed>> {
+            near_sdk::serde_json::from_str(json).unwrap()
+        };
+        let accepted = r#"{"Ok": {"Accepted": {"id": 7, "order_type": "Limit", "order_creator": "prince_near", "ts": 0}}}"#;
+        let fill = r#"{"Ok": {"PartiallyFilled": {"order_id": 7, "side": "Bid", "order_type": "Limit", "price": 1.0, "qty": 3, "fee": 0, "fee_asset": "OrderAsset", "order_creator": "prince_near", "ts": 0}}}"#;
+
+        let halted = results(r#"[{"Err": {"TradingHalted": 5}}]"#);
+        assert_eq!(rejected_quantity(&halted, 10), 10);
+        let resting = results(&format!("[{}, {}]", accepted, fill));
+        assert_eq!(rejected_quantity(&resting, 10), 0);
+        // the rest is refunded as unfilled instead
+        let band_rejected = results(&format!(
+            r#"[{}, {}, {{"Err": {{"PriceBandViolation": 7}}}}]"#,
+            accepted, fill
+        ));
+        assert_eq!(rejected_quantity(&band_rejected, 10), 0);
+    }
+
+    #[test]
+    fn band_rejected_stop_refund() {
+        let mut context = get_context(vec![], false);
+        // settled as the contract's own callback
+        context.predecessor_account_id = context.current_account_id.clone();
+        // covers deposits attached to the token transfers
+        context.account_balance = 10u128.pow(30);
+        testing_env!(context);
+        let mut contract = Market::new();
+        let book = &mut contract.market_order_book;
+        let limit_order = |side, price, quantity| {
+            orders::new_limit_order_request(
+                Asset::nBook,
+                Asset::nDAI,
+                side,
+                price,
+                quantity,
+                "prince_near".to_string(),
+                0,
+            )
+        };
+        book.set_price_band(Some(PriceBand::new(
+            10.0,
+            ReferencePrice::LastTrade,
+            BandAction::Reject,
+            0,
+        )));
+        book.process_order(limit_order(OrderSide::Bid, 0.95, 1));
+        book.process_order(limit_order(OrderSide::Bid, 0.5, 5));
+        book.process_order(
+            orders::new_trailing_stop_request(
+                Asset::nBook,
+                Asset::nDAI,
+                OrderSide::Ask,
+                3,
+                Trail::Fixed(0.95),
+                "stop_near".to_string(),
+                0,
+            )
+            .with_limit_price(0.4),
+        );
+
+        // the stop is triggered at 0.95, the best bid is out of the band
+        let res = book.process_order(limit_order(OrderSide::Ask, 0.95, 1));
+        assert!(res
+            .iter()
+            .any(|result| matches!(result, Err(Failed::PriceBandViolation(3)))));
+        contract.process_orderbook_result(res);
+
+        let refunded = env::created_receipts().iter().any(|receipt| {
+            let receipt = near_sdk::serde_json::to_string(receipt).unwrap();
+            let receipt: near_sdk::serde_json::Value =
+                near_sdk::serde_json::from_str(&receipt).unwrap();
+            let action = &receipt["actions"][0]["FunctionCall"];
+            let args: near_sdk::serde_json::Value =
+                near_sdk::serde_json::from_str(action["args"].as_str().unwrap()).unwrap();
+            receipt["receiver_id"] == "nbook.hacker.testnet"
+                && action["method_name"] == "transfer"
+                && args["new_owner_id"] == "stop_near"
+                && args["amount"] == "3"
+        });
+        assert!(refunded);
+    }
+
+    #[test]
+    fn cancel_own_order_refund() {
+        let mut context = get_context(vec![], false);
+        context.predecessor_account_id = context.current_account_id.clone();
+        context.account_balance = 10u128.pow(30);
+        testing_env!(context);
+        let mut contract = Market::new();
+        for order_creator in &["carol_near", "prince_near"] {
+            contract
+                .market_order_book
+                .process_order(orders::new_limit_order_request(
+                    Asset::nBook,
+                    Asset::nDAI,
+                    OrderSide::Bid,
+                    1.0,
+                    4,
+                    order_creator.to_string(),
+                    0,
+                ));
+        }
+
+        // orders of others can't be cancelled
+        assert_eq!(
+            contract.cancel_limit_order(1, "Bid".to_string()),
+            vec![Err(Failed::OrderNotFound(1))]
+        );
+        assert!(env::created_receipts().is_empty());
+
+        let res = contract.cancel_limit_order(2, "Bid".to_string());
+        assert!(matches!(
+            res[0],
+            Ok(Success::Cancelled { id: 2, qty: 4, .. })
+        ));
+        let receipts = env::created_receipts();
+        assert_eq!(receipts.len(), 1);
+        let receipt = near_sdk::serde_json::to_string(&receipts[0]).unwrap();
+        assert!(receipt.contains("ndai.hacker.testnet"));
+    }
+
+    #[test]
+    fn batch_escrow_out_of_range() {
+        let context = get_context(vec![], false);
+        testing_env!(context);
+        let contract = Market::new();
+        let new_order = |quantity| BatchOrder::New {
+            price: 1.0,
+            quantity,
+            side: "Ask".to_string(),
+            options: None,
+        };
+
+        assert_eq!(
+            contract.batch_escrow(&[new_order(5), new_order(2)]),
+            Ok((7, 0))
+        );
+        assert!(contract.batch_escrow(&[new_order(u128::MAX)]).is_err());
+        let half = i128::MAX as u128 / 2 + 1;
+        assert!(contract
+            .batch_escrow(&[new_order(half), new_order(half)])
+            .is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "Order quantity is out of range.")]
+    fn oco_quantity_out_of_range() {
+        let context = get_context(vec![], false);
+        testing_env!(context);
+        let mut contract = Market::new();
+        contract.new_oco_order(
+            1.0,
+            u128::MAX,
+            "Ask".to_string(),
+            Trail::Fixed(0.5),
+            None,
+            None,
+        );
+    }
+
     #[test]
     fn get_ask_order() {
         let context = get_context(vec![], false);
@@ -281,12 +1312,12 @@ mod tests {
         assert_eq!(spread[1], 0.0);
 
         // Ask Order
-        let res = contract.new_limit_order(1.25, 2, "Ask".to_string());
+        let res = contract.new_limit_order(1.25, 2, "Ask".to_string(), None);
         // let res1 = contract.get_ask_orders();
         println!("Ask Result: {:?}", res);
 
         // Bid Order
-        let res2 = contract.new_limit_order(1.22, 1, "Bid".to_string());
+        let res2 = contract.new_limit_order(1.22, 1, "Bid".to_string(), None);
         // let res3 = contract.get_bid_orders();
         println!("Bid Result: {:?}", res2);
 
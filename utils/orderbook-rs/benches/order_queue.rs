@@ -0,0 +1,114 @@
+//! Order queue benchmarks.
+//!
+//! Run with `cargo bench`, optionally passing book sizes:
+//! `cargo bench -- 10000 100000`. Orders are generated from a fixed seed, so
+//! numbers are comparable between runs and versions.
+
+extern crate orderbook;
+
+use std::env;
+use std::time::Instant;
+
+use orderbook::{OrderQueue, OrderSide};
+
+const DEFAULT_SIZES: [usize; 3] = [10_000, 100_000, 1_000_000];
+const PRICE_LEVELS: u64 = 1_000;
+const SAMPLE: usize = 10_000;
+
+/// Deterministic xorshift generator
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+
+    fn price(&mut self) -> f64 {
+        1.0 + self.below(PRICE_LEVELS) as f64 / 1000.0
+    }
+}
+
+#[derive(Clone)]
+struct Order {
+    qty: u128,
+}
+
+/// Queue of `size` resting orders with IDs 1..=size
+fn resting_orders(rng: &mut Rng, size: usize) -> OrderQueue<Order> {
+    let mut queue = OrderQueue::new(OrderSide::Ask, size);
+    for id in 1..=size as u64 {
+        let qty = 1 + rng.below(100) as u128;
+        queue.insert(id, rng.price(), qty, id, Order { qty });
+    }
+    queue
+}
+
+fn report(name: &str, size: usize, start: Instant) {
+    let elapsed = start.elapsed();
+    let nanos = elapsed.as_secs() as f64 * 1e9 + elapsed.subsec_nanos() as f64;
+    println!(
+        "{:<6} {:>9} resting: {:>10.0} ns/op",
+        name,
+        size,
+        nanos / SAMPLE as f64
+    );
+}
+
+fn bench(size: usize) {
+    let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+    let mut queue = resting_orders(&mut rng, size);
+    let ids: Vec<u64> = (0..SAMPLE).map(|_| 1 + rng.below(size as u64)).collect();
+
+    let start = Instant::now();
+    for i in 0..SAMPLE as u64 {
+        let id = size as u64 + 1 + i;
+        queue.insert(id, rng.price(), 1, id, Order { qty: 1 });
+    }
+    report("insert", size, start);
+
+    let start = Instant::now();
+    for (i, &id) in ids.iter().enumerate() {
+        let ts = (2 * size + i) as u64;
+        let qty = 1 + rng.below(100) as u128;
+        queue.amend(id, rng.price(), qty, ts, Order { qty });
+    }
+    report("amend", size, start);
+
+    let start = Instant::now();
+    for &id in &ids {
+        queue.cancel(id);
+    }
+    report("cancel", size, start);
+
+    let start = Instant::now();
+    let mut levels = 0;
+    for _ in 0..SAMPLE {
+        levels += queue.depth(10).len();
+    }
+    report("depth", size, start);
+    assert!(levels > 0);
+
+    let start = Instant::now();
+    let mut swept = 0;
+    for _ in 0..SAMPLE {
+        swept += queue.pop().map_or(0, |order| order.qty);
+    }
+    report("sweep", size, start);
+    assert!(swept > 0);
+}
+
+fn main() {
+    let sizes: Vec<usize> = env::args().filter_map(|arg| arg.parse().ok()).collect();
+    let sizes = if sizes.is_empty() {
+        DEFAULT_SIZES.to_vec()
+    } else {
+        sizes
+    };
+
+    for size in sizes {
+        bench(size);
+    }
+}
@@ -0,0 +1,96 @@
+use super::numeric::{self, total, Quantity};
+
+/// Find auction clearing price as a tuple: (price, volume)
+///
+/// Clearing price maximizes executable volume. Ties are resolved by minimal
+/// surplus (unmatched quantity at that price), then by the closest distance
+/// to the reference price, or the middle of the remaining range without one.
+///
+/// Both `bids` and `asks` are (price, quantity) pairs of resting limit orders.
+/// Volume is a total over many orders, so it is returned as `u128`.
+pub fn clearing_price<Price, Qty>(
+    bids: &[(Price, Qty)],
+    asks: &[(Price, Qty)],
+    reference_price: Option<Price>,
+) -> Option<(Price, u128)>
+where
+    Price: numeric::Price,
+    Qty: Quantity,
+{
+    // every limit price is a candidate for clearing price
+    let mut candidates: Vec<Price> = bids.iter().chain(asks.iter()).map(|o| o.0).collect();
+    candidates.sort_by(|a, b| a.cmp_price(b));
+    candidates.dedup();
+
+    // (price, volume, surplus) for the best candidates
+    let mut best: Vec<(Price, u128, u128)> = vec![];
+    for price in candidates {
+        let demand = total(bids.iter().filter(|o| o.0 >= price).map(|o| o.1));
+        let supply = total(asks.iter().filter(|o| o.0 <= price).map(|o| o.1));
+        let volume = demand.min(supply);
+        let surplus = demand.max(supply) - volume;
+
+        if volume == 0 {
+            continue;
+        }
+
+        match best.first() {
+            Some(&(_, best_volume, best_surplus))
+                if volume < best_volume || (volume == best_volume && surplus > best_surplus) => {}
+            Some(&(_, best_volume, best_surplus))
+                if volume == best_volume && surplus == best_surplus =>
+            {
+                best.push((price, volume, surplus))
+            }
+            _ => best = vec![(price, volume, surplus)],
+        }
+    }
+
+    let volume = best.first()?.1;
+    let price = match reference_price {
+        Some(reference_price) => {
+            let reference_price = reference_price.to_f64();
+            best.iter()
+                .map(|c| c.0)
+                .min_by(|a, b| {
+                    let a = (a.to_f64() - reference_price).abs();
+                    let b = (b.to_f64() - reference_price).abs();
+                    a.partial_cmp(&b).unwrap()
+                })
+                .unwrap()
+        }
+        None => best.first()?.0.midpoint(best.last()?.0),
+    };
+
+    Some((price, volume))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_crossing() {
+        assert_eq!(clearing_price(&[(0.99, 5u128)], &[(1.01, 5)], None), None);
+        assert_eq!(clearing_price(&[], &[(1.01, 5u128)], None), None);
+    }
+
+    #[test]
+    fn max_volume() {
+        let bids = [(1.03, 2u128), (1.02, 3), (1.00, 5)];
+        let asks = [(0.99, 1), (1.01, 4), (1.02, 4)];
+
+        // at 1.02: demand 5, supply 9; at 1.01: demand 5, supply 5
+        assert_eq!(clearing_price(&bids, &asks, None), Some((1.01, 5)));
+    }
+
+    #[test]
+    fn reference_tie_break() {
+        let bids = [(1.5, 3u128)];
+        let asks = [(1.0, 3)];
+
+        assert_eq!(clearing_price(&bids, &asks, None), Some((1.25, 3)));
+        assert_eq!(clearing_price(&bids, &asks, Some(1.4)), Some((1.5, 3)));
+        assert_eq!(clearing_price(&bids, &asks, Some(0.5)), Some((1.0, 3)));
+    }
+}
@@ -0,0 +1,42 @@
+use super::numeric::Quantity;
+
+extern crate near_sdk;
+use self::near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use self::near_sdk::serde::{Deserialize, Serialize};
+
+/// Execution constraint of a limit order.
+///
+/// Applies to every fill of the order resting in the book, and to the whole
+/// immediate execution of the incoming order.
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
+pub enum FillConstraint<Qty = u128> {
+    /// Filled completely at once or not at all
+    AllOrNone,
+    /// Executed by at least this quantity at once, or by the rest of the order
+    MinQty(Qty),
+}
+
+impl<Qty: Quantity> FillConstraint<Qty> {
+    /// Smallest execution accepted by an order with `qty` left
+    pub fn min_fill(&self, qty: Qty) -> Qty {
+        match *self {
+            FillConstraint::AllOrNone => qty,
+            FillConstraint::MinQty(min_qty) => min_qty.min(qty),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn min_fill() {
+        assert_eq!(FillConstraint::AllOrNone.min_fill(10u128), 10);
+        assert_eq!(FillConstraint::MinQty(4u128).min_fill(10), 4);
+        // the rest of the order is always accepted
+        assert_eq!(FillConstraint::MinQty(4u128).min_fill(3), 3);
+    }
+}
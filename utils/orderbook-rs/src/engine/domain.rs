@@ -1,10 +1,16 @@
 use std::fmt::Debug;
 
+use super::constraints::FillConstraint;
+use super::numeric::Quantity;
+use super::pegging::Peg;
+
 extern crate near_sdk;
 use self::near_sdk::borsh::{BorshDeserialize, BorshSerialize};
-use self::near_sdk::serde::Serialize;
+use self::near_sdk::serde::{Deserialize, Serialize};
 
-#[derive( Debug, Copy, Clone, BorshDeserialize, BorshSerialize, Serialize)]
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
 pub enum OrderSide {
     Bid,
     Ask,
@@ -17,19 +23,50 @@ impl Default for OrderSide {
 #[derive(Default, Debug, Clone, BorshDeserialize, BorshSerialize)]
 pub struct Asset;
 
-#[derive(Default, Debug, Clone, BorshDeserialize, BorshSerialize)]
-pub struct Order<Asset>
-{
+#[derive(
+    Default, Debug, Clone, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
+pub struct Order<Asset, Price = f64, Qty = u128> {
     pub order_id: u64,
     pub order_asset: Asset,
     pub price_asset: Asset,
     pub side: OrderSide,
-    pub price: f64,
-    pub qty: u128,
+    pub price: Price,
+    pub qty: Qty,
+    pub order_creator: String,
+    pub client_order_id: Option<String>,
+    pub hidden: bool,
+    pub peg: Option<Peg<Price>>,
+    pub constraint: Option<FillConstraint<Qty>>,
+}
+
+impl<Asset, Price, Qty: Quantity> Order<Asset, Price, Qty> {
+    /// Smallest fill the order accepts, zero if it is not constrained
+    pub fn min_fill(&self) -> Qty {
+        match self.constraint {
+            Some(constraint) => constraint.min_fill(self.qty),
+            None => Qty::zero(),
+        }
+    }
 }
 
-#[derive(Eq, PartialEq, Debug, Copy, Clone, Serialize)]
+#[derive(
+    Eq, PartialEq, Debug, Copy, Clone, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
 pub enum OrderType {
     Market,
     Limit,
+    TrailingStop,
+}
+
+#[derive(
+    Eq, PartialEq, Debug, Copy, Clone, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
+pub enum TradingPhase {
+    Continuous,
+    Auction,
+}
+
+impl Default for TradingPhase {
+    fn default() -> Self { TradingPhase::Continuous }
 }
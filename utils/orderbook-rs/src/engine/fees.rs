@@ -0,0 +1,136 @@
+use super::domain::OrderSide;
+use super::validation::ValidationError;
+
+extern crate near_sdk;
+use self::near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use self::near_sdk::serde::{Deserialize, Serialize};
+
+const BPS_DENOMINATOR: i128 = 10_000;
+
+/// Asset of the pair a fee is charged in
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
+pub enum FeeAsset {
+    OrderAsset,
+    PriceAsset,
+}
+
+impl FeeAsset {
+    /// Fee is charged in the asset received by the filled side:
+    /// bids receive the order asset, asks receive the price asset
+    pub fn received_by(side: OrderSide) -> Self {
+        match side {
+            OrderSide::Bid => FeeAsset::OrderAsset,
+            OrderSide::Ask => FeeAsset::PriceAsset,
+        }
+    }
+
+    /// Maker fee is charged in the asset received by the maker, a rebate is
+    /// paid out of the taker fee of the same fill, in the taker fee asset
+    pub fn of_maker_fee(maker_side: OrderSide, fee: i128) -> Self {
+        match (maker_side, fee < 0) {
+            (side, false) => FeeAsset::received_by(side),
+            (OrderSide::Bid, true) => FeeAsset::PriceAsset,
+            (OrderSide::Ask, true) => FeeAsset::OrderAsset,
+        }
+    }
+}
+
+/// Maker and taker fee rates, in basis points of the filled quantity.
+///
+/// Negative maker rate is a rebate paid to the resting order out of the
+/// taker fee, see `FeeAsset::of_maker_fee`.
+#[derive(
+    Default, Debug, Copy, Clone, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
+pub struct FeeSchedule {
+    pub maker_bps: i32,
+    pub taker_bps: i32,
+}
+
+impl FeeSchedule {
+    /// Rates are limited to 100%, and a rebate can't exceed the taker fee
+    pub fn new(maker_bps: i32, taker_bps: i32) -> Result<Self, ValidationError> {
+        if maker_bps.abs() > 10_000
+            || taker_bps.abs() > 10_000
+            || taker_bps < 0
+            || maker_bps + taker_bps < 0
+        {
+            return Err(ValidationError::BadFeeSchedule {
+                maker_bps,
+                taker_bps,
+            });
+        }
+        Ok(FeeSchedule {
+            maker_bps,
+            taker_bps,
+        })
+    }
+
+    pub fn maker_fee(&self, qty: u128) -> i128 {
+        fee(qty, self.maker_bps)
+    }
+
+    pub fn taker_fee(&self, qty: u128) -> i128 {
+        fee(qty, self.taker_bps)
+    }
+}
+
+/// Fees are rounded up and rebates down, so the venue never pays out more than it collects
+fn fee(qty: u128, rate_bps: i32) -> i128 {
+    let qty = qty.min(i128::MAX as u128) as i128;
+    let rate = rate_bps.abs() as i128;
+    let (whole, part) = (qty / BPS_DENOMINATOR, qty % BPS_DENOMINATOR);
+    let amount = whole * rate;
+    if rate_bps >= 0 {
+        amount + (part * rate + BPS_DENOMINATOR - 1) / BPS_DENOMINATOR
+    } else {
+        -(amount + part * rate / BPS_DENOMINATOR)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fee_rounding() {
+        let fees = FeeSchedule::new(-2, 5).unwrap();
+
+        assert_eq!(fees.taker_fee(10_000), 5);
+        assert_eq!(fees.taker_fee(1), 1);
+        assert_eq!(fees.taker_fee(0), 0);
+        assert_eq!(fees.maker_fee(10_000), -2);
+        assert_eq!(fees.maker_fee(4_999), 0);
+        assert_eq!(fees.taker_fee(u128::MAX), i128::MAX / 2_000 + 1);
+        assert_eq!(FeeSchedule::default().taker_fee(100), 0);
+    }
+
+    #[test]
+    fn maker_fee_asset() {
+        assert_eq!(
+            FeeAsset::of_maker_fee(OrderSide::Ask, 2),
+            FeeAsset::PriceAsset
+        );
+        assert_eq!(
+            FeeAsset::of_maker_fee(OrderSide::Ask, -2),
+            FeeAsset::OrderAsset
+        );
+        assert_eq!(
+            FeeAsset::of_maker_fee(OrderSide::Bid, -2),
+            FeeAsset::PriceAsset
+        );
+    }
+
+    #[test]
+    fn rebate_above_taker_fee() {
+        assert_eq!(
+            FeeSchedule::new(-6, 5),
+            Err(ValidationError::BadFeeSchedule {
+                maker_bps: -6,
+                taker_bps: 5,
+            })
+        );
+    }
+}
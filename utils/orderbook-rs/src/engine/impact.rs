@@ -0,0 +1,174 @@
+use super::numeric::Price;
+
+extern crate near_sdk;
+use self::near_sdk::serde::{Deserialize, Serialize};
+
+const BPS_PER_UNIT: f64 = 10_000.0;
+
+/// Point of a cumulative depth curve: everything available up to `price`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DepthPoint<Price = f64> {
+    pub price: Price,
+    // order asset
+    pub qty: u128,
+    // price asset, the cost of `qty` at the level prices
+    pub notional: f64,
+}
+
+/// Estimated execution of a taker order sweeping displayed price levels
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Impact<Price = f64> {
+    // less than requested when the levels run out
+    pub qty: u128,
+    pub notional: f64,
+    pub average_price: Option<f64>,
+    pub worst_price: Option<Price>,
+    // distance of the average price from the best one, in basis points
+    pub slippage_bps: f64,
+}
+
+/// Cumulative quantity and notional of price levels, given the best first
+pub fn depth_curve<P, I>(levels: I) -> Vec<DepthPoint<P>>
+where
+    P: Price,
+    I: IntoIterator<Item = (P, u128)>,
+{
+    let (mut qty, mut notional) = (0u128, 0f64);
+    levels
+        .into_iter()
+        .map(|(price, level_qty)| {
+            qty += level_qty;
+            notional += price.to_f64() * level_qty as f64;
+            DepthPoint {
+                price,
+                qty,
+                notional,
+            }
+        })
+        .collect()
+}
+
+/// Execution of `qty` against price levels, given the best first.
+///
+/// Estimated from the level prices only: fees, price bands and fill
+/// constraints of the resting orders are not taken into account.
+pub fn impact<P, I>(levels: I, qty: u128) -> Impact<P>
+where
+    P: Price,
+    I: IntoIterator<Item = (P, u128)>,
+{
+    let (mut filled, mut notional) = (0u128, 0f64);
+    let (mut best_price, mut worst_price) = (None, None);
+    for (price, level_qty) in levels {
+        if filled == qty {
+            break;
+        }
+        let fill_qty = level_qty.min(qty - filled);
+        filled += fill_qty;
+        notional += price.to_f64() * fill_qty as f64;
+        best_price = best_price.or(Some(price));
+        worst_price = Some(price);
+    }
+
+    let average_price = if filled > 0 {
+        Some(notional / filled as f64)
+    } else {
+        None
+    };
+    Impact {
+        qty: filled,
+        notional,
+        average_price,
+        worst_price,
+        slippage_bps: match (best_price, average_price) {
+            (Some(best_price), Some(average_price)) => {
+                price_move_bps(best_price.to_f64(), average_price)
+            }
+            _ => 0.0,
+        },
+    }
+}
+
+/// Quantity of price levels, given the best first, within `max_move_pct`
+/// percents from the best price
+pub fn qty_within<P, I>(levels: I, max_move_pct: f64) -> u128
+where
+    P: Price,
+    I: IntoIterator<Item = (P, u128)>,
+{
+    let mut levels = levels.into_iter().peekable();
+    let best_price = match levels.peek() {
+        Some(&(price, _)) => price.to_f64(),
+        None => return 0,
+    };
+    levels
+        .take_while(|&(price, _)| {
+            price_move_bps(best_price, price.to_f64()) <= max_move_pct * 100.0
+        })
+        .map(|(_, level_qty)| level_qty)
+        .sum()
+}
+
+fn price_move_bps(from: f64, to: f64) -> f64 {
+    (to - from).abs() / from * BPS_PER_UNIT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn asks() -> Vec<(f64, u128)> {
+        vec![(2.0, 4), (2.5, 4), (3.0, 8)]
+    }
+
+    #[test]
+    fn curve() {
+        assert_eq!(
+            depth_curve(asks()),
+            vec![
+                DepthPoint {
+                    price: 2.0,
+                    qty: 4,
+                    notional: 8.0,
+                },
+                DepthPoint {
+                    price: 2.5,
+                    qty: 8,
+                    notional: 18.0,
+                },
+                DepthPoint {
+                    price: 3.0,
+                    qty: 16,
+                    notional: 42.0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn sweep() {
+        assert_eq!(
+            impact(asks(), 8),
+            Impact {
+                qty: 8,
+                notional: 18.0,
+                average_price: Some(2.25),
+                worst_price: Some(2.5),
+                slippage_bps: 1250.0,
+            }
+        );
+        assert_eq!(impact(asks(), 20).qty, 16);
+        assert_eq!(impact(asks(), 2).slippage_bps, 0.0);
+        assert_eq!(impact(Vec::<(f64, u128)>::new(), 2).average_price, None);
+    }
+
+    #[test]
+    fn within_move() {
+        assert_eq!(qty_within(asks(), 0.0), 4);
+        assert_eq!(qty_within(asks(), 25.0), 8);
+        assert_eq!(qty_within(asks(), 50.0), 16);
+
+        // bids drop from the best price
+        assert_eq!(qty_within(vec![(2.0, 4), (1.5, 2), (1.0, 1)], 25.0), 6);
+    }
+}
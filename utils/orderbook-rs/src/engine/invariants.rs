@@ -0,0 +1,299 @@
+use super::domain::OrderSide;
+use super::numeric::Quantity;
+use super::orderbook::{Failed, Success};
+
+/// Broken internal consistency of the order book
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvariantViolation {
+    /// Orders of a price level are not in time priority
+    UnsortedIndex { side: OrderSide, id: u64 },
+    /// Active order has no index
+    MissingIndex { side: OrderSide, id: u64 },
+    /// Price level links or totals don't match its orders
+    BrokenLevel { side: OrderSide, price: f64 },
+    /// Active order is indexed more than once
+    DuplicateIndex { side: OrderSide, id: u64 },
+    /// Index price or quantity differs from the order
+    IndexMismatch { side: OrderSide, id: u64 },
+    /// Active order with nothing left to fill
+    EmptyOrder { side: OrderSide, id: u64 },
+    /// Order is stored in the queue of the other side
+    WrongSide { side: OrderSide, id: u64 },
+    /// The same ID is used by several active or pending orders
+    DuplicateOrderID(u64),
+    /// Best bid is not below best ask in continuous trading
+    CrossedBook { bid: f64, ask: f64 },
+    /// Bid and ask sides of the fills don't add up
+    UnbalancedFills { bid_qty: u128, ask_qty: u128 },
+}
+
+/// Check that every fill in processing results has a counterpart:
+/// quantity filled on the bid side equals quantity filled on the ask side.
+pub fn check_fills<Price, Qty: Quantity>(
+    results: &[Result<Success<Price, Qty>, Failed>],
+) -> Result<(), InvariantViolation> {
+    let mut bid_qty = 0u128;
+    let mut ask_qty = 0u128;
+
+    for result in results {
+        let (side, qty) = match *result {
+            Ok(Success::Filled { side, qty, .. }) => (side, qty),
+            Ok(Success::PartiallyFilled { side, qty, .. }) => (side, qty),
+            _ => continue,
+        };
+        match side {
+            OrderSide::Bid => bid_qty += qty.into(),
+            OrderSide::Ask => ask_qty += qty.into(),
+        }
+    }
+
+    if bid_qty != ask_qty {
+        return Err(InvariantViolation::UnbalancedFills { bid_qty, ask_qty });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::matching::{MatchingPolicy, ProRata, Rounding, TopOrderProRata};
+    use super::super::orderbook::Orderbook;
+    use super::super::orders::{self, OrderRequest};
+    use super::super::test_utils::set_block_timestamp;
+    use super::*;
+
+    #[derive(PartialEq, Eq, Debug, Copy, Clone)]
+    pub enum Asset {
+        USD,
+        BTC,
+    }
+
+    const CREATORS: [&str; 3] = ["alice", "bob", "carol"];
+
+    /// Deterministic xorshift generator, so a failing seed could be replayed
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    fn book_qty<Policy: MatchingPolicy>(orderbook: &Orderbook<Asset, Policy>) -> u128 {
+        let orders = orderbook.bid_queue.iter().chain(orderbook.ask_queue.iter());
+        orders.map(|order| order.qty).sum()
+    }
+
+    fn random_request<Policy: MatchingPolicy>(
+        rng: &mut Rng,
+        orderbook: &Orderbook<Asset, Policy>,
+    ) -> OrderRequest<Asset> {
+        let side = if rng.below(2) == 0 {
+            OrderSide::Bid
+        } else {
+            OrderSide::Ask
+        };
+        // small price grid makes crossing and equal prices frequent
+        let price = (95 + rng.below(11)) as f64 / 100.0;
+        let qty = 1 + rng.below(10) as u128;
+        let order_creator = String::from(CREATORS[rng.below(3) as usize]);
+
+        // existing order most of the time, random ID otherwise
+        let active: Vec<(u64, OrderSide)> = orderbook
+            .bid_queue
+            .iter()
+            .chain(orderbook.ask_queue.iter())
+            .map(|order| (order.order_id, order.side))
+            .collect();
+        let (id, id_side) = if active.is_empty() || rng.below(10) == 0 {
+            (1 + rng.below(1000), side)
+        } else {
+            active[rng.below(active.len() as u64) as usize]
+        };
+
+        match rng.below(20) {
+            0..=10 => orders::new_limit_order_request(
+                Asset::BTC,
+                Asset::USD,
+                side,
+                price,
+                qty,
+                order_creator,
+                0,
+            ),
+            11 => orders::new_limit_order_request(
+                Asset::BTC,
+                Asset::USD,
+                side,
+                price,
+                qty,
+                order_creator,
+                0,
+            )
+            .with_all_or_none(),
+            12..=13 => orders::new_market_order_request(
+                Asset::BTC,
+                Asset::USD,
+                side,
+                qty,
+                order_creator,
+                0,
+            ),
+            14..=15 => orders::amend_order_request(id, id_side, price, qty, 0),
+            16..=17 => orders::limit_order_cancel_request(id, id_side),
+            18 => orders::cancel_replace_request(id, id_side, price, qty, order_creator, 0),
+            _ => orders::mass_cancel_request(order_creator, Some(side), None),
+        }
+    }
+
+    /// Expected book quantity after successful processing of the request
+    fn expected_book_qty(
+        request: &OrderRequest<Asset>,
+        results: &[Result<Success, Failed>],
+        before: u128,
+    ) -> u128 {
+        let mut filled = 0;
+        let mut cancelled = 0;
+        let mut unfilled = 0;
+        for result in results {
+            match *result {
+                Ok(Success::Filled { qty, .. }) | Ok(Success::PartiallyFilled { qty, .. }) => {
+                    filled += qty
+                }
+                Ok(Success::Cancelled { qty, .. }) => cancelled += qty,
+                Ok(Success::Unfilled { qty, .. }) => unfilled += qty,
+                _ => {}
+            }
+        }
+
+        // every fill is reported for both sides, unfilled quantity is either
+        // the rest of the new order or a dropped constrained order
+        let expected = match *request {
+            OrderRequest::NewLimitOrder { qty, .. } | OrderRequest::NewOco { qty, .. } => {
+                before + qty - filled
+            }
+            OrderRequest::NewMarketOrder { .. } => before - filled / 2,
+            OrderRequest::AmendOrder { .. } => before,
+            OrderRequest::CancelOrder { .. } | OrderRequest::MassCancel { .. } => {
+                before - cancelled
+            }
+            OrderRequest::CancelReplace { qty, .. } => before - cancelled + qty - filled,
+            OrderRequest::NewTrailingStop { .. } => before,
+        };
+        expected - unfilled
+    }
+
+    fn run_order_stream<Policy: MatchingPolicy>(
+        mut orderbook: Orderbook<Asset, Policy>,
+        seed: u64,
+        steps: usize,
+    ) {
+        let mut rng = Rng(seed);
+
+        for step in 0..steps {
+            let request = random_request(&mut rng, &orderbook);
+            let before = book_qty(&orderbook);
+            // amended quantity replaces the resting one
+            let before = match request {
+                OrderRequest::AmendOrder { id, side, qty, .. } => {
+                    let order_queue = match side {
+                        OrderSide::Bid => &orderbook.bid_queue,
+                        OrderSide::Ask => &orderbook.ask_queue,
+                    };
+                    match order_queue.get(id) {
+                        Some(order) => before - order.qty + qty,
+                        None => before,
+                    }
+                }
+                _ => before,
+            };
+
+            let results = orderbook.process_order(request.clone());
+            let context = format!("seed {}, step {}, {:?}", seed, step, request);
+
+            if let Err(violation) = orderbook.check_invariants() {
+                panic!("{:?} after {}", violation, context);
+            }
+            if let Err(violation) = check_fills(&results) {
+                panic!("{:?} after {}", violation, context);
+            }
+
+            // quantity is not conserved in the book when part of the request
+            // was rejected or moved to pending orders
+            let complete = !results
+                .iter()
+                .any(|result| matches!(result, Err(_) | Ok(Success::MatchingSuspended { .. })));
+            if complete {
+                let expected = expected_book_qty(&request, &results, before);
+                assert_eq!(book_qty(&orderbook), expected, "{}", context);
+            }
+
+            if orderbook.pending_orders() > 0 && rng.below(4) == 0 {
+                let results = orderbook.resume_matching();
+                if let Err(violation) = orderbook.check_invariants() {
+                    panic!("{:?} after resume, {}", violation, context);
+                }
+                if let Err(violation) = check_fills(&results) {
+                    panic!("{:?} after resume, {}", violation, context);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn random_stream_fifo() {
+        set_block_timestamp(0);
+        for seed in 1..6 {
+            run_order_stream(Orderbook::new(Asset::BTC, Asset::USD), seed, 3000);
+        }
+    }
+
+    #[test]
+    fn random_stream_pro_rata() {
+        set_block_timestamp(0);
+        for seed in 1..6 {
+            let policy = ProRata::new(2, Rounding::Nearest);
+            let orderbook = Orderbook::with_policy(Asset::BTC, Asset::USD, policy);
+            run_order_stream(orderbook, seed, 3000);
+
+            let policy = TopOrderProRata::new(40, ProRata::new(0, Rounding::Down));
+            let orderbook = Orderbook::with_policy(Asset::BTC, Asset::USD, policy);
+            run_order_stream(orderbook, seed, 3000);
+        }
+    }
+
+    #[test]
+    fn random_stream_fills_limit() {
+        set_block_timestamp(0);
+        for seed in 1..6 {
+            let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+            orderbook.set_max_fills(2);
+            run_order_stream(orderbook, seed, 3000);
+        }
+    }
+
+    #[test]
+    fn unbalanced_fills() {
+        set_block_timestamp(0);
+        let results: Vec<Result<Success, Failed>> = vec![Ok(Success::Filled {
+            order_id: 1,
+            side: OrderSide::Bid,
+            order_type: super::super::domain::OrderType::Limit,
+            price: 1.0,
+            qty: 2,
+            fee: 0,
+            fee_asset: super::super::fees::FeeAsset::OrderAsset,
+            order_creator: String::from("alice"),
+            ts: 0,
+        })];
+        assert_eq!(
+            check_fills(&results),
+            Err(InvariantViolation::UnbalancedFills {
+                bid_qty: 2,
+                ask_qty: 0
+            })
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use std::fmt::Write;
+
+use super::domain::OrderSide;
+use super::numeric;
+
+extern crate near_sdk;
+use self::near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use self::near_sdk::serde::{Deserialize, Serialize};
+
+/// Number of best price levels per side covered by the book checksum
+pub const CHECKSUM_LEVELS: usize = 10;
+
+/// New aggregate size of a price level, zero when the level is gone.
+///
+/// Deltas are numbered by the book sequence without gaps, so a consumer
+/// applying them over a snapshot detects missed ones. `checksum` is taken
+/// after the request which produced the delta.
+#[derive(Debug, Clone, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+pub struct BookDelta<Price = f64> {
+    pub seq: u64,
+    pub side: OrderSide,
+    pub price: Price,
+    pub qty: u128,
+    pub checksum: u32,
+}
+
+/// Aggregated price levels, the best ones first, as of the book sequence `seq`
+#[derive(Debug, Clone, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+pub struct BookSnapshot<Price = f64> {
+    pub seq: u64,
+    pub bids: Vec<(Price, u128)>,
+    pub asks: Vec<(Price, u128)>,
+    pub checksum: u32,
+}
+
+/// CRC32 of the best `CHECKSUM_LEVELS` levels of both sides, best first.
+///
+/// Levels are written as `price:qty`, separated by `,`, asks go before bids
+/// and sides are separated by `|`, e.g. `1.01:10,1.02:3|1:5`. Prices are
+/// formatted as the shortest decimal of their `f64` value.
+pub fn book_checksum<Price: numeric::Price>(bids: &[(Price, u128)], asks: &[(Price, u128)]) -> u32 {
+    let mut levels = String::new();
+    for (i, side) in [asks, bids].iter().enumerate() {
+        if i > 0 {
+            levels.push('|');
+        }
+        for (j, level) in side.iter().take(CHECKSUM_LEVELS).enumerate() {
+            if j > 0 {
+                levels.push(',');
+            }
+            write!(levels, "{}:{}", level.0.to_f64(), level.1).unwrap();
+        }
+    }
+    crc32(levels.as_bytes())
+}
+
+/// CRC-32 (IEEE 802.3), as computed by zlib
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(
+            book_checksum(&[(1.0, 5)], &[(1.01, 10), (1.02, 3)]),
+            crc32(b"1.01:10,1.02:3|1:5")
+        );
+        // example of SCHEMA.md
+        assert_eq!(
+            book_checksum(&[(1.0, 5)], &[(1.01, 10), (1.02, 3)]),
+            1_757_926_102
+        );
+        assert_eq!(book_checksum::<f64>(&[], &[]), crc32(b"|"));
+
+        // integer ticks are formatted the same way
+        assert_eq!(
+            book_checksum(&[(100u64, 5)], &[]),
+            book_checksum(&[(100.0, 5)], &[])
+        );
+
+        // levels beyond the covered depth don't count
+        let asks: Vec<(f64, u128)> = (1..20).map(|i| (i as f64, 1)).collect();
+        assert_eq!(
+            book_checksum(&[], &asks),
+            book_checksum(&[], &asks[..CHECKSUM_LEVELS])
+        );
+    }
+}
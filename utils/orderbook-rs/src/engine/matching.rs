@@ -0,0 +1,186 @@
+use super::numeric::{left_after, total, Quantity};
+
+extern crate near_sdk;
+use self::near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+
+/// Allocation of incoming quantity between resting orders of a price level
+pub trait MatchingPolicy {
+    /// Split `qty` between resting order quantities, given in time priority.
+    ///
+    /// Returns allocation per resting order in the same order. Allocations
+    /// sum up to `qty` (which never exceeds the level total) and never
+    /// exceed the resting order quantity.
+    fn allocate<Qty: Quantity>(&self, qty: Qty, resting: &[Qty]) -> Vec<Qty>;
+}
+
+/// Price-time priority
+#[derive(Default, Debug, Copy, Clone, BorshDeserialize, BorshSerialize)]
+pub struct Fifo;
+
+impl MatchingPolicy for Fifo {
+    fn allocate<Qty: Quantity>(&self, qty: Qty, resting: &[Qty]) -> Vec<Qty> {
+        let mut allocations = vec![Qty::zero(); resting.len()];
+        fill_fifo(qty, resting, &mut allocations);
+        allocations
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone, BorshDeserialize, BorshSerialize)]
+pub enum Rounding {
+    Down,
+    Nearest,
+}
+
+impl Default for Rounding {
+    fn default() -> Self { Rounding::Down }
+}
+
+/// Allocation proportional to resting order quantity.
+///
+/// Shares below `min_allocation` are dropped. Quantity left after rounding
+/// is allocated in time priority.
+#[derive(Default, Debug, Copy, Clone, BorshDeserialize, BorshSerialize)]
+pub struct ProRata {
+    pub min_allocation: u128,
+    pub rounding: Rounding,
+}
+
+impl ProRata {
+    pub fn new(min_allocation: u128, rounding: Rounding) -> Self {
+        ProRata {
+            min_allocation,
+            rounding,
+        }
+    }
+
+    fn share(&self, qty: u128, part: u128, total: u128) -> u128 {
+        match qty.checked_mul(part) {
+            Some(product) => match self.rounding {
+                Rounding::Down => product / total,
+                Rounding::Nearest => (product + total / 2) / total,
+            },
+            // too large for exact integer math
+            None => {
+                let share = qty as f64 * (part as f64 / total as f64);
+                match self.rounding {
+                    Rounding::Down => share.floor() as u128,
+                    Rounding::Nearest => share.round() as u128,
+                }
+            }
+        }
+    }
+}
+
+impl MatchingPolicy for ProRata {
+    fn allocate<Qty: Quantity>(&self, qty: Qty, resting: &[Qty]) -> Vec<Qty> {
+        let total = total(resting.iter().cloned());
+        let mut allocations = vec![Qty::zero(); resting.len()];
+        if total == 0 {
+            return allocations;
+        }
+
+        // shares never exceed `qty`, so they fit into its type
+        let mut left: u128 = qty.into();
+        for (allocation, order_qty) in allocations.iter_mut().zip(resting) {
+            let order_qty: u128 = (*order_qty).into();
+            let share = self
+                .share(qty.into(), order_qty, total)
+                .min(order_qty)
+                .min(left);
+            if share >= self.min_allocation {
+                *allocation = Qty::from_u128(share).unwrap();
+                left -= share;
+            }
+        }
+
+        fill_fifo(Qty::from_u128(left).unwrap(), resting, &mut allocations);
+        allocations
+    }
+}
+
+/// The first order at the level gets up to `top_order_pct` percents of the
+/// incoming quantity, the rest is allocated pro-rata.
+#[derive(Default, Debug, Copy, Clone, BorshDeserialize, BorshSerialize)]
+pub struct TopOrderProRata {
+    pub top_order_pct: u128,
+    pub pro_rata: ProRata,
+}
+
+impl TopOrderProRata {
+    pub fn new(top_order_pct: u128, pro_rata: ProRata) -> Self {
+        TopOrderProRata {
+            top_order_pct: top_order_pct.min(100),
+            pro_rata,
+        }
+    }
+}
+
+impl MatchingPolicy for TopOrderProRata {
+    fn allocate<Qty: Quantity>(&self, qty: Qty, resting: &[Qty]) -> Vec<Qty> {
+        if resting.is_empty() {
+            return vec![];
+        }
+
+        let top_share = self.pro_rata.share(qty.into(), self.top_order_pct, 100);
+        let top_allocation = Qty::from_u128(top_share).unwrap().min(resting[0]);
+
+        let mut rest = resting.to_vec();
+        rest[0] = left_after(rest[0], top_allocation);
+
+        let mut allocations = self
+            .pro_rata
+            .allocate(left_after(qty, top_allocation), &rest);
+        allocations[0] = allocations[0]
+            .checked_add(top_allocation)
+            .expect("allocation exceeds order quantity");
+        allocations
+    }
+}
+
+/// Allocate quantity left in time priority
+fn fill_fifo<Qty: Quantity>(mut qty: Qty, resting: &[Qty], allocations: &mut [Qty]) {
+    for (allocation, order_qty) in allocations.iter_mut().zip(resting) {
+        if qty.is_zero() {
+            break;
+        }
+        let fill = left_after(*order_qty, *allocation).min(qty);
+        *allocation = allocation
+            .checked_add(fill)
+            .expect("allocation exceeds order quantity");
+        qty = left_after(qty, fill);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fifo_allocation() {
+        assert_eq!(Fifo.allocate(5u128, &[3, 4, 2]), vec![3, 2, 0]);
+        assert_eq!(Fifo.allocate(9u128, &[3, 4, 2]), vec![3, 4, 2]);
+    }
+
+    #[test]
+    fn pro_rata_allocation() {
+        let pro_rata = ProRata::new(0, Rounding::Down);
+        // 10 * 6 / 12 = 5, 10 * 4 / 12 = 3.33, 10 * 2 / 12 = 1.67, leftover goes in time priority
+        assert_eq!(pro_rata.allocate(10u128, &[6, 4, 2]), vec![6, 3, 1]);
+
+        let pro_rata = ProRata::new(0, Rounding::Nearest);
+        assert_eq!(pro_rata.allocate(10u128, &[6, 4, 2]), vec![5, 3, 2]);
+
+        // the smallest share is below minimum
+        let pro_rata = ProRata::new(2, Rounding::Down);
+        assert_eq!(pro_rata.allocate(10u128, &[6, 4, 2]), vec![6, 4, 0]);
+    }
+
+    #[test]
+    fn top_order_pro_rata_allocation() {
+        let policy = TopOrderProRata::new(50, ProRata::new(0, Rounding::Down));
+
+        // top order gets 5 first, then 5 is split as 1 * 5 / 7, 4 * 5 / 7, 2 * 5 / 7
+        assert_eq!(policy.allocate(10u128, &[6, 4, 2]), vec![6, 3, 1]);
+        assert_eq!(policy.allocate(12u64, &[6, 4, 2]), vec![6, 4, 2]);
+    }
+}
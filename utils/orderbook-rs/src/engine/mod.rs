@@ -1,6 +1,22 @@
+pub mod auction;
+pub mod constraints;
 pub mod domain;
+pub mod fees;
+pub mod impact;
+pub mod invariants;
+pub mod market_data;
+pub mod matching;
+pub mod numeric;
+pub mod oco;
 pub mod order_queues;
 pub mod orderbook;
 pub mod orders;
+pub mod pegging;
+pub mod price_band;
+pub mod quote;
+pub mod registry;
 pub mod sequence;
+pub mod stops;
+#[cfg(test)]
+pub mod test_utils;
 pub mod validation;
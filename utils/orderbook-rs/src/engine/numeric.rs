@@ -0,0 +1,169 @@
+use std::cmp::Ordering;
+use std::fmt::Debug;
+
+/// Price representation: floating point, integer ticks or a decimal type
+pub trait Price: Copy + PartialOrd + Debug + Default {
+    /// Only positive prices are accepted by the order book
+    fn is_positive(&self) -> bool;
+
+    /// Conversion for statistics (deviation from reference price, distance),
+    /// may lose precision
+    fn to_f64(&self) -> f64;
+
+    /// Price in the middle between two prices, rounded down for integer ticks
+    fn midpoint(self, other: Self) -> Self;
+
+    /// Price moved up by `offset`, `None` if out of range
+    fn checked_add(self, offset: Self) -> Option<Self>;
+
+    /// Price moved down by `offset`, `None` if out of range
+    fn checked_sub(self, offset: Self) -> Option<Self>;
+
+    /// Total order of valid (positive) prices
+    fn cmp_price(&self, other: &Self) -> Ordering {
+        self.partial_cmp(other).unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Quantity representation: unsigned integer amount of the order asset.
+///
+/// Totals over many orders are accumulated in `u128`.
+pub trait Quantity: Copy + Ord + Debug + Default + Into<u128> {
+    fn zero() -> Self {
+        Self::default()
+    }
+
+    fn is_zero(&self) -> bool {
+        *self == Self::zero()
+    }
+
+    fn checked_add(self, other: Self) -> Option<Self>;
+
+    fn checked_sub(self, other: Self) -> Option<Self>;
+
+    /// Convert result of `u128` arithmetic back, `None` if out of range
+    fn from_u128(value: u128) -> Option<Self>;
+}
+
+macro_rules! float_price {
+    ($t:ty) => {
+        impl Price for $t {
+            fn is_positive(&self) -> bool {
+                *self > 0.0
+            }
+
+            fn to_f64(&self) -> f64 {
+                *self as f64
+            }
+
+            fn midpoint(self, other: Self) -> Self {
+                (self + other) / 2.0
+            }
+
+            fn checked_add(self, offset: Self) -> Option<Self> {
+                Some(self + offset).filter(|price| price.is_finite())
+            }
+
+            fn checked_sub(self, offset: Self) -> Option<Self> {
+                Some(self - offset).filter(|price| price.is_finite())
+            }
+        }
+    };
+}
+
+macro_rules! integer_price {
+    ($t:ty) => {
+        impl Price for $t {
+            fn is_positive(&self) -> bool {
+                *self > 0
+            }
+
+            fn to_f64(&self) -> f64 {
+                *self as f64
+            }
+
+            fn midpoint(self, other: Self) -> Self {
+                // avoid overflow of the sum
+                self / 2 + other / 2 + (self % 2 + other % 2) / 2
+            }
+
+            fn checked_add(self, offset: Self) -> Option<Self> {
+                <$t>::checked_add(self, offset)
+            }
+
+            fn checked_sub(self, offset: Self) -> Option<Self> {
+                <$t>::checked_sub(self, offset)
+            }
+
+            fn cmp_price(&self, other: &Self) -> Ordering {
+                self.cmp(other)
+            }
+        }
+    };
+}
+
+macro_rules! integer_quantity {
+    ($t:ty) => {
+        impl Quantity for $t {
+            fn checked_add(self, other: Self) -> Option<Self> {
+                <$t>::checked_add(self, other)
+            }
+
+            fn checked_sub(self, other: Self) -> Option<Self> {
+                <$t>::checked_sub(self, other)
+            }
+
+            fn from_u128(value: u128) -> Option<Self> {
+                if value > <$t>::MAX as u128 {
+                    None
+                } else {
+                    Some(value as $t)
+                }
+            }
+        }
+    };
+}
+
+float_price!(f32);
+float_price!(f64);
+integer_price!(u32);
+integer_price!(u64);
+integer_price!(u128);
+integer_quantity!(u32);
+integer_quantity!(u64);
+integer_quantity!(u128);
+
+/// Quantity left after a fill, which never exceeds the order quantity
+pub fn left_after<Qty: Quantity>(qty: Qty, fill: Qty) -> Qty {
+    qty.checked_sub(fill).expect("fill exceeds order quantity")
+}
+
+/// Sum of quantities, saturating at `u128::MAX`
+pub fn total<Qty: Quantity, I: IntoIterator<Item = Qty>>(quantities: I) -> u128 {
+    quantities
+        .into_iter()
+        .fold(0u128, |sum, qty| sum.saturating_add(qty.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integer_ticks() {
+        assert_eq!(101u64.midpoint(103), 102);
+        assert_eq!(101u64.midpoint(102), 101);
+        assert_eq!(u64::MAX.midpoint(u64::MAX), u64::MAX);
+        assert!(!0u64.is_positive());
+        assert_eq!(Price::checked_sub(1u64, 2), None);
+        assert_eq!(Price::checked_add(1.5, 0.25), Some(1.75));
+    }
+
+    #[test]
+    fn checked_quantities() {
+        assert_eq!(Quantity::checked_sub(1u32, 2), None);
+        assert_eq!(u32::from_u128(1 << 40), None);
+        assert_eq!(u64::from_u128(7), Some(7));
+        assert_eq!(total(vec![u64::MAX, 1]), u64::MAX as u128 + 1);
+    }
+}
@@ -0,0 +1,26 @@
+use super::domain::OrderSide;
+
+extern crate near_sdk;
+use self::near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use self::near_sdk::serde::{Deserialize, Serialize};
+
+/// What a partial fill of one OCO leg does to the other one.
+///
+/// A complete fill always cancels the other leg.
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
+pub enum PartialFillAction {
+    /// Cancel the other leg at the first fill
+    CancelOther,
+    /// Reduce the other leg by the filled quantity
+    ReduceOther,
+}
+
+/// Link from an order to the other leg of its OCO pair
+#[derive(Debug, Copy, Clone, PartialEq, BorshDeserialize, BorshSerialize)]
+pub struct OcoLink {
+    pub linked_id: u64,
+    pub linked_side: OrderSide,
+    pub on_partial_fill: PartialFillAction,
+}
@@ -1,222 +1,802 @@
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io;
+use std::mem;
 
 use super::domain::OrderSide;
+use super::invariants::InvariantViolation;
+use super::numeric::{self, Quantity};
 
 extern crate near_sdk;
 use self::near_sdk::borsh::{BorshDeserialize, BorshSerialize};
-use self::near_sdk::serde::Serialize;
+use self::near_sdk::serde::{Deserialize, Serialize};
 
-#[derive(Clone, BorshDeserialize, BorshSerialize, Debug, Serialize)]
-pub struct OrderIndex {
+#[derive(Clone, BorshDeserialize, BorshSerialize, Debug, Serialize, Deserialize)]
+pub struct OrderIndex<Price = f64, Qty = u128> {
     pub id: u64,
-    pub price: f64,
-    pub quantity: u128,
+    pub price: Price,
+    pub quantity: Qty,
     pub timestamp: u64,
     pub order_side: OrderSide,
 }
 
 // Arrange at first by price and after that by time
-impl Ord for OrderIndex {
+impl<Price: numeric::Price, Qty> Ord for OrderIndex<Price, Qty> {
     fn cmp(&self, other: &Self) -> Ordering {
-        if self.price < other.price {
-            match self.order_side {
+        match self.price.cmp_price(&other.price) {
+            Ordering::Less => match self.order_side {
                 OrderSide::Bid => Ordering::Less,
                 OrderSide::Ask => Ordering::Greater,
-            }
-        } else if self.price > other.price {
-            match self.order_side {
+            },
+            Ordering::Greater => match self.order_side {
                 OrderSide::Bid => Ordering::Greater,
                 OrderSide::Ask => Ordering::Less,
-            }
-        } else {
+            },
             // FIFO
-            other.timestamp.cmp(&self.timestamp)
+            Ordering::Equal => other.timestamp.cmp(&self.timestamp),
         }
     }
 }
 
-impl PartialOrd for OrderIndex {
+impl<Price: numeric::Price, Qty> PartialOrd for OrderIndex<Price, Qty> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl PartialEq for OrderIndex {
+impl<Price: numeric::Price, Qty> PartialEq for OrderIndex<Price, Qty> {
     fn eq(&self, other: &Self) -> bool {
-        if self.price > other.price || self.price < other.price {
-            false
-        } else {
-            self.timestamp == other.timestamp
-        }
+        self.price.cmp_price(&other.price) == Ordering::Equal && self.timestamp == other.timestamp
+    }
+}
+
+impl<Price: numeric::Price, Qty> Eq for OrderIndex<Price, Qty> {}
+
+/// Handle of a stored order: its slot in the order storage
+type Handle = u64;
+
+#[derive(Clone, Debug)]
+struct Node<T, Price, Qty> {
+    id: u64,
+    price: Price,
+    quantity: Qty,
+    timestamp: u64,
+    hidden: bool,
+    order: T,
+    prev: Option<Handle>,
+    next: Option<Handle>,
+}
+
+impl<T, Price, Qty> Node<T, Price, Qty> {
+    /// Position within the price level, lower goes first
+    fn priority(&self) -> (bool, u64) {
+        (self.hidden, self.timestamp)
+    }
+}
+
+/// Orders at a single price, linked in time priority, hidden orders after
+/// displayed ones. Empty levels are removed.
+#[derive(Default, Clone, Copy, Debug)]
+struct Level {
+    head: Handle,
+    tail: Handle,
+    quantity: u128,
+    displayed: u128,
+    count: u64,
+}
+
+/// Price as a key of price levels, ordered by `numeric::Price::cmp_price`
+#[derive(Clone, Copy, Debug)]
+struct PriceKey<Price>(Price);
+
+impl<Price: numeric::Price> Ord for PriceKey<Price> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp_price(&other.0)
     }
 }
 
-impl Eq for OrderIndex {}
+impl<Price: numeric::Price> PartialOrd for PriceKey<Price> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Price: numeric::Price> PartialEq for PriceKey<Price> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<Price: numeric::Price> Eq for PriceKey<Price> {}
+
+/// Previous state of everything changed since `begin_journal`
+#[derive(Clone, Debug)]
+struct Journal<T, Price, Qty> {
+    // slots added since the start are dropped instead
+    nodes_len: usize,
+    nodes: HashMap<Handle, Option<Node<T, Price, Qty>>>,
+    levels: BTreeMap<PriceKey<Price>, Option<Level>>,
+    handles: HashMap<u64, Option<Handle>>,
+    // handles taken from (`Some`) and returned to (`None`) the free list
+    free: Vec<Option<Handle>>,
+    changed: BTreeSet<PriceKey<Price>>,
+}
 
 /// Public methods
-#[derive(Default, BorshDeserialize, BorshSerialize, Clone, Debug)]
-pub struct OrderQueue<T> {
-    // use Option in order to replace heap in mutable borrow
-    pub idx_queue: Option<Vec<OrderIndex>>,
-    orders: HashMap<u64, T>,
-    op_counter: u64,
-    max_stalled: u64,
+#[derive(Default, Clone, Debug)]
+pub struct OrderQueue<T, Price = f64, Qty = u128> {
+    levels: BTreeMap<PriceKey<Price>, Level>,
+    // slots of removed orders are reused
+    nodes: Vec<Option<Node<T, Price, Qty>>>,
+    free: Vec<Handle>,
+    handles: HashMap<u64, Handle>,
     queue_side: OrderSide,
+    // levels changed since the last `take_changed_levels`, not stored
+    changed: BTreeSet<PriceKey<Price>>,
+    // changes to undo by `rollback_journal`, not stored
+    journal: Option<Journal<T, Price, Qty>>,
 }
 
-impl<T> OrderQueue<T> {
+impl<T: Clone, Price: numeric::Price, Qty: Quantity> OrderQueue<T, Price, Qty> {
     /// Create new order queue
     ///
     /// Queue is universal and could be used for both asks and bids
-    pub fn new(side: OrderSide, max_stalled: u64, capacity: usize) -> Self {
+    pub fn new(side: OrderSide, capacity: usize) -> Self {
         OrderQueue {
-            idx_queue: Some(Vec::with_capacity(capacity)),
-            orders: HashMap::with_capacity(capacity),
-            op_counter: 0,
-            max_stalled,
+            levels: BTreeMap::new(),
+            nodes: Vec::with_capacity(capacity),
+            free: vec![],
+            handles: HashMap::with_capacity(capacity),
             queue_side: side,
+            changed: BTreeSet::new(),
+            journal: None,
         }
     }
 
-    pub fn peek(&mut self) -> Option<&T> {
-        // get best order ID
-        let order_id = self.get_current_order_id()?;
+    pub fn peek(&self) -> Option<&T> {
+        let handle = self.top_level()?.head;
+        Some(&self.node(handle).order)
+    }
+
+    /// Get active order by ID
+    pub fn get(&self, id: u64) -> Option<&T> {
+        let handle = self.handles.get(&id)?;
+        Some(&self.node(*handle).order)
+    }
+
+    /// Active orders at the best price, in time priority
+    pub fn best_level(&self) -> Vec<&T> {
+        match self.top_level() {
+            Some(level) => self
+                .level_handles(level)
+                .map(|handle| &self.node(handle).order)
+                .collect(),
+            None => vec![],
+        }
+    }
 
-        // obtain order info
-        if self.orders.contains_key(&order_id) {
-            self.orders.get(&order_id)
-        } else {
-            self.idx_queue.as_mut().unwrap().pop()?;
-            self.peek()
+    /// Active orders at the price, in time priority
+    pub fn level(&self, price: Price) -> Vec<&T> {
+        match self.levels.get(&PriceKey(price)) {
+            Some(level) => self
+                .level_handles(level)
+                .map(|handle| &self.node(handle).order)
+                .collect(),
+            None => vec![],
         }
     }
 
+    /// Iterate over active orders, starting from the best one
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.iter_handles()
+            .map(move |handle| &self.node(handle).order)
+    }
+
+    /// Indices of active displayed orders, starting from the best one
+    pub fn indices(&self) -> Vec<OrderIndex<Price, Qty>> {
+        self.iter_handles()
+            .filter(|handle| !self.node(*handle).hidden)
+            .map(|handle| {
+                let node = self.node(handle);
+                OrderIndex {
+                    id: node.id,
+                    price: node.price,
+                    quantity: node.quantity,
+                    timestamp: node.timestamp,
+                    order_side: self.queue_side,
+                }
+            })
+            .collect()
+    }
+
+    /// Displayed quantity of price levels, starting from the best one.
+    ///
+    /// Levels with hidden orders only are skipped.
+    pub fn displayed_levels<'a>(&'a self) -> impl Iterator<Item = (Price, u128)> + 'a {
+        let levels: Box<dyn Iterator<Item = (&PriceKey<Price>, &Level)>> = match self.queue_side {
+            OrderSide::Bid => Box::new(self.levels.iter().rev()),
+            OrderSide::Ask => Box::new(self.levels.iter()),
+        };
+        levels
+            .filter(|&(_, level)| level.displayed > 0)
+            .map(|(key, level)| (key.0, level.displayed))
+    }
+
+    /// Displayed quantity of up to `levels` price levels, starting from the best one
+    pub fn depth(&self, levels: usize) -> Vec<(Price, u128)> {
+        self.displayed_levels().take(levels).collect()
+    }
+
+    /// New displayed quantity of every level changed since the previous call,
+    /// in ascending price order. Removed levels have zero quantity.
+    ///
+    /// Changes of hidden orders are not tracked.
+    pub fn take_changed_levels(&mut self) -> Vec<(Price, u128)> {
+        let changed = mem::take(&mut self.changed);
+        changed
+            .into_iter()
+            .map(|key| {
+                let quantity = self.levels.get(&key).map_or(0, |level| level.displayed);
+                (key.0, quantity)
+            })
+            .collect()
+    }
+
     pub fn pop(&mut self) -> Option<T> {
-        // remove order index from queue in any case
-        let order_id = self.idx_queue.as_mut()?.pop()?.id;
+        let handle = self.top_level()?.head;
+        Some(self.remove(handle))
+    }
+
+    // Add new limit order to the queue
+    pub fn insert(&mut self, id: u64, price: Price, qty: Qty, ts: u64, order: T) -> bool {
+        self.insert_node(id, price, qty, ts, false, order)
+    }
 
-        if self.orders.contains_key(&order_id) {
-            self.orders.remove(&order_id)
-        } else {
-            self.pop()
+    /// Add limit order which is not displayed: it is matched after displayed
+    /// orders of its price level and excluded from indices and depth
+    pub fn insert_hidden(&mut self, id: u64, price: Price, qty: Qty, ts: u64, order: T) -> bool {
+        self.insert_node(id, price, qty, ts, true, order)
+    }
+
+    // use it when price was changed
+    pub fn amend(&mut self, id: u64, price: Price, qty: Qty, ts: u64, order: T) -> bool {
+        let handle = match self.handles.get(&id) {
+            Some(handle) => *handle,
+            None => return false,
+        };
+
+        // amended order loses its time priority
+        self.unlink(handle);
+        {
+            let node = self.node_mut(handle);
+            node.price = price;
+            node.quantity = qty;
+            node.timestamp = ts;
+            node.order = order;
         }
+        self.link(handle);
+        true
     }
 
-    // Add new limit order to the queue
-    pub fn insert(&mut self, id: u64, price: f64, qty: u128, ts: u64, order: T) -> bool {
-        if self.orders.contains_key(&id) {
+    pub fn cancel(&mut self, id: u64) -> bool {
+        match self.handles.get(&id) {
+            Some(handle) => {
+                let handle = *handle;
+                self.remove(handle);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancel all orders matching the filter at once.
+    ///
+    /// Returns cancelled orders, starting from the best one.
+    pub fn cancel_where<F>(&mut self, filter: F) -> Vec<T>
+    where
+        F: Fn(&T) -> bool,
+    {
+        let handles: Vec<Handle> = self
+            .iter_handles()
+            .filter(|handle| filter(&self.node(*handle).order))
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| self.remove(handle))
+            .collect()
+    }
+
+    /// Used internally when current order is partially matched.
+    ///
+    /// Note: do not modify price or time, cause index doesn't change!
+    pub fn modify_current_order(&mut self, qty: Qty, new_order: T) -> bool {
+        match self.top_level() {
+            Some(level) => {
+                let handle = level.head;
+                self.update(handle, qty, new_order);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Used internally when an order of the best level is partially matched.
+    ///
+    /// Note: do not modify price or time, cause index doesn't change!
+    pub fn modify_order(&mut self, id: u64, qty: Qty, new_order: T) -> bool {
+        match self.handles.get(&id) {
+            Some(handle) => {
+                let handle = *handle;
+                self.update(handle, qty, new_order);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Start recording changes, so that they can be undone at once
+    pub fn begin_journal(&mut self) {
+        self.journal = Some(Journal {
+            nodes_len: self.nodes.len(),
+            nodes: HashMap::new(),
+            levels: BTreeMap::new(),
+            handles: HashMap::new(),
+            free: vec![],
+            changed: self.changed.clone(),
+        });
+    }
+
+    /// Whether changes are recorded by `begin_journal`
+    pub fn is_journaling(&self) -> bool {
+        self.journal.is_some()
+    }
+
+    /// Keep the changes made since `begin_journal`
+    pub fn commit_journal(&mut self) {
+        self.journal = None;
+    }
+
+    /// Undo the changes made since `begin_journal`, restoring time priority
+    /// of the orders as well
+    pub fn rollback_journal(&mut self) {
+        let journal = match self.journal.take() {
+            Some(journal) => journal,
+            None => return,
+        };
+
+        self.nodes.truncate(journal.nodes_len);
+        for (handle, node) in journal.nodes {
+            self.nodes[handle as usize] = node;
+        }
+        for (key, level) in journal.levels {
+            match level {
+                Some(level) => self.levels.insert(key, level),
+                None => self.levels.remove(&key),
+            };
+        }
+        for (id, handle) in journal.handles {
+            match handle {
+                Some(handle) => self.handles.insert(id, handle),
+                None => self.handles.remove(&id),
+            };
+        }
+        for taken in journal.free.into_iter().rev() {
+            match taken {
+                Some(handle) => self.free.push(handle),
+                None => {
+                    self.free.pop();
+                }
+            }
+        }
+        self.changed = journal.changed;
+    }
+
+    /// Verify consistency of price levels and stored orders.
+    ///
+    /// `index_of` returns price and quantity of the stored order, which must
+    /// match its index.
+    pub fn check_invariants<F>(&self, index_of: F) -> Result<(), InvariantViolation>
+    where
+        F: Fn(&T) -> (Price, Qty),
+    {
+        let side = self.queue_side;
+        let mut visited = HashSet::with_capacity(self.handles.len());
+
+        for (key, level) in &self.levels {
+            let price = key.0.to_f64();
+            let mut prev: Option<Handle> = None;
+            let mut priority = (false, 0);
+            let mut quantity = 0u128;
+            let mut displayed = 0u128;
+            let mut count = 0;
+
+            for handle in self.level_handles(level) {
+                let node = match self.nodes.get(handle as usize) {
+                    Some(Some(node)) if node.prev == prev => node,
+                    _ => return Err(InvariantViolation::BrokenLevel { side, price }),
+                };
+                let id = node.id;
+                if !visited.insert(handle) || self.handles.get(&id) != Some(&handle) {
+                    return Err(InvariantViolation::DuplicateIndex { side, id });
+                }
+                let (order_price, order_qty) = index_of(&node.order);
+                if PriceKey(node.price) != *key
+                    || order_price.cmp_price(&node.price) != Ordering::Equal
+                    || order_qty != node.quantity
+                {
+                    return Err(InvariantViolation::IndexMismatch { side, id });
+                }
+                if node.quantity.is_zero() {
+                    return Err(InvariantViolation::EmptyOrder { side, id });
+                }
+                if node.priority() < priority {
+                    return Err(InvariantViolation::UnsortedIndex { side, id });
+                }
+
+                prev = Some(handle);
+                priority = node.priority();
+                quantity = quantity.saturating_add(node.quantity.into());
+                if !node.hidden {
+                    displayed = displayed.saturating_add(node.quantity.into());
+                }
+                count += 1;
+            }
+
+            if prev != Some(level.tail)
+                || quantity != level.quantity
+                || displayed != level.displayed
+                || count != level.count
+            {
+                return Err(InvariantViolation::BrokenLevel { side, price });
+            }
+        }
+
+        for (id, handle) in &self.handles {
+            if !visited.contains(handle) {
+                return Err(InvariantViolation::MissingIndex { side, id: *id });
+            }
+        }
+
+        Ok(())
+    }
+
+    /* Internal methods */
+
+    fn insert_node(
+        &mut self,
+        id: u64,
+        price: Price,
+        qty: Qty,
+        ts: u64,
+        hidden: bool,
+        order: T,
+    ) -> bool {
+        if self.handles.contains_key(&id) {
             // do not update existing order
             return false;
         }
 
-        // store new order
-        self.idx_queue.as_mut().unwrap().push(OrderIndex {
+        self.journal_handle(id);
+        let handle = self.store(Node {
             id,
             price,
-            timestamp: ts,
             quantity: qty,
-            order_side: self.queue_side,
+            timestamp: ts,
+            hidden,
+            order,
+            prev: None,
+            next: None,
         });
-        self.orders.insert(id, order);
+        self.handles.insert(id, handle);
+        self.link(handle);
         true
     }
 
-    // use it when price was changed
-    pub fn amend(&mut self, id: u64, price: f64, qty: u128, ts: u64, order: T) -> bool {
-        if self.orders.contains_key(&id) {
-            // store new order data
-            self.orders.insert(id, order);
-            self.rebuild_idx(id, price, qty, ts);
-            true
-        } else {
-            false
+    fn top_level(&self) -> Option<&Level> {
+        match self.queue_side {
+            OrderSide::Bid => self.levels.values().next_back(),
+            OrderSide::Ask => self.levels.values().next(),
         }
     }
 
-    pub fn cancel(&mut self, id: u64) -> bool {
-        match self.orders.remove(&id) {
-            Some(_) => {
-                self.clean_check();
-                true
+    /// Iterate over handles of active orders, starting from the best one
+    fn iter_handles(&self) -> impl Iterator<Item = Handle> + '_ {
+        let levels: Box<dyn Iterator<Item = &Level>> = match self.queue_side {
+            OrderSide::Bid => Box::new(self.levels.values().rev()),
+            OrderSide::Ask => Box::new(self.levels.values()),
+        };
+        levels.flat_map(move |level| self.level_handles(level))
+    }
+
+    fn level_handles<'a>(&'a self, level: &Level) -> LevelHandles<'a, T, Price, Qty> {
+        LevelHandles {
+            nodes: &self.nodes,
+            next: Some(level.head),
+        }
+    }
+
+    fn node(&self, handle: Handle) -> &Node<T, Price, Qty> {
+        self.nodes[handle as usize]
+            .as_ref()
+            .expect("stale order handle")
+    }
+
+    fn node_mut(&mut self, handle: Handle) -> &mut Node<T, Price, Qty> {
+        self.journal_node(handle);
+        self.nodes[handle as usize]
+            .as_mut()
+            .expect("stale order handle")
+    }
+
+    fn store(&mut self, node: Node<T, Price, Qty>) -> Handle {
+        match self.free.pop() {
+            Some(handle) => {
+                if let Some(ref mut journal) = self.journal {
+                    journal.free.push(Some(handle));
+                }
+                self.journal_node(handle);
+                self.nodes[handle as usize] = Some(node);
+                handle
+            }
+            None => {
+                self.nodes.push(Some(node));
+                (self.nodes.len() - 1) as Handle
             }
-            None => false,
         }
     }
 
-    /* Internal methods */
+    fn remove(&mut self, handle: Handle) -> T {
+        self.unlink(handle);
+        self.journal_node(handle);
+        let node = self.nodes[handle as usize]
+            .take()
+            .expect("stale order handle");
+        if let Some(ref mut journal) = self.journal {
+            journal.free.push(None);
+        }
+        self.free.push(handle);
+        self.journal_handle(node.id);
+        self.handles.remove(&node.id);
+        node.order
+    }
 
-    /// Used internally when current order is partially matched.
-    ///
-    /// Note: do not modify price or time, cause index doesn't change!
-    pub fn modify_current_order(&mut self, new_order: T) -> bool {
-        if let Some(order_id) = self.get_current_order_id() {
-            if self.orders.contains_key(&order_id) {
-                self.orders.insert(order_id, new_order);
-                return true;
+    /// Record the slot before its first change, slots added since the start
+    /// of the journal are just dropped
+    fn journal_node(&mut self, handle: Handle) {
+        if let Some(ref mut journal) = self.journal {
+            if handle as usize >= journal.nodes_len || journal.nodes.contains_key(&handle) {
+                return;
             }
+            journal
+                .nodes
+                .insert(handle, self.nodes[handle as usize].clone());
         }
-        false
     }
 
-    /// Verify if queue should be cleaned
-    fn clean_check(&mut self) {
-        if self.op_counter > self.max_stalled {
-            self.op_counter = 0;
-            self.remove_stalled()
-        } else {
-            self.op_counter += 1;
+    fn journal_level(&mut self, key: PriceKey<Price>) {
+        if let Some(ref mut journal) = self.journal {
+            let level = self.levels.get(&key).cloned();
+            journal.levels.entry(key).or_insert(level);
         }
     }
 
-    /// Remove dangling indices without orders from queue
-    fn remove_stalled(&mut self) {
-        if let Some(idx_queue) = self.idx_queue.take() {
-            let mut active_orders = idx_queue;
-            active_orders.retain(|order_ptr| self.orders.contains_key(&order_ptr.id));
-            self.idx_queue = Some(Vec::from(active_orders));
+    fn journal_handle(&mut self, id: u64) {
+        if let Some(ref mut journal) = self.journal {
+            let handle = self.handles.get(&id).cloned();
+            journal.handles.entry(id).or_insert(handle);
         }
     }
 
-    /// Recreate order-index queue with changed index info
-    fn rebuild_idx(&mut self, id: u64, price: f64, qty: u128, ts: u64) {
-        if let Some(idx_queue) = self.idx_queue.take() {
-            // deconstruct queue
-            let mut active_orders = idx_queue;
-            // remove old idx value
-            active_orders.retain(|order_ptr| order_ptr.id != id);
-            // insert new one
-            active_orders.push(OrderIndex {
-                id,
-                price,
-                quantity: qty,
-                timestamp: ts,
-                order_side: self.queue_side,
-            });
-            // construct new queue
-            let amended_queue = Vec::from(active_orders);
-            self.idx_queue = Some(amended_queue);
+    /// Change quantity of the order in place
+    fn update(&mut self, handle: Handle, qty: Qty, order: T) {
+        let (key, old_qty, hidden) = {
+            let node = self.node_mut(handle);
+            let old_qty = node.quantity;
+            node.quantity = qty;
+            node.order = order;
+            (PriceKey(node.price), old_qty, node.hidden)
+        };
+        if !hidden {
+            self.changed.insert(key);
+        }
+        self.journal_level(key);
+        if let Some(level) = self.levels.get_mut(&key) {
+            level.quantity = level.quantity - old_qty.into() + qty.into();
+            if !hidden {
+                level.displayed = level.displayed - old_qty.into() + qty.into();
+            }
         }
     }
 
-    /// Return ID of current order in queue
-    fn get_current_order_id(&self) -> Option<u64> {
-        let order_id = self.idx_queue.as_ref()?.last()?;
-        Some(order_id.id)
+    /// Add order to its price level, behind orders with the same or higher priority
+    fn link(&mut self, handle: Handle) {
+        let (key, quantity, priority) = {
+            let node = self.node(handle);
+            (PriceKey(node.price), node.quantity.into(), node.priority())
+        };
+        let hidden = priority.0;
+        let displayed = if hidden { 0 } else { quantity };
+        if !hidden {
+            self.changed.insert(key);
+        }
+        self.journal_level(key);
+
+        let mut level = match self.levels.get(&key) {
+            Some(level) => *level,
+            None => {
+                self.levels.insert(
+                    key,
+                    Level {
+                        head: handle,
+                        tail: handle,
+                        quantity,
+                        displayed,
+                        count: 1,
+                    },
+                );
+                return;
+            }
+        };
+
+        // orders usually arrive in time order, so the search stops at the tail
+        let mut prev = Some(level.tail);
+        while let Some(prev_handle) = prev {
+            if self.node(prev_handle).priority() <= priority {
+                break;
+            }
+            prev = self.node(prev_handle).prev;
+        }
+        let next = match prev {
+            Some(prev_handle) => self.node(prev_handle).next,
+            None => Some(level.head),
+        };
+
+        {
+            let node = self.node_mut(handle);
+            node.prev = prev;
+            node.next = next;
+        }
+        match prev {
+            Some(prev_handle) => self.node_mut(prev_handle).next = Some(handle),
+            None => level.head = handle,
+        }
+        match next {
+            Some(next_handle) => self.node_mut(next_handle).prev = Some(handle),
+            None => level.tail = handle,
+        }
+
+        level.quantity += quantity;
+        level.displayed += displayed;
+        level.count += 1;
+        self.levels.insert(key, level);
+    }
+
+    /// Remove order from its price level, dropping the level when it gets empty
+    fn unlink(&mut self, handle: Handle) {
+        let (key, quantity, hidden, prev, next) = {
+            let node = self.node_mut(handle);
+            let links = (node.prev.take(), node.next.take());
+            let key = PriceKey(node.price);
+            (key, node.quantity.into(), node.hidden, links.0, links.1)
+        };
+        if !hidden {
+            self.changed.insert(key);
+        }
+        self.journal_level(key);
+
+        if let Some(prev_handle) = prev {
+            self.node_mut(prev_handle).next = next;
+        }
+        if let Some(next_handle) = next {
+            self.node_mut(next_handle).prev = prev;
+        }
+
+        let mut level = self.levels[&key];
+        if level.count == 1 {
+            self.levels.remove(&key);
+            return;
+        }
+        level.quantity -= quantity;
+        if !hidden {
+            level.displayed -= quantity;
+        }
+        level.count -= 1;
+        if prev.is_none() {
+            level.head = next.expect("broken price level");
+        }
+        if next.is_none() {
+            level.tail = prev.expect("broken price level");
+        }
+        self.levels.insert(key, level);
+    }
+}
+
+/// Stored as a list of orders in priority order, price levels are rebuilt on load
+impl<T, Price, Qty> BorshSerialize for OrderQueue<T, Price, Qty>
+where
+    T: Clone + BorshSerialize,
+    Price: numeric::Price + BorshSerialize,
+    Qty: Quantity + BorshSerialize,
+{
+    fn serialize<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        BorshSerialize::serialize(&self.queue_side, writer)?;
+        BorshSerialize::serialize(&(self.handles.len() as u64), writer)?;
+        for handle in self.iter_handles() {
+            let node = self.node(handle);
+            BorshSerialize::serialize(&node.id, writer)?;
+            BorshSerialize::serialize(&node.price, writer)?;
+            BorshSerialize::serialize(&node.quantity, writer)?;
+            BorshSerialize::serialize(&node.timestamp, writer)?;
+            BorshSerialize::serialize(&node.hidden, writer)?;
+            BorshSerialize::serialize(&node.order, writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T, Price, Qty> BorshDeserialize for OrderQueue<T, Price, Qty>
+where
+    T: Clone + BorshDeserialize,
+    Price: numeric::Price + BorshDeserialize,
+    Qty: Quantity + BorshDeserialize,
+{
+    fn deserialize(buf: &mut &[u8]) -> io::Result<Self> {
+        let side: OrderSide = BorshDeserialize::deserialize(buf)?;
+        let len: u64 = BorshDeserialize::deserialize(buf)?;
+        let mut queue = OrderQueue::new(side, 0);
+        for _ in 0..len {
+            let id: u64 = BorshDeserialize::deserialize(buf)?;
+            let price: Price = BorshDeserialize::deserialize(buf)?;
+            let qty: Qty = BorshDeserialize::deserialize(buf)?;
+            let ts: u64 = BorshDeserialize::deserialize(buf)?;
+            let hidden: bool = BorshDeserialize::deserialize(buf)?;
+            let order: T = BorshDeserialize::deserialize(buf)?;
+            // orders of a level come in priority order and are appended to its tail
+            if !queue.insert_node(id, price, qty, ts, hidden, order) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "duplicate order ID",
+                ));
+            }
+        }
+        queue.changed.clear();
+        Ok(queue)
+    }
+}
+
+/// Iterator over handles of a single price level
+struct LevelHandles<'a, T: 'a, Price: 'a, Qty: 'a> {
+    nodes: &'a [Option<Node<T, Price, Qty>>],
+    next: Option<Handle>,
+}
+
+impl<'a, T, Price, Qty> Iterator for LevelHandles<'a, T, Price, Qty> {
+    type Item = Handle;
+
+    fn next(&mut self) -> Option<Handle> {
+        let handle = self.next?;
+        self.next = match self.nodes.get(handle as usize) {
+            Some(Some(node)) => node.next,
+            _ => None,
+        };
+        Some(handle)
     }
 }
 
 #[cfg(test)]
 mod test {
+    use super::super::test_utils::set_block_timestamp;
     use super::*;
 
-    #[derive(Debug, Eq, PartialEq)]
+    #[derive(Debug, Clone, Eq, PartialEq)]
     struct TestOrder {
         pub name: &'static str,
     }
 
     fn get_queue_empty(side: OrderSide) -> OrderQueue<TestOrder> {
-        OrderQueue::new(side, 5, 10)
+        set_block_timestamp(0);
+        OrderQueue::new(side, 10)
     }
 
     fn get_current_time() -> u64 {
@@ -227,7 +807,13 @@ mod test {
     fn get_queue_bids() -> OrderQueue<TestOrder> {
         let mut bid_queue = get_queue_empty(OrderSide::Bid);
 
-        assert!(bid_queue.insert(1, 1.01, 2, get_current_time(), TestOrder { name: "low bid" },));
+        assert!(bid_queue.insert(
+            1,
+            1.01,
+            2,
+            get_current_time(),
+            TestOrder { name: "low bid" },
+        ));
         assert!(bid_queue.insert(
             2,
             1.02,
@@ -332,9 +918,12 @@ mod test {
     fn queue_operations_modify_order() {
         let mut bid_queue = get_queue_bids();
 
-        assert!(bid_queue.modify_current_order(TestOrder {
-            name: "current bid partially matched"
-        },));
+        assert!(bid_queue.modify_current_order(
+            1,
+            TestOrder {
+                name: "current bid partially matched"
+            },
+        ));
 
         assert_eq!(
             bid_queue.pop().unwrap().name,
@@ -389,6 +978,19 @@ mod test {
         assert_eq!(bid_queue.pop().unwrap().name, "low bid");
     }
 
+    #[test]
+    fn queue_operations_cancel_where() {
+        let mut bid_queue = get_queue_bids();
+
+        let cancelled = bid_queue.cancel_where(|order| order.name.starts_with("high"));
+        assert_eq!(cancelled.len(), 2);
+        assert_eq!(cancelled[0].name, "high bid first");
+        assert_eq!(cancelled[1].name, "high bid second");
+
+        assert_eq!(bid_queue.indices().len(), 1);
+        assert_eq!(bid_queue.pop().unwrap().name, "low bid");
+    }
+
     #[test]
     fn queue_operations_cancel_order2() {
         let mut ask_queue = get_queue_asks();
@@ -398,4 +1000,107 @@ mod test {
         assert_eq!(ask_queue.pop().unwrap().name, "low ask first");
         assert_eq!(ask_queue.pop().unwrap().name, "high ask");
     }
+
+    #[test]
+    fn queue_operations_time_priority() {
+        let mut ask_queue = get_queue_empty(OrderSide::Ask);
+        assert!(ask_queue.insert(1, 1.01, 1, 20, TestOrder { name: "late" }));
+        assert!(ask_queue.insert(2, 1.01, 1, 10, TestOrder { name: "early" }));
+        assert!(ask_queue.insert(3, 1.01, 1, 20, TestOrder { name: "late 2" }));
+        assert_eq!(ask_queue.check_invariants(|_| (1.01, 1)), Ok(()));
+
+        // cancelled slot is reused by the next order
+        assert!(ask_queue.cancel(1));
+        assert!(ask_queue.insert(4, 1.01, 1, 30, TestOrder { name: "last" }));
+        assert_eq!(ask_queue.check_invariants(|_| (1.01, 1)), Ok(()));
+
+        let ids: Vec<u64> = ask_queue.indices().iter().map(|idx| idx.id).collect();
+        assert_eq!(ids, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn queue_operations_journal() {
+        let mut ask_queue = get_queue_asks();
+        ask_queue.take_changed_levels();
+
+        ask_queue.begin_journal();
+        assert_eq!(ask_queue.pop().unwrap().name, "low ask first");
+        assert!(ask_queue.cancel(2));
+        assert!(ask_queue.insert(4, 1.00, 1, 0, TestOrder { name: "new" }));
+        assert!(ask_queue.insert(5, 1.03, 1, 0, TestOrder { name: "new 2" }));
+        assert!(ask_queue.modify_order(3, 1, TestOrder { name: "matched" }));
+        assert!(ask_queue.amend(3, 1.02, 1, 0, TestOrder { name: "amended" }));
+
+        // same time priority as before, even at the same timestamp
+        ask_queue.rollback_journal();
+        assert!(!ask_queue.is_journaling());
+        assert!(ask_queue.take_changed_levels().is_empty());
+        assert_eq!(ask_queue.depth(5), vec![(1.01, 3), (1.02, 1)]);
+        assert_eq!(ask_queue.pop().unwrap().name, "low ask first");
+        assert_eq!(ask_queue.pop().unwrap().name, "low ask second");
+        assert_eq!(ask_queue.pop().unwrap().name, "high ask");
+        assert_eq!(ask_queue.pop(), None);
+
+        // committed changes are kept
+        let mut ask_queue = get_queue_asks();
+        ask_queue.begin_journal();
+        assert!(ask_queue.cancel(1));
+        ask_queue.commit_journal();
+        ask_queue.rollback_journal();
+        assert_eq!(ask_queue.peek().unwrap().name, "low ask second");
+    }
+
+    #[test]
+    fn queue_operations_changed_levels() {
+        let mut bid_queue = get_queue_bids();
+        assert_eq!(bid_queue.depth(5), vec![(1.02, 3), (1.01, 2)]);
+        assert_eq!(bid_queue.depth(1), vec![(1.02, 3)]);
+        bid_queue.take_changed_levels();
+
+        bid_queue.cancel(1);
+        bid_queue.cancel(2);
+        assert!(bid_queue.insert(4, 1.03, 4, 0, TestOrder { name: "top bid" }));
+        assert_eq!(
+            bid_queue.take_changed_levels(),
+            vec![(1.01, 0), (1.02, 2), (1.03, 4)]
+        );
+        assert!(bid_queue.take_changed_levels().is_empty());
+    }
+
+    #[test]
+    fn queue_operations_hidden() {
+        let mut ask_queue = get_queue_empty(OrderSide::Ask);
+        assert!(ask_queue.insert_hidden(1, 1.01, 5, 10, TestOrder { name: "hidden" }));
+        assert!(ask_queue.insert_hidden(
+            2,
+            1.00,
+            5,
+            10,
+            TestOrder {
+                name: "hidden best"
+            }
+        ));
+        assert!(ask_queue.insert(3, 1.01, 1, 20, TestOrder { name: "displayed" }));
+        assert_eq!(
+            ask_queue.check_invariants(|order| match order.name {
+                "displayed" => (1.01, 1),
+                "hidden" => (1.01, 5),
+                _ => (1.00, 5),
+            }),
+            Ok(())
+        );
+
+        // hidden orders are matched, but not shown
+        assert_eq!(ask_queue.peek().unwrap().name, "hidden best");
+        assert_eq!(ask_queue.depth(5), vec![(1.01, 1)]);
+        let ids: Vec<u64> = ask_queue.indices().iter().map(|idx| idx.id).collect();
+        assert_eq!(ids, vec![3]);
+        assert_eq!(ask_queue.take_changed_levels(), vec![(1.01, 1)]);
+
+        // displayed order goes first at the same price
+        ask_queue.pop();
+        assert_eq!(ask_queue.pop().unwrap().name, "displayed");
+        assert_eq!(ask_queue.take_changed_levels(), vec![(1.01, 0)]);
+        assert_eq!(ask_queue.pop().unwrap().name, "hidden");
+    }
 }
@@ -1,24 +1,45 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
+use std::mem;
 
-use super::domain::{Order, OrderSide, OrderType};
+use super::auction;
+use super::constraints::FillConstraint;
+use super::domain::{Order, OrderSide, OrderType, TradingPhase};
+use super::fees::{FeeAsset, FeeSchedule};
+use super::impact::{self, DepthPoint, Impact};
+use super::invariants::InvariantViolation;
+use super::market_data::{self, BookDelta, BookSnapshot, CHECKSUM_LEVELS};
+use super::matching::{Fifo, MatchingPolicy};
+use super::numeric::{self, Quantity};
+use super::oco::{OcoLink, PartialFillAction};
 use super::order_queues::{OrderQueue};
-use super::orders::OrderRequest;
+use super::orders::{self, OrderRequest};
+use super::pegging::Peg;
+use super::price_band::{BandAction, PriceBand, ReferencePrice};
+use super::quote::Quote;
 use super::sequence;
-use super::validation::OrderRequestValidator;
+use super::stops::TrailingStop;
+use super::validation::{OrderRequestValidator, ValidationError};
 
-const MIN_SEQUENCE_ID: u64 = 1;
-const MAX_SEQUENCE_ID: u64 = 1000;
-const MAX_STALLED_INDICES_IN_QUEUE: u64 = 10;
+/// Range of order IDs, shared by all books of a `MarketRegistry`
+pub const MIN_SEQUENCE_ID: u64 = 1;
+pub const MAX_SEQUENCE_ID: u64 = 1000;
 const ORDER_QUEUE_INIT_CAPACITY: usize = 500;
+const MAX_FILLS_PER_REQUEST: u64 = 50;
+// client order IDs of inactive orders are released after a day (in ns)
+const CLIENT_ORDER_ID_TTL: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Version of JSON representation of requests and results (see SCHEMA.md)
+pub const SCHEMA_VERSION: u32 = 1;
 
 extern crate near_sdk;
 use self::near_sdk::borsh::{BorshDeserialize, BorshSerialize};
-use self::near_sdk::serde::Serialize;
+use self::near_sdk::serde::{Deserialize, Serialize};
 
-pub type OrderProcessingResult = Vec<Result<Success, Failed>>;
+pub type OrderProcessingResult<Price = f64, Qty = u128> = Vec<Result<Success<Price, Qty>, Failed>>;
 
-#[derive(Debug, Serialize)]
-pub enum Success {
+#[derive(Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+pub enum Success<Price = f64, Qty = u128> {
     Accepted {
         id: u64,
         order_type: OrderType,
@@ -30,8 +51,11 @@ pub enum Success {
         order_id: u64,
         side: OrderSide,
         order_type: OrderType,
-        price: f64,
-        qty: u128,
+        price: Price,
+        qty: Qty,
+        // negative for a maker rebate
+        fee: i128,
+        fee_asset: FeeAsset,
         order_creator: String,
         ts: u64,
     },
@@ -40,41 +64,193 @@ pub enum Success {
         order_id: u64,
         side: OrderSide,
         order_type: OrderType,
-        price: f64,
-        qty: u128,
+        price: Price,
+        qty: Qty,
+        fee: i128,
+        fee_asset: FeeAsset,
         order_creator: String,
         ts: u64,
     },
 
     Amended {
         id: u64,
-        price: f64,
-        qty: u128,
+        price: Price,
+        qty: Qty,
         ts: u64,
     },
 
     Cancelled {
         id: u64,
+        side: OrderSide,
+        qty: Qty,
+        ts: u64,
+    },
+
+    TradingHalted {
+        reference_price: Price,
+        price: Price,
+        until: u64,
+        ts: u64,
+    },
+
+    TradingResumed {
+        ts: u64,
+    },
+
+    AuctionUncrossed {
+        price: Price,
+        // total volume, which may exceed a single order quantity
+        qty: u128,
+        ts: u64,
+    },
+
+    MatchingSuspended {
+        order_id: u64,
+        qty: Qty,
+        ts: u64,
+    },
+
+    Repriced {
+        id: u64,
+        side: OrderSide,
+        price: Price,
+        ts: u64,
+    },
+
+    StopTriggered {
+        id: u64,
+        side: OrderSide,
+        // type of the order the stop turns into
+        order_type: OrderType,
+        // trade price which triggered the stop
+        price: Price,
+        ts: u64,
+    },
+
+    LinkedOrderReduced {
+        id: u64,
+        side: OrderSide,
+        // released quantity
+        qty: Qty,
+        // zero when the order is cancelled
+        remaining: Qty,
+        order_creator: String,
+        ts: u64,
+    },
+
+    // unmatched rest of a triggered or resumed market order, rest of an
+    // order rejected by the price band or a constrained order which would
+    // rest across the book, which the creator gets back
+    Unfilled {
+        id: u64,
+        side: OrderSide,
+        qty: Qty,
+        order_creator: String,
         ts: u64,
     },
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
 pub enum Failed {
-    ValidationFailed(String),
+    ValidationFailed(ValidationError),
     DuplicateOrderID(u64),
     NoMatch(u64),
     OrderNotFound(u64),
+    PriceBandViolation(u64),
+    TradingHalted(u64),
+    AuctionInProgress,
+    BatchRejected,
+    AmendCrossesBook(u64),
+    UnknownMarket,
+    DuplicateClientOrderID(String),
+    ClientOrderNotFound(String),
+    OrderIDsExhausted,
+}
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum BatchMode {
+    /// Apply all requests or none of them
+    AllOrNothing,
+    /// Apply every request independently
+    BestEffort,
+}
+
+/// State of the book besides its queues and client order IDs before a batch
+struct BatchUndo<Price, Qty> {
+    seq: sequence::TradeSequence,
+    last_trade_price: Option<Price>,
+    halted_until: Option<u64>,
+    trading_phase: TradingPhase,
+    pending_orders: Vec<PendingOrder<Price, Qty>>,
+    book_seq: u64,
+    // number of book deltas
+    book_deltas: usize,
+    pegged_orders: Vec<(OrderSide, u64)>,
+    peg_references: (Option<Price>, Option<Price>),
+    trailing_stops: Vec<TrailingStop<Price, Qty>>,
+    oco_links: HashMap<u64, OcoLink>,
+}
+
+/// Limit order of a request, matched and stored once it gets an ID
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+struct NewLimitOrder<Price, Qty> {
+    side: OrderSide,
+    price: Price,
+    qty: Qty,
+    order_creator: String,
+    client_order_id: Option<String>,
+    hidden: bool,
+    peg: Option<Peg<Price>>,
+    constraint: Option<FillConstraint<Qty>>,
+    ts: u64,
+}
+
+/// New order which has reached fills limit and waits for `resume_matching`,
+/// a market one has no price
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+struct PendingOrder<Price, Qty> {
+    order_id: u64,
+    order_type: OrderType,
+    order: NewLimitOrder<Price, Qty>,
+    reference_price: Option<Price>,
 }
 
 #[derive(Default, BorshDeserialize, BorshSerialize, Clone)]
-pub struct Orderbook<Asset> {
+pub struct Orderbook<Asset, Policy = Fifo, Price = f64, Qty = u128>
+where
+    Price: numeric::Price,
+    Qty: Quantity,
+{
     order_asset: Asset,
     price_asset: Asset,
-    pub bid_queue: OrderQueue<Order<Asset>>,
-    pub ask_queue: OrderQueue<Order<Asset>>,
+    pub bid_queue: OrderQueue<Order<Asset, Price, Qty>, Price, Qty>,
+    pub ask_queue: OrderQueue<Order<Asset, Price, Qty>, Price, Qty>,
     seq: sequence::TradeSequence,
     order_validator: OrderRequestValidator<Asset>,
+    price_band: Option<PriceBand>,
+    fees: FeeSchedule,
+    last_trade_price: Option<Price>,
+    halted_until: Option<u64>,
+    trading_phase: TradingPhase,
+    matching_policy: Policy,
+    max_fills: u64,
+    pending_orders: Vec<PendingOrder<Price, Qty>>,
+    // (order creator, client order ID) => (order ID, time taken), kept to reject
+    // retried submissions
+    client_order_ids: HashMap<(String, String), (u64, u64)>,
+    // sequence number of the last book delta
+    book_seq: u64,
+    book_deltas: Vec<BookDelta<Price>>,
+    // pegged orders, including filled or cancelled ones until the next repricing
+    pegged_orders: Vec<(OrderSide, u64)>,
+    // best (bid, ask) the pegged orders were priced for
+    peg_references: (Option<Price>, Option<Price>),
+    // in the order of arrival, which is the order of triggering
+    trailing_stops: Vec<TrailingStop<Price, Qty>>,
+    // both legs of OCO pairs, order ID => the other leg
+    oco_links: HashMap<u64, OcoLink>,
+    // IDs given by `process_order_with_ids`, taken before the own sequence
+    assigned_ids: Vec<u64>,
 }
 
 fn get_current_time() -> u64 {
@@ -82,6 +258,52 @@ fn get_current_time() -> u64 {
     return env::block_timestamp();
 }
 
+/// Report quantity of an order which is neither matched nor stored
+fn report_unfilled<Price, Qty: Quantity>(
+    results: &mut OrderProcessingResult<Price, Qty>,
+    order_id: u64,
+    side: OrderSide,
+    qty: Qty,
+    order_creator: String,
+) {
+    if !qty.is_zero() {
+        results.push(Ok(Success::Unfilled {
+            id: order_id,
+            side,
+            qty,
+            order_creator,
+            ts: get_current_time(),
+        }));
+    }
+}
+
+/// Client order ID of the new order request with its creator
+fn client_order_key<Asset, Price, Qty>(
+    request: &OrderRequest<Asset, Price, Qty>,
+) -> Option<(String, String)>
+where
+    Asset: Debug + Clone,
+{
+    match *request {
+        OrderRequest::NewMarketOrder {
+            ref order_creator,
+            client_order_id: Some(ref client_order_id),
+            ..
+        }
+        | OrderRequest::NewLimitOrder {
+            ref order_creator,
+            client_order_id: Some(ref client_order_id),
+            ..
+        }
+        | OrderRequest::CancelReplace {
+            ref order_creator,
+            client_order_id: Some(ref client_order_id),
+            ..
+        } => Some((order_creator.clone(), client_order_id.clone())),
+        _ => None,
+    }
+}
+
 impl<Asset> Orderbook<Asset>
 where
     Asset: Debug + Clone + Copy + Eq + PartialEq,
@@ -92,25 +314,36 @@ where
     ///
     /// Basic usage:
     /// ```
-    /// let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
-    /// let result = orderbook.process_order(OrderRequest::MarketOrder{  });
-    /// assert_eq!(orderbook)
+    /// use orderbook::Orderbook;
+    ///
+    /// #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    /// enum Asset {
+    ///     BTC,
+    ///     USD,
+    /// }
+    ///
+    /// let orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+    /// assert_eq!(orderbook.current_spread(), None);
     /// ```
-    // todo fix doc test!
     pub fn new(order_asset: Asset, price_asset: Asset) -> Self {
+        Orderbook::with_policy(order_asset, price_asset, Fifo)
+    }
+}
+
+impl<Asset, Policy, Price, Qty> Orderbook<Asset, Policy, Price, Qty>
+where
+    Asset: Debug + Clone + Copy + Eq + PartialEq,
+    Policy: MatchingPolicy,
+    Price: numeric::Price,
+    Qty: Quantity,
+{
+    /// Create new orderbook for pair of assets with given matching policy
+    pub fn with_policy(order_asset: Asset, price_asset: Asset, matching_policy: Policy) -> Self {
         Orderbook {
             order_asset,
             price_asset,
-            bid_queue: OrderQueue::new(
-                OrderSide::Bid,
-                MAX_STALLED_INDICES_IN_QUEUE,
-                ORDER_QUEUE_INIT_CAPACITY,
-            ),
-            ask_queue: OrderQueue::new(
-                OrderSide::Ask,
-                MAX_STALLED_INDICES_IN_QUEUE,
-                ORDER_QUEUE_INIT_CAPACITY,
-            ),
+            bid_queue: OrderQueue::new(OrderSide::Bid, ORDER_QUEUE_INIT_CAPACITY),
+            ask_queue: OrderQueue::new(OrderSide::Ask, ORDER_QUEUE_INIT_CAPACITY),
             seq: sequence::new_sequence_gen(MIN_SEQUENCE_ID, MAX_SEQUENCE_ID),
             order_validator: OrderRequestValidator::new(
                 order_asset,
@@ -118,30 +351,357 @@ where
                 MIN_SEQUENCE_ID,
                 MAX_SEQUENCE_ID,
             ),
+            price_band: None,
+            fees: FeeSchedule::default(),
+            last_trade_price: None,
+            halted_until: None,
+            trading_phase: TradingPhase::Continuous,
+            matching_policy,
+            max_fills: MAX_FILLS_PER_REQUEST,
+            pending_orders: vec![],
+            client_order_ids: HashMap::new(),
+            book_seq: 0,
+            book_deltas: vec![],
+            pegged_orders: vec![],
+            peg_references: (None, None),
+            trailing_stops: vec![],
+            oco_links: HashMap::new(),
+            assigned_ids: vec![],
+        }
+    }
+
+    /// Set max number of opposite orders filled by a single request.
+    ///
+    /// When the limit is hit, the rest of the limit order is stored in the queue
+    /// if it doesn't cross the book anymore. Otherwise matching is suspended
+    /// until `resume_matching` is called.
+    pub fn set_max_fills(&mut self, max_fills: u64) {
+        self.max_fills = max_fills.max(1);
+    }
+
+    /// Number of orders waiting for `resume_matching`
+    pub fn pending_orders(&self) -> usize {
+        self.pending_orders.len()
+    }
+
+    /// Check if the ID belongs to an active, pending or stop order
+    pub fn is_id_in_use(&self, order_id: u64) -> bool {
+        self.bid_queue.get(order_id).is_some()
+            || self.ask_queue.get(order_id).is_some()
+            || self
+                .pending_orders
+                .iter()
+                .any(|pending| pending.order_id == order_id)
+            || self
+                .trailing_stops
+                .iter()
+                .any(|stop| stop.order_id == order_id)
+    }
+
+    /// Trailing stops waiting for their trigger, in the order of arrival
+    pub fn trailing_stops(&self) -> &[TrailingStop<Price, Qty>] {
+        &self.trailing_stops
+    }
+
+    /// The other leg of an OCO order, if it is still linked
+    pub fn linked_order(&self, order_id: u64) -> Option<u64> {
+        self.oco_links.get(&order_id).map(|link| link.linked_id)
+    }
+
+    /// Check if the creator has already submitted an order with the client order ID
+    pub fn is_client_order_id_used(&self, order_creator: &str, client_order_id: &str) -> bool {
+        let key = (order_creator.to_string(), client_order_id.to_string());
+        self.client_order_ids.contains_key(&key)
+    }
+
+    /// Continue matching of the oldest suspended order with a new fills limit
+    pub fn resume_matching(&mut self) -> OrderProcessingResult<Price, Qty> {
+        let mut proc_result: OrderProcessingResult<Price, Qty> = vec![];
+        if self.pending_orders.is_empty() {
+            return proc_result;
+        }
+
+        let pending = self.pending_orders.remove(0);
+        match pending.order_type {
+            OrderType::Market => {
+                let order = pending.order;
+                let unfilled = self.process_market_order(
+                    &mut proc_result,
+                    pending.order_id,
+                    pending.reference_price,
+                    order.side,
+                    order.qty,
+                    order.order_creator.clone(),
+                );
+                report_unfilled(
+                    &mut proc_result,
+                    pending.order_id,
+                    order.side,
+                    unfilled,
+                    order.order_creator,
+                );
+            }
+            OrderType::Limit => self.process_limit_order(
+                &mut proc_result,
+                pending.order_id,
+                pending.reference_price,
+                pending.order,
+            ),
+            OrderType::TrailingStop => unreachable!("triggered stops are market or limit orders"),
+        }
+        self.finish_request(&mut proc_result);
+        proc_result
+    }
+
+    /// Enable (or disable with `None`) price band protection
+    pub fn set_price_band(&mut self, price_band: Option<PriceBand>) {
+        self.price_band = price_band;
+    }
+
+    /// Set maker and taker fees charged on following fills
+    pub fn set_fee_schedule(&mut self, fees: FeeSchedule) {
+        self.fees = fees;
+    }
+
+    pub fn fee_schedule(&self) -> FeeSchedule {
+        self.fees
+    }
+
+    pub fn last_trade_price(&self) -> Option<Price> {
+        self.last_trade_price
+    }
+
+    /// Return the end of the current trading halt, if any
+    pub fn halted_until(&self) -> Option<u64> {
+        self.halted_until
+    }
+
+    pub fn trading_phase(&self) -> TradingPhase {
+        self.trading_phase
+    }
+
+    /// Switch to call auction: limit orders are collected without matching
+    /// until `uncross` is called. Starting an auction ends trading halt.
+    pub fn start_auction(&mut self) {
+        self.trading_phase = TradingPhase::Auction;
+        self.halted_until = None;
+    }
+
+    /// Get indicative auction result as a tuple: (price, volume)
+    ///
+    /// Constrained orders don't take part in the auction.
+    pub fn indicative_uncross(&self) -> Option<(Price, u128)> {
+        let bids: Vec<(Price, Qty)> = self
+            .bid_queue
+            .iter()
+            .filter(|o| o.constraint.is_none())
+            .map(|o| (o.price, o.qty))
+            .collect();
+        let asks: Vec<(Price, Qty)> = self
+            .ask_queue
+            .iter()
+            .filter(|o| o.constraint.is_none())
+            .map(|o| (o.price, o.qty))
+            .collect();
+        auction::clearing_price(&bids, &asks, self.last_trade_price)
+    }
+
+    /// Execute all matchable orders at the single clearing price
+    /// and return to continuous trading.
+    pub fn uncross(&mut self) -> OrderProcessingResult<Price, Qty> {
+        let mut proc_result: OrderProcessingResult<Price, Qty> = vec![];
+        let deal_time = get_current_time();
+        self.trading_phase = TradingPhase::Continuous;
+
+        let (price, volume) = match self.indicative_uncross() {
+            Some(uncross) => uncross,
+            None => {
+                proc_result.push(Ok(Success::AuctionUncrossed {
+                    price: self.last_trade_price.unwrap_or_default(),
+                    qty: 0,
+                    ts: deal_time,
+                }));
+                return proc_result;
+            }
+        };
+
+        proc_result.push(Ok(Success::AuctionUncrossed {
+            price,
+            qty: volume,
+            ts: deal_time,
+        }));
+
+        // best orders on both sides are always within clearing price
+        let mut remaining = volume;
+        while remaining > 0 {
+            let bid = self
+                .bid_queue
+                .iter()
+                .find(|o| o.constraint.is_none())
+                .cloned()
+                .unwrap();
+            let ask = self
+                .ask_queue
+                .iter()
+                .find(|o| o.constraint.is_none())
+                .cloned()
+                .unwrap();
+            let qty = match Qty::from_u128(remaining) {
+                Some(remaining) => remaining.min(bid.qty).min(ask.qty),
+                None => bid.qty.min(ask.qty),
+            };
+
+            self.fill_resting_order(&mut proc_result, &bid, price, qty, deal_time);
+            self.fill_resting_order(&mut proc_result, &ask, price, qty, deal_time);
+            remaining -= qty.into();
+        }
+
+        self.last_trade_price = Some(price);
+        for stop in &mut self.trailing_stops {
+            stop.track(price);
+        }
+        self.finish_request(&mut proc_result);
+        proc_result
+    }
+
+    /// Process request, recording changed price levels as book deltas
+    pub fn process_order(
+        &mut self,
+        order: OrderRequest<Asset, Price, Qty>,
+    ) -> OrderProcessingResult<Price, Qty> {
+        let mut proc_result = self.execute_order(order);
+        self.finish_request(&mut proc_result);
+        proc_result
+    }
+
+    /// Process request on a copy of the book, leaving the book intact.
+    ///
+    /// New market and not pegged limit orders are processed on a copy of only
+    /// the orders they may match and the best ones reference prices depend
+    /// on, so events of other orders (e.g. triggered stops) are left out.
+    pub fn simulate(
+        &self,
+        order: OrderRequest<Asset, Price, Qty>,
+    ) -> OrderProcessingResult<Price, Qty>
+    where
+        Self: Clone,
+        Policy: Clone,
+    {
+        match self.scratch_book(&order) {
+            Some(mut book) => book.process_order(order),
+            None => self.clone().process_order(order),
+        }
+    }
+
+    /// Would-be fills, average price, remaining quantity and fees of the
+    /// new order, see `simulate`
+    pub fn quote(&self, order: OrderRequest<Asset, Price, Qty>) -> Quote<Price, Qty>
+    where
+        Self: Clone,
+        Policy: Clone,
+    {
+        let qty = order.new_order_qty().unwrap_or_else(Qty::zero);
+        Quote::new(qty, self.simulate(order))
+    }
+
+    /// Take book deltas recorded since the previous call
+    pub fn take_book_deltas(&mut self) -> Vec<BookDelta<Price>> {
+        mem::take(&mut self.book_deltas)
+    }
+
+    /// Sequence number of the last book delta
+    pub fn book_sequence(&self) -> u64 {
+        self.book_seq
+    }
+
+    /// Up to `levels` best price levels of both sides.
+    ///
+    /// Deltas with a greater sequence number are applied on top of the snapshot.
+    pub fn snapshot(&self, levels: usize) -> BookSnapshot<Price> {
+        BookSnapshot {
+            seq: self.book_seq,
+            bids: self.depth(OrderSide::Bid, levels),
+            asks: self.depth(OrderSide::Ask, levels),
+            checksum: self.checksum(),
         }
     }
 
-    pub fn process_order(&mut self, order: OrderRequest<Asset>) -> OrderProcessingResult {
+    /// Checksum of the best price levels, see `market_data::book_checksum`
+    pub fn checksum(&self) -> u32 {
+        market_data::book_checksum(
+            &self.depth(OrderSide::Bid, CHECKSUM_LEVELS),
+            &self.depth(OrderSide::Ask, CHECKSUM_LEVELS),
+        )
+    }
+
+    fn execute_order(
+        &mut self,
+        order: OrderRequest<Asset, Price, Qty>,
+    ) -> OrderProcessingResult<Price, Qty> {
         // processing result accumulator
-        let mut proc_result: OrderProcessingResult = vec![];
+        let mut proc_result: OrderProcessingResult<Price, Qty> = vec![];
 
         // validate request
         if let Err(reason) = self.order_validator.validate(&order) {
-            proc_result.push(Err(Failed::ValidationFailed(String::from(reason))));
+            proc_result.push(Err(Failed::ValidationFailed(reason)));
+            return proc_result;
+        }
+
+        let client_order_key = client_order_key(&order);
+        if let Some(ref key) = client_order_key {
+            if self.client_order_ids.contains_key(key) {
+                proc_result.push(Err(Failed::DuplicateClientOrderID(key.1.clone())));
+                return proc_result;
+            }
+        }
+
+        // OCO pair places two orders
+        let new_orders = match order {
+            OrderRequest::NewOco { .. } => 2,
+            _ if order.new_order_qty().is_some() => 1,
+            _ => 0,
+        };
+        if !self.has_free_order_ids(new_orders) {
+            proc_result.push(Err(Failed::OrderIDsExhausted));
             return proc_result;
         }
 
+        // new orders are not accepted during trading halt
+        match order {
+            OrderRequest::NewMarketOrder { .. }
+            | OrderRequest::NewLimitOrder { .. }
+            | OrderRequest::CancelReplace { .. }
+            | OrderRequest::NewTrailingStop { .. }
+            | OrderRequest::NewOco { .. }
+                if self.is_halted(&mut proc_result) =>
+            {
+                return proc_result;
+            }
+            _ => {}
+        }
+
+        // market orders can't be priced during auction
+        if let OrderRequest::NewMarketOrder { .. } = order {
+            if self.trading_phase == TradingPhase::Auction {
+                proc_result.push(Err(Failed::AuctionInProgress));
+                return proc_result;
+            }
+        }
+
         match order {
             OrderRequest::NewMarketOrder {
-                order_asset,
-                price_asset,
+                order_asset: _order_asset,
+                price_asset: _price_asset,
                 side,
                 qty,
                 order_creator,
                 ts: _ts,
+                client_order_id: _,
             } => {
                 // generate new ID for order
-                let order_id = self.seq.next_id();
+                let order_id = self
+                    .next_order_id()
+                    .expect("order IDs are checked before the request");
                 proc_result.push(Ok(Success::Accepted {
                     id: order_id,
                     order_type: OrderType::Market,
@@ -149,11 +709,11 @@ where
                     ts: get_current_time(),
                 }));
 
+                let reference_price = self.band_reference_price();
                 self.process_market_order(
                     &mut proc_result,
                     order_id,
-                    order_asset,
-                    price_asset,
+                    reference_price,
                     side,
                     qty,
                     order_creator,
@@ -161,33 +721,30 @@ where
             }
 
             OrderRequest::NewLimitOrder {
-                order_asset,
-                price_asset,
+                order_asset: _order_asset,
+                price_asset: _price_asset,
                 side,
                 price,
                 qty,
                 order_creator,
+                client_order_id,
+                hidden,
+                peg,
+                constraint,
                 ts,
             } => {
-                let order_id = self.seq.next_id();
-                proc_result.push(Ok(Success::Accepted {
-                    id: order_id,
-                    order_type: OrderType::Limit,
-                    order_creator: order_creator.clone(),
-                    ts: get_current_time(),
-                }));
-
-                self.process_limit_order(
-                    &mut proc_result,
-                    order_id,
-                    order_asset,
-                    price_asset,
+                let order = NewLimitOrder {
                     side,
                     price,
                     qty,
                     order_creator,
+                    client_order_id,
+                    hidden,
+                    peg,
+                    constraint,
                     ts,
-                );
+                };
+                self.process_new_limit_order(&mut proc_result, order);
             }
 
             OrderRequest::AmendOrder {
@@ -203,168 +760,677 @@ where
             OrderRequest::CancelOrder { id, side } => {
                 self.process_order_cancel(&mut proc_result, id, side);
             }
-        }
-
-        // return collected processing results
-        proc_result
-    }
-
-    /// Get current spread as a tuple: (bid, ask)
-    pub fn current_spread(&mut self) -> Option<(f64, f64)> {
-        let bid = self.bid_queue.peek()?.price;
-        let ask = self.ask_queue.peek()?.price;
-        Some((bid, ask))
-    }
-
-    /* Processing logic */
 
-    fn process_market_order(
-        &mut self,
-        results: &mut OrderProcessingResult,
-        order_id: u64,
-        order_asset: Asset,
-        price_asset: Asset,
-        side: OrderSide,
-        qty: u128,
-        order_creator: String,
-    ) {
-        // get copy of the current limit order
-        let opposite_order_result = {
-            let opposite_queue = match side {
-                OrderSide::Bid => &mut self.ask_queue,
-                OrderSide::Ask => &mut self.bid_queue,
-            };
-            opposite_queue.peek().cloned()
-        };
+            OrderRequest::MassCancel {
+                order_creator,
+                side,
+                price_range,
+            } => {
+                self.process_mass_cancel(&mut proc_result, order_creator, side, price_range);
+            }
 
-        if let Some(opposite_order) = opposite_order_result {
-            let matching_complete = self.order_matching(
-                results,
-                &opposite_order,
-                order_id,
-                order_asset,
-                price_asset,
-                OrderType::Market,
+            OrderRequest::CancelReplace {
+                id,
                 side,
+                price,
                 qty,
-                &order_creator,
-            );
-
-            if !matching_complete {
-                // match the rest
-                self.process_market_order(
-                    results,
-                    order_id,
-                    order_asset,
-                    price_asset,
+                order_creator,
+                client_order_id,
+                ts,
+            } => {
+                let order = NewLimitOrder {
                     side,
-                    qty - opposite_order.qty,
+                    price,
+                    qty,
                     order_creator,
-                );
+                    client_order_id,
+                    hidden: false,
+                    peg: None,
+                    constraint: None,
+                    ts,
+                };
+                self.process_cancel_replace(&mut proc_result, id, order);
             }
-        } else {
-            // no limit orders found
-            results.push(Err(Failed::NoMatch(order_id)));
-        }
-    }
-
-    fn process_limit_order(
-        &mut self,
-        results: &mut OrderProcessingResult,
-        order_id: u64,
-        order_asset: Asset,
-        price_asset: Asset,
-        side: OrderSide,
-        price: f64,
-        qty: u128,
-        order_creator: String,
-        ts: u64,
-    ) {
-        // take a look at current opposite limit order
-        let opposite_order_result = {
-            let opposite_queue = match side {
-                OrderSide::Bid => &mut self.ask_queue,
-                OrderSide::Ask => &mut self.bid_queue,
-            };
-            opposite_queue.peek().cloned()
-        };
 
-        if let Some(opposite_order) = opposite_order_result {
-            let could_be_matched = match side {
-                // verify bid/ask price overlap
-                OrderSide::Bid => price >= opposite_order.price,
-                OrderSide::Ask => price <= opposite_order.price,
-            };
-
-            if could_be_matched {
-                // match immediately
-                let matching_complete = self.order_matching(
-                    results,
-                    &opposite_order,
+            OrderRequest::NewTrailingStop {
+                order_asset: _order_asset,
+                price_asset: _price_asset,
+                side,
+                qty,
+                trail,
+                limit_price,
+                order_creator,
+                ts,
+            } => {
+                let order_id = self
+                    .next_order_id()
+                    .expect("order IDs are checked before the request");
+                let stop = TrailingStop {
                     order_id,
-                    order_asset,
-                    price_asset,
-                    OrderType::Limit,
                     side,
                     qty,
-                    &order_creator,
-                );
-
-                if !matching_complete {
-                    // process the rest of new limit order
-                    self.process_limit_order(
-                        results,
-                        order_id,
-                        order_asset,
-                        price_asset,
-                        side,
-                        price,
-                        qty - opposite_order.qty,
-                        order_creator,
-                        ts,
-                    );
-                }
-            } else {
-                // just insert new order in queue
-                self.store_new_limit_order(
-                    results,
-                    order_id,
-                    order_asset,
-                    price_asset,
+                    trail,
+                    limit_price,
+                    order_creator,
+                    best_price: self.last_trade_price,
+                    ts,
+                };
+                self.add_trailing_stop(&mut proc_result, stop);
+            }
+
+            OrderRequest::NewOco {
+                order_asset: _order_asset,
+                price_asset: _price_asset,
+                side,
+                qty,
+                price,
+                stop,
+                stop_limit_price,
+                on_partial_fill,
+                order_creator,
+                ts,
+            } => {
+                // stop-loss leg goes first, the take-profit one may be filled at once
+                let stop_id = self
+                    .next_order_id()
+                    .expect("order IDs are checked before the request");
+                let stop = TrailingStop {
+                    order_id: stop_id,
+                    side,
+                    qty,
+                    trail: stop,
+                    limit_price: stop_limit_price,
+                    order_creator: order_creator.clone(),
+                    best_price: self.last_trade_price,
+                    ts,
+                };
+                self.add_trailing_stop(&mut proc_result, stop);
+                let start = proc_result.len();
+                let order = NewLimitOrder {
                     side,
                     price,
                     qty,
+                    order_creator,
+                    client_order_id: None,
+                    hidden: false,
+                    peg: None,
+                    constraint: None,
                     ts,
-                );
+                };
+                self.process_new_limit_order(&mut proc_result, order);
+
+                if let Ok(Success::Accepted { id: limit_id, .. }) = proc_result[start] {
+                    self.oco_links.insert(
+                        stop_id,
+                        OcoLink {
+                            linked_id: limit_id,
+                            linked_side: side,
+                            on_partial_fill,
+                        },
+                    );
+                    self.oco_links.insert(
+                        limit_id,
+                        OcoLink {
+                            linked_id: stop_id,
+                            linked_side: side,
+                            on_partial_fill,
+                        },
+                    );
+                }
+            }
+        }
+
+        // client order ID is taken once the order is accepted
+        if let Some(client_order_key) = client_order_key {
+            for result in &proc_result {
+                if let Ok(Success::Accepted { id, .. }) = *result {
+                    // a batch which may be undone only adds IDs
+                    if !self.bid_queue.is_journaling() {
+                        self.expire_client_order_ids();
+                    }
+                    self.client_order_ids
+                        .insert(client_order_key, (id, get_current_time()));
+                    break;
+                }
             }
+        }
+
+        // return collected processing results
+        proc_result
+    }
+
+    /// Active order by its creator and client order ID
+    pub fn client_order(
+        &self,
+        order_creator: &str,
+        client_order_id: &str,
+    ) -> Option<&Order<Asset, Price, Qty>> {
+        let key = (order_creator.to_string(), client_order_id.to_string());
+        let order_id = self.client_order_ids.get(&key)?.0;
+        let order = match self.bid_queue.get(order_id) {
+            Some(order) => order,
+            None => self.ask_queue.get(order_id)?,
+        };
+
+        // order IDs are reused, make sure it is still the same order
+        if order.order_creator == order_creator
+            && order.client_order_id.as_deref() == Some(client_order_id)
+        {
+            Some(order)
         } else {
-            self.store_new_limit_order(
+            None
+        }
+    }
+
+    /// Release client order IDs taken longer than `CLIENT_ORDER_ID_TTL` ago,
+    /// unless their orders are still active.
+    fn expire_client_order_ids(&mut self) {
+        let now = get_current_time();
+        let bid_queue = &self.bid_queue;
+        let ask_queue = &self.ask_queue;
+        let pending_orders = &self.pending_orders;
+        self.client_order_ids
+            .retain(|(order_creator, client_order_id), &mut (order_id, ts)| {
+                if now.saturating_sub(ts) < CLIENT_ORDER_ID_TTL {
+                    return true;
+                }
+                let is_active = |order: &Order<Asset, Price, Qty>| {
+                    order.order_creator == *order_creator
+                        && order.client_order_id.as_ref() == Some(client_order_id)
+                };
+                bid_queue
+                    .get(order_id)
+                    .into_iter()
+                    .chain(ask_queue.get(order_id))
+                    .any(is_active)
+                    || pending_orders.iter().any(|pending| {
+                        pending.order_id == order_id
+                            && pending.order.client_order_id.as_ref() == Some(client_order_id)
+                    })
+            });
+    }
+
+    /// Cancel active order by its creator and client order ID
+    pub fn cancel_client_order(
+        &mut self,
+        order_creator: &str,
+        client_order_id: &str,
+    ) -> OrderProcessingResult<Price, Qty> {
+        let (order_id, side) = match self.client_order(order_creator, client_order_id) {
+            Some(order) => (order.order_id, order.side),
+            None => {
+                return vec![Err(Failed::ClientOrderNotFound(
+                    client_order_id.to_string(),
+                ))]
+            }
+        };
+        self.process_order(orders::limit_order_cancel_request(order_id, side))
+    }
+
+    /// Amend active order by its creator and client order ID
+    pub fn amend_client_order(
+        &mut self,
+        order_creator: &str,
+        client_order_id: &str,
+        price: Price,
+        qty: Qty,
+        ts: u64,
+    ) -> OrderProcessingResult<Price, Qty> {
+        let (order_id, side) = match self.client_order(order_creator, client_order_id) {
+            Some(order) => (order.order_id, order.side),
+            None => {
+                return vec![Err(Failed::ClientOrderNotFound(
+                    client_order_id.to_string(),
+                ))]
+            }
+        };
+        self.process_order(orders::amend_order_request(order_id, side, price, qty, ts))
+    }
+
+    /// Process request, assigning the given ID to a new order instead of
+    /// taking the next one from the own sequence.
+    ///
+    /// Used by `MarketRegistry` to share order ID space between books.
+    pub fn process_order_with_id(
+        &mut self,
+        order: OrderRequest<Asset, Price, Qty>,
+        order_id: u64,
+    ) -> OrderProcessingResult<Price, Qty> {
+        self.process_order_with_ids(order, &[order_id])
+    }
+
+    /// Same as `process_order_with_id` for requests placing several orders,
+    /// IDs are assigned in the order the orders are accepted.
+    pub fn process_order_with_ids(
+        &mut self,
+        order: OrderRequest<Asset, Price, Qty>,
+        order_ids: &[u64],
+    ) -> OrderProcessingResult<Price, Qty> {
+        self.assigned_ids = order_ids.to_vec();
+        let proc_result = self.process_order(order);
+        self.assigned_ids.clear();
+        proc_result
+    }
+
+    /// Process several requests at once, returning a result per request.
+    ///
+    /// In `AllOrNothing` mode requests are applied one by one, so they may
+    /// depend on each other, and their changes are undone if any of them
+    /// fails. Processing stops at the first failed request, and every result
+    /// is `Failed::BatchRejected`, preceded by the errors of the failed request.
+    pub fn process_batch(
+        &mut self,
+        requests: Vec<OrderRequest<Asset, Price, Qty>>,
+        mode: BatchMode,
+    ) -> Vec<OrderProcessingResult<Price, Qty>> {
+        if mode == BatchMode::BestEffort {
+            return requests
+                .into_iter()
+                .map(|request| self.process_order(request))
+                .collect();
+        }
+
+        // orders are journaled by the queues, the rest of the state is small
+        let undo = BatchUndo {
+            seq: self.seq.clone(),
+            last_trade_price: self.last_trade_price,
+            halted_until: self.halted_until,
+            trading_phase: self.trading_phase,
+            pending_orders: self.pending_orders.clone(),
+            book_seq: self.book_seq,
+            book_deltas: self.book_deltas.len(),
+            pegged_orders: self.pegged_orders.clone(),
+            peg_references: self.peg_references,
+            trailing_stops: self.trailing_stops.clone(),
+            oco_links: self.oco_links.clone(),
+        };
+        self.bid_queue.begin_journal();
+        self.ask_queue.begin_journal();
+
+        let count = requests.len();
+        let mut results = Vec::with_capacity(count);
+        let mut client_order_keys = vec![];
+        for (index, request) in requests.into_iter().enumerate() {
+            let client_order_key = client_order_key(&request);
+            let result = self.process_order(request);
+            let accepted = result
+                .iter()
+                .any(|result| matches!(result, Ok(Success::Accepted { .. })));
+            if let (Some(key), true) = (client_order_key, accepted) {
+                client_order_keys.push(key);
+            }
+
+            if result.iter().any(|result| result.is_err()) {
+                self.bid_queue.rollback_journal();
+                self.ask_queue.rollback_journal();
+                self.undo_batch(undo, &client_order_keys);
+
+                let mut rejected: Vec<OrderProcessingResult<Price, Qty>> = (0..count)
+                    .map(|_| vec![Err(Failed::BatchRejected)])
+                    .collect();
+                let mut errors: OrderProcessingResult<Price, Qty> = result
+                    .into_iter()
+                    .filter(|result| result.is_err())
+                    .collect();
+                errors.push(Err(Failed::BatchRejected));
+                rejected[index] = errors;
+                return rejected;
+            }
+            results.push(result);
+        }
+
+        self.bid_queue.commit_journal();
+        self.ask_queue.commit_journal();
+        results
+    }
+
+    /// Restore the state besides the queues from before a rejected batch
+    fn undo_batch(&mut self, undo: BatchUndo<Price, Qty>, client_order_keys: &[(String, String)]) {
+        self.seq = undo.seq;
+        self.last_trade_price = undo.last_trade_price;
+        self.halted_until = undo.halted_until;
+        self.trading_phase = undo.trading_phase;
+        self.pending_orders = undo.pending_orders;
+        self.book_seq = undo.book_seq;
+        self.book_deltas.truncate(undo.book_deltas);
+        self.pegged_orders = undo.pegged_orders;
+        self.peg_references = undo.peg_references;
+        self.trailing_stops = undo.trailing_stops;
+        self.oco_links = undo.oco_links;
+        for key in client_order_keys {
+            self.client_order_ids.remove(key);
+        }
+    }
+
+    /// Get current spread as a tuple: (bid, ask)
+    pub fn current_spread(&self) -> Option<(Price, Price)> {
+        let bid = self.best_price(OrderSide::Bid)?;
+        let ask = self.best_price(OrderSide::Ask)?;
+        Some((bid, ask))
+    }
+
+    /// Price of the best displayed order on the side
+    pub fn best_price(&self, side: OrderSide) -> Option<Price> {
+        self.depth(side, 1).first().map(|level| level.0)
+    }
+
+    /// Aggregated quantity of up to `levels` best price levels of the side
+    pub fn depth(&self, side: OrderSide, levels: usize) -> Vec<(Price, u128)> {
+        match side {
+            OrderSide::Bid => self.bid_queue.depth(levels),
+            OrderSide::Ask => self.ask_queue.depth(levels),
+        }
+    }
+
+    /// Cumulative quantity and notional of up to `levels` best displayed
+    /// price levels of the side
+    pub fn depth_curve(&self, side: OrderSide, levels: usize) -> Vec<DepthPoint<Price>> {
+        impact::depth_curve(self.depth(side, levels))
+    }
+
+    /// Estimated execution of a `side` market order of `qty` against the
+    /// displayed opposite levels: notional, average price and slippage
+    pub fn market_impact(&self, side: OrderSide, qty: u128) -> Impact<Price> {
+        let opposite_queue = match side {
+            OrderSide::Bid => &self.ask_queue,
+            OrderSide::Ask => &self.bid_queue,
+        };
+        impact::impact(opposite_queue.displayed_levels(), qty)
+    }
+
+    /// Displayed quantity a `side` order can take before the price moves by
+    /// more than `max_move_pct` percents from the best opposite price
+    pub fn qty_within(&self, side: OrderSide, max_move_pct: f64) -> u128 {
+        let opposite_queue = match side {
+            OrderSide::Bid => &self.ask_queue,
+            OrderSide::Ask => &self.bid_queue,
+        };
+        impact::qty_within(opposite_queue.displayed_levels(), max_move_pct)
+    }
+
+    /// Verify internal consistency of the book.
+    ///
+    /// Walks over all active orders, so it is meant for tests and monitoring
+    /// rather than for every processed request.
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        let mut ids = HashSet::new();
+
+        for (side, order_queue) in [
+            (OrderSide::Bid, &self.bid_queue),
+            (OrderSide::Ask, &self.ask_queue),
+        ] {
+            order_queue.check_invariants(|order| (order.price, order.qty))?;
+            for order in order_queue.iter() {
+                if order.side != side {
+                    return Err(InvariantViolation::WrongSide {
+                        side,
+                        id: order.order_id,
+                    });
+                }
+                if !ids.insert(order.order_id) {
+                    return Err(InvariantViolation::DuplicateOrderID(order.order_id));
+                }
+            }
+        }
+
+        for pending in &self.pending_orders {
+            if !ids.insert(pending.order_id) {
+                return Err(InvariantViolation::DuplicateOrderID(pending.order_id));
+            }
+        }
+
+        for stop in &self.trailing_stops {
+            if !ids.insert(stop.order_id) {
+                return Err(InvariantViolation::DuplicateOrderID(stop.order_id));
+            }
+        }
+
+        // the book may be crossed only while collecting auction orders
+        if self.trading_phase == TradingPhase::Continuous {
+            if let (Some(bid), Some(ask)) =
+                (self.bid_queue.iter().next(), self.ask_queue.iter().next())
+            {
+                if bid.price >= ask.price {
+                    return Err(InvariantViolation::CrossedBook {
+                        bid: bid.price.to_f64(),
+                        ask: ask.price.to_f64(),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /* Processing logic */
+
+    fn process_new_limit_order(
+        &mut self,
+        results: &mut OrderProcessingResult<Price, Qty>,
+        order: NewLimitOrder<Price, Qty>,
+    ) {
+        // pegged order enters the book at its current peg price
+        let price = match order.peg {
+            Some(peg) => self.peg_price(&peg, order.side).unwrap_or(order.price),
+            None => order.price,
+        };
+        let order = NewLimitOrder { price, ..order };
+
+        let order_id = self
+            .next_order_id()
+            .expect("order IDs are checked before the request");
+        results.push(Ok(Success::Accepted {
+            id: order_id,
+            order_type: OrderType::Limit,
+            order_creator: order.order_creator.clone(),
+            ts: get_current_time(),
+        }));
+
+        if self.trading_phase == TradingPhase::Auction {
+            // collect orders without matching
+            self.store_new_limit_order(results, order_id, order);
+            return;
+        }
+
+        let reference_price = self.band_reference_price();
+        self.process_limit_order(results, order_id, reference_price, order);
+    }
+
+    /// Match market order, returning its quantity left without a match.
+    ///
+    /// Suspended order is matched later, so no quantity is returned for it.
+    fn process_market_order(
+        &mut self,
+        results: &mut OrderProcessingResult<Price, Qty>,
+        order_id: u64,
+        reference_price: Option<Price>,
+        side: OrderSide,
+        qty: Qty,
+        order_creator: String,
+    ) -> Qty {
+        let mut qty = qty;
+        let mut fills_left = self.max_fills;
+
+        loop {
+            // take a look at current opposite limit order
+            let opposite_price = match self.best_matchable_price(side, qty) {
+                Some(opposite_price) => opposite_price,
+                None => {
+                    // no limit orders found
+                    results.push(Err(Failed::NoMatch(order_id)));
+                    return qty;
+                }
+            };
+
+            if !self.check_price_band(results, order_id, reference_price, opposite_price) {
+                // the rest of the order is rejected
+                return qty;
+            }
+
+            if fills_left == 0 {
+                self.suspend_matching(
+                    results,
+                    PendingOrder {
+                        order_id,
+                        order_type: OrderType::Market,
+                        order: NewLimitOrder {
+                            side,
+                            price: Price::default(),
+                            qty,
+                            order_creator,
+                            client_order_id: None,
+                            hidden: false,
+                            peg: None,
+                            constraint: None,
+                            ts: get_current_time(),
+                        },
+                        reference_price,
+                    },
+                );
+                return Qty::zero();
+            }
+
+            let (remaining_qty, fills) = self.order_matching(
                 results,
                 order_id,
-                order_asset,
-                price_asset,
+                OrderType::Market,
                 side,
-                price,
+                opposite_price,
                 qty,
-                ts,
+                &order_creator,
+                fills_left,
+            );
+            qty = remaining_qty;
+            fills_left -= fills;
+
+            if qty.is_zero() {
+                return qty;
+            }
+        }
+    }
+
+    fn process_limit_order(
+        &mut self,
+        results: &mut OrderProcessingResult<Price, Qty>,
+        order_id: u64,
+        reference_price: Option<Price>,
+        order: NewLimitOrder<Price, Qty>,
+    ) {
+        let mut order = order;
+        let (side, price) = (order.side, order.price);
+
+        // constrained order is matched only if enough can be executed at once,
+        // so its matching can't be suspended by the fills limit
+        let (executable, mut fills_left) = match order.constraint {
+            Some(constraint) => (
+                self.executable_qty(side, price, order.qty, reference_price)
+                    >= constraint.min_fill(order.qty),
+                u64::MAX,
+            ),
+            None => (true, self.max_fills),
+        };
+
+        loop {
+            // verify bid/ask price overlap
+            let opposite_price = self
+                .best_matchable_price(side, order.qty)
+                .filter(|_| executable);
+            let could_be_matched = match opposite_price {
+                Some(opposite_price) => match side {
+                    OrderSide::Bid => price >= opposite_price,
+                    OrderSide::Ask => price <= opposite_price,
+                },
+                None => false,
+            };
+
+            if !could_be_matched {
+                // constrained order can't be executed at once, nor rest
+                // across the book
+                if order.constraint.is_some() && self.amend_crosses_book(side, price) {
+                    report_unfilled(results, order_id, side, order.qty, order.order_creator);
+                    return;
+                }
+
+                // just insert the rest of new order in queue
+                self.store_new_limit_order(results, order_id, order);
+                return;
+            }
+
+            let opposite_price = opposite_price.unwrap();
+            if !self.check_price_band(results, order_id, reference_price, opposite_price) {
+                // the rest of the order is rejected
+                report_unfilled(results, order_id, side, order.qty, order.order_creator);
+                return;
+            }
+
+            if fills_left == 0 {
+                self.suspend_matching(
+                    results,
+                    PendingOrder {
+                        order_id,
+                        order_type: OrderType::Limit,
+                        order,
+                        reference_price,
+                    },
+                );
+                return;
+            }
+
+            // match immediately
+            let (remaining_qty, fills) = self.order_matching(
+                results,
+                order_id,
+                OrderType::Limit,
+                side,
+                opposite_price,
+                order.qty,
+                &order.order_creator,
+                fills_left,
             );
+            order.qty = remaining_qty;
+            fills_left -= fills;
+
+            if order.qty.is_zero() {
+                return;
+            }
         }
     }
 
     fn process_order_amend(
         &mut self,
-        results: &mut OrderProcessingResult,
+        results: &mut OrderProcessingResult<Price, Qty>,
         order_id: u64,
         side: OrderSide,
-        price: f64,
-        qty: u128,
+        price: Price,
+        qty: Qty,
         ts: u64,
     ) {
+        if self.amend_crosses_book(side, price) {
+            results.push(Err(Failed::AmendCrossesBook(order_id)));
+            return;
+        }
+
         let order_queue = match side {
             OrderSide::Bid => &mut self.bid_queue,
             OrderSide::Ask => &mut self.ask_queue,
         };
 
+        // keep the owner, client ID, visibility, peg and constraint of amended order
+        let (order_creator, client_order_id, hidden, peg, constraint) =
+            match order_queue.get(order_id) {
+                Some(order) => (
+                    order.order_creator.clone(),
+                    order.client_order_id.clone(),
+                    order.hidden,
+                    order.peg,
+                    order.constraint,
+                ),
+                None => {
+                    results.push(Err(Failed::OrderNotFound(order_id)));
+                    return;
+                }
+            };
+
         if order_queue.amend(
             order_id,
             price,
@@ -377,6 +1443,11 @@ where
                 side,
                 price,
                 qty,
+                order_creator,
+                client_order_id,
+                hidden,
+                peg,
+                constraint,
             },
         ) {
             results.push(Ok(Success::Amended {
@@ -392,7 +1463,7 @@ where
 
     fn process_order_cancel(
         &mut self,
-        results: &mut OrderProcessingResult,
+        results: &mut OrderProcessingResult<Price, Qty>,
         order_id: u64,
         side: OrderSide,
     ) {
@@ -401,9 +1472,30 @@ where
             OrderSide::Ask => &mut self.ask_queue,
         };
 
+        let qty = match order_queue.get(order_id) {
+            Some(order) => order.qty,
+            None => {
+                match self.trailing_stop_index(order_id, side) {
+                    Some(index) => {
+                        let stop = self.trailing_stops.remove(index);
+                        results.push(Ok(Success::Cancelled {
+                            id: order_id,
+                            side,
+                            qty: stop.qty,
+                            ts: get_current_time(),
+                        }));
+                    }
+                    None => results.push(Err(Failed::OrderNotFound(order_id))),
+                }
+                return;
+            }
+        };
+
         if order_queue.cancel(order_id) {
             results.push(Ok(Success::Cancelled {
                 id: order_id,
+                side,
+                qty,
                 ts: get_current_time(),
             }));
         } else {
@@ -411,186 +1503,1066 @@ where
         }
     }
 
-    /* Helpers */
-
-    fn store_new_limit_order(
+    /// Cancel creator's order and place a new one instead.
+    ///
+    /// Nothing is changed if the order was already filled or cancelled.
+    fn process_cancel_replace(
         &mut self,
-        results: &mut OrderProcessingResult,
+        results: &mut OrderProcessingResult<Price, Qty>,
         order_id: u64,
-        order_asset: Asset,
-        price_asset: Asset,
-        side: OrderSide,
-        price: f64,
-        qty: u128,
-        ts: u64,
+        order: NewLimitOrder<Price, Qty>,
     ) {
+        let side = order.side;
+        if !self.is_owned_by(order_id, side, &order.order_creator) {
+            results.push(Err(Failed::OrderNotFound(order_id)));
+            return;
+        }
+
+        // replacement of a hidden, pegged or constrained order is hidden,
+        // pegged or constrained too
         let order_queue = match side {
-            OrderSide::Bid => &mut self.bid_queue,
-            OrderSide::Ask => &mut self.ask_queue,
+            OrderSide::Bid => &self.bid_queue,
+            OrderSide::Ask => &self.ask_queue,
         };
-        if !order_queue.insert(
-            order_id,
-            price,
-            qty,
-            ts,
-            Order {
-                order_id,
-                order_asset,
-                price_asset,
-                side,
-                price,
-                qty,
-            },
-        ) {
-            results.push(Err(Failed::DuplicateOrderID(order_id)))
+        let (hidden, peg, constraint) = order_queue
+            .get(order_id)
+            .map_or((false, None, None), |order| {
+                (order.hidden, order.peg, order.constraint)
+            });
+
+        self.process_order_cancel(results, order_id, side);
+
+        let order = NewLimitOrder {
+            hidden,
+            peg,
+            constraint,
+            ..order
         };
+        self.process_new_limit_order(results, order);
     }
 
-    fn order_matching(
+    fn process_mass_cancel(
         &mut self,
-        results: &mut OrderProcessingResult,
-        opposite_order: &Order<Asset>,
-        order_id: u64,
-        order_asset: Asset,
-        price_asset: Asset,
-        order_type: OrderType,
-        side: OrderSide,
-        qty: u128,
-        order_creator: &str,
-    ) -> bool {
-        // real processing time
-        let deal_time = get_current_time();
+        results: &mut OrderProcessingResult<Price, Qty>,
+        order_creator: String,
+        side: Option<OrderSide>,
+        price_range: Option<(Price, Price)>,
+    ) {
+        let sides = match side {
+            Some(side) => vec![side],
+            None => vec![OrderSide::Bid, OrderSide::Ask],
+        };
+        let in_range = |price: Price| match price_range {
+            Some((min_price, max_price)) => price >= min_price && price <= max_price,
+            None => true,
+        };
+        let ts = get_current_time();
 
-        // match immediately
-        if qty < opposite_order.qty {
-            // fill new limit and modify opposite limit
+        for side in sides {
+            let order_queue = match side {
+                OrderSide::Bid => &mut self.bid_queue,
+                OrderSide::Ask => &mut self.ask_queue,
+            };
 
-            // report filled new order
-            results.push(Ok(Success::Filled {
-                order_id,
-                side,
-                order_type,
-                price: opposite_order.price,
-                qty,
-                order_creator: order_creator.to_string(),
-                ts: deal_time,
-            }));
+            let cancelled = order_queue.cancel_where(|order| {
+                order.order_creator == order_creator && in_range(order.price)
+            });
+            for order in cancelled {
+                results.push(Ok(Success::Cancelled {
+                    id: order.order_id,
+                    side,
+                    qty: order.qty,
+                    ts,
+                }));
+            }
 
-            // report partially filled opposite limit order
-            results.push(Ok(Success::PartiallyFilled {
-                order_id: opposite_order.order_id,
-                side: opposite_order.side,
-                order_type: OrderType::Limit,
-                price: opposite_order.price,
-                qty,
-                order_creator: order_creator.to_string(),
-                ts: deal_time,
-            }));
+            // stops have no price yet, so they don't fall into a price range
+            if price_range.is_none() {
+                let (cancelled, kept) = mem::take(&mut self.trailing_stops)
+                    .into_iter()
+                    .partition(|stop| stop.side == side && stop.order_creator == order_creator);
+                self.trailing_stops = kept;
+                for stop in cancelled {
+                    results.push(Ok(Success::Cancelled {
+                        id: stop.order_id,
+                        side,
+                        qty: stop.qty,
+                        ts,
+                    }));
+                }
+            }
+        }
+    }
 
-            // modify unmatched part of the opposite limit order
-            {
-                let opposite_queue = match side {
-                    OrderSide::Bid => &mut self.ask_queue,
-                    OrderSide::Ask => &mut self.bid_queue,
-                };
-                opposite_queue.modify_current_order(Order {
-                    order_id: opposite_order.order_id,
-                    order_asset,
-                    price_asset,
-                    side: opposite_order.side,
-                    price: opposite_order.price,
-                    qty: opposite_order.qty - qty,
-                });
+    /// Turn trailing stops hit by the last trade price into market or limit orders.
+    ///
+    /// Triggered orders may trade and trigger further stops. Stops wait while
+    /// trading is halted or an auction is running.
+    fn trigger_trailing_stops(&mut self, results: &mut OrderProcessingResult<Price, Qty>) {
+        loop {
+            if self.trading_phase == TradingPhase::Auction {
+                return;
+            }
+            if let Some(until) = self.halted_until {
+                if get_current_time() < until {
+                    return;
+                }
             }
-        } else if qty > opposite_order.qty {
-            // partially fill new limit order, fill opposite limit and notify to process the rest
 
-            // report new order partially filled
-            results.push(Ok(Success::PartiallyFilled {
-                order_id,
-                side,
+            let price = match self.last_trade_price {
+                Some(price) => price,
+                None => return,
+            };
+            let stop = match self
+                .trailing_stops
+                .iter()
+                .position(|stop| stop.is_triggered(price))
+            {
+                Some(index) => self.trailing_stops.remove(index),
+                None => return,
+            };
+
+            let order_type = match stop.limit_price {
+                Some(_) => OrderType::Limit,
+                None => OrderType::Market,
+            };
+            let start = results.len();
+            results.push(Ok(Success::StopTriggered {
+                id: stop.order_id,
+                side: stop.side,
                 order_type,
-                price: opposite_order.price,
-                qty: opposite_order.qty,
-                order_creator: order_creator.to_string(),
-                ts: deal_time,
+                price,
+                ts: get_current_time(),
             }));
 
-            // report filled opposite limit order
-            results.push(Ok(Success::Filled {
-                order_id: opposite_order.order_id,
-                side: opposite_order.side,
-                order_type: OrderType::Limit,
-                price: opposite_order.price,
-                qty: opposite_order.qty,
-                order_creator: order_creator.to_string(),
-                ts: deal_time,
+            let reference_price = self.band_reference_price();
+            match stop.limit_price {
+                Some(limit_price) => {
+                    let order = NewLimitOrder {
+                        side: stop.side,
+                        price: limit_price,
+                        qty: stop.qty,
+                        order_creator: stop.order_creator,
+                        client_order_id: None,
+                        hidden: false,
+                        peg: None,
+                        constraint: None,
+                        ts: stop.ts,
+                    };
+                    self.process_limit_order(results, stop.order_id, reference_price, order)
+                }
+                None => {
+                    let unfilled = self.process_market_order(
+                        results,
+                        stop.order_id,
+                        reference_price,
+                        stop.side,
+                        stop.qty,
+                        stop.order_creator.clone(),
+                    );
+                    report_unfilled(
+                        results,
+                        stop.order_id,
+                        stop.side,
+                        unfilled,
+                        stop.order_creator,
+                    );
+                }
+            }
+            self.settle_linked_orders(results, start);
+        }
+    }
+
+    /// Cancel or reduce the other leg of OCO orders filled in `results[from..]`.
+    ///
+    /// Released quantity is reported to the creator of the other leg.
+    fn settle_linked_orders(
+        &mut self,
+        results: &mut OrderProcessingResult<Price, Qty>,
+        from: usize,
+    ) {
+        if self.oco_links.is_empty() {
+            return;
+        }
+        let fills: Vec<(u64, Qty, bool)> = results[from..]
+            .iter()
+            .filter_map(|result| match *result {
+                Ok(Success::Filled { order_id, qty, .. }) => Some((order_id, qty, true)),
+                Ok(Success::PartiallyFilled { order_id, qty, .. }) => Some((order_id, qty, false)),
+                _ => None,
+            })
+            .collect();
+
+        let ts = get_current_time();
+        for (order_id, qty, complete) in fills {
+            let link = match self.oco_links.get(&order_id) {
+                Some(link) => *link,
+                None => continue,
+            };
+            let reduce_by = match link.on_partial_fill {
+                PartialFillAction::ReduceOther if !complete => Some(qty),
+                _ => None,
+            };
+
+            let remaining = match self.reduce_order(link.linked_id, link.linked_side, reduce_by) {
+                Some((released, remaining, order_creator)) => {
+                    results.push(Ok(Success::LinkedOrderReduced {
+                        id: link.linked_id,
+                        side: link.linked_side,
+                        qty: released,
+                        remaining,
+                        order_creator,
+                        ts,
+                    }));
+                    remaining
+                }
+                None => Qty::zero(),
+            };
+            if remaining.is_zero() {
+                self.oco_links.remove(&order_id);
+                self.oco_links.remove(&link.linked_id);
+            }
+        }
+    }
+
+    /// Drop links of OCO pairs with a leg which is not active anymore
+    fn unlink_finished_orders(&mut self) {
+        if self.oco_links.is_empty() {
+            return;
+        }
+        let finished: Vec<u64> = self
+            .oco_links
+            .iter()
+            .filter(|&(id, link)| !self.is_id_in_use(*id) || !self.is_id_in_use(link.linked_id))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in finished {
+            self.oco_links.remove(&id);
+        }
+    }
+
+    /// Move pegged orders after their reference prices.
+    ///
+    /// Repriced order loses its time priority. It is not moved into the
+    /// opposite side of the book, keeping its current price instead.
+    fn reprice_pegged_orders(&mut self, results: &mut OrderProcessingResult<Price, Qty>) {
+        if self.pegged_orders.is_empty() {
+            return;
+        }
+        let (bid, ask) = (
+            self.peg_reference(OrderSide::Bid),
+            self.peg_reference(OrderSide::Ask),
+        );
+        if (bid, ask) == self.peg_references {
+            return;
+        }
+        self.peg_references = (bid, ask);
+
+        let ts = get_current_time();
+        for (side, order_id) in mem::take(&mut self.pegged_orders) {
+            let order_queue = match side {
+                OrderSide::Bid => &self.bid_queue,
+                OrderSide::Ask => &self.ask_queue,
+            };
+            // IDs are reused, the order may be a different one by now
+            let (order, peg) = match order_queue.get(order_id) {
+                Some(order) => match order.peg {
+                    Some(peg) => (order.clone(), peg),
+                    None => continue,
+                },
+                None => continue,
+            };
+            self.pegged_orders.push((side, order_id));
+
+            let price = match peg.price(side, bid, ask) {
+                Some(price) => price,
+                None => continue,
+            };
+            if price == order.price || self.amend_crosses_book(side, price) {
+                continue;
+            }
+
+            let order_queue = match side {
+                OrderSide::Bid => &mut self.bid_queue,
+                OrderSide::Ask => &mut self.ask_queue,
+            };
+            order_queue.amend(order_id, price, order.qty, ts, Order { price, ..order });
+            results.push(Ok(Success::Repriced {
+                id: order_id,
+                side,
+                price,
+                ts,
             }));
+        }
+    }
 
-            // remove filled limit order from the queue
-            {
-                let opposite_queue = match side {
-                    OrderSide::Bid => &mut self.ask_queue,
-                    OrderSide::Ask => &mut self.bid_queue,
-                };
-                opposite_queue.pop();
+    /* Helpers */
+
+    /// Settle linked orders, trigger stops and reprice pegged orders after
+    /// the request, then record book deltas
+    fn finish_request(&mut self, results: &mut OrderProcessingResult<Price, Qty>) {
+        self.drop_crossed_constrained_orders(results);
+        self.settle_linked_orders(results, 0);
+        self.trigger_trailing_stops(results);
+        self.unlink_finished_orders();
+        self.reprice_pegged_orders(results);
+        self.record_book_deltas();
+    }
+
+    /// Cancel resting constrained orders crossed by the opposite side, as
+    /// `Unfilled`: they can't be executed at once, nor rest across the book
+    fn drop_crossed_constrained_orders(&mut self, results: &mut OrderProcessingResult<Price, Qty>) {
+        if self.trading_phase == TradingPhase::Auction {
+            return;
+        }
+        loop {
+            let (bid, ask) = match (self.bid_queue.iter().next(), self.ask_queue.iter().next()) {
+                (Some(bid), Some(ask)) if bid.price >= ask.price => (bid, ask),
+                _ => return,
+            };
+            let (side, order) = if bid.constraint.is_some() {
+                (OrderSide::Bid, bid.clone())
+            } else if ask.constraint.is_some() {
+                (OrderSide::Ask, ask.clone())
+            } else {
+                return;
+            };
+
+            let order_queue = match side {
+                OrderSide::Bid => &mut self.bid_queue,
+                OrderSide::Ask => &mut self.ask_queue,
+            };
+            order_queue.cancel(order.order_id);
+            report_unfilled(
+                results,
+                order.order_id,
+                side,
+                order.qty,
+                order.order_creator,
+            );
+        }
+    }
+
+    /// Store new stop order
+    fn add_trailing_stop(
+        &mut self,
+        results: &mut OrderProcessingResult<Price, Qty>,
+        stop: TrailingStop<Price, Qty>,
+    ) {
+        results.push(Ok(Success::Accepted {
+            id: stop.order_id,
+            order_type: OrderType::TrailingStop,
+            order_creator: stop.order_creator.clone(),
+            ts: get_current_time(),
+        }));
+        self.trailing_stops.push(stop);
+    }
+
+    /// Reduce active, stop or pending order by `qty`, cancelling it if
+    /// nothing is left or `qty` is `None`.
+    ///
+    /// Returns released and remaining quantity with the order creator.
+    fn reduce_order(
+        &mut self,
+        order_id: u64,
+        side: OrderSide,
+        qty: Option<Qty>,
+    ) -> Option<(Qty, Qty, String)> {
+        let left_after = |current: Qty| match qty {
+            Some(qty) => current.checked_sub(qty).unwrap_or_default(),
+            None => Qty::zero(),
+        };
+
+        let order_queue = match side {
+            OrderSide::Bid => &mut self.bid_queue,
+            OrderSide::Ask => &mut self.ask_queue,
+        };
+        if let Some(order) = order_queue.get(order_id).cloned() {
+            let left = left_after(order.qty);
+            if left.is_zero() {
+                order_queue.cancel(order_id);
+            } else {
+                order_queue.modify_order(
+                    order_id,
+                    left,
+                    Order {
+                        qty: left,
+                        ..order.clone()
+                    },
+                );
+            }
+            let released = numeric::left_after(order.qty, left);
+            return Some((released, left, order.order_creator));
+        }
+
+        if let Some(index) = self.trailing_stop_index(order_id, side) {
+            let (current, order_creator) = {
+                let stop = &self.trailing_stops[index];
+                (stop.qty, stop.order_creator.clone())
+            };
+            let left = left_after(current);
+            if left.is_zero() {
+                self.trailing_stops.remove(index);
+            } else {
+                self.trailing_stops[index].qty = left;
             }
+            return Some((numeric::left_after(current, left), left, order_creator));
+        }
 
-            // matching incomplete
-            return false;
+        let index = self
+            .pending_orders
+            .iter()
+            .position(|pending| pending.order_id == order_id && pending.order.side == side)?;
+        let (current, order_creator) = {
+            let pending = &self.pending_orders[index].order;
+            (pending.qty, pending.order_creator.clone())
+        };
+        let left = left_after(current);
+        if left.is_zero() {
+            self.pending_orders.remove(index);
         } else {
-            // orders exactly match -> fill both and remove old limit
+            self.pending_orders[index].order.qty = left;
+        }
+        Some((numeric::left_after(current, left), left, order_creator))
+    }
 
-            // report filled new order
-            results.push(Ok(Success::Filled {
-                order_id,
+    /// Turn price levels changed by the request into numbered book deltas
+    fn record_book_deltas(&mut self) {
+        let bids = self.bid_queue.take_changed_levels();
+        let asks = self.ask_queue.take_changed_levels();
+        if bids.is_empty() && asks.is_empty() {
+            return;
+        }
+
+        let checksum = self.checksum();
+        let levels = bids
+            .into_iter()
+            .map(|level| (OrderSide::Bid, level))
+            .chain(asks.into_iter().map(|level| (OrderSide::Ask, level)));
+        for (side, (price, qty)) in levels {
+            self.book_seq += 1;
+            self.book_deltas.push(BookDelta {
+                seq: self.book_seq,
                 side,
-                order_type,
-                price: opposite_order.price,
+                price,
+                qty,
+                checksum,
+            });
+        }
+    }
+
+    /// Next sequence ID which is not used by an active or pending order,
+    /// `None` if all of them are used
+    fn next_order_id(&mut self) -> Option<u64> {
+        if !self.assigned_ids.is_empty() {
+            return Some(self.assigned_ids.remove(0));
+        }
+        // a single round of the sequence
+        for _ in MIN_SEQUENCE_ID..=MAX_SEQUENCE_ID {
+            let order_id = self.seq.next_id();
+            if !self.is_id_in_use(order_id) {
+                return Some(order_id);
+            }
+        }
+        None
+    }
+
+    /// ID the next new order gets, without taking it
+    fn peek_order_id(&self) -> Option<u64> {
+        if let Some(order_id) = self.assigned_ids.first() {
+            return Some(*order_id);
+        }
+        let mut seq = self.seq.clone();
+        for _ in MIN_SEQUENCE_ID..=MAX_SEQUENCE_ID {
+            let order_id = seq.next_id();
+            if !self.is_id_in_use(order_id) {
+                return Some(order_id);
+            }
+        }
+        None
+    }
+
+    /// Whether IDs are left for `count` new orders
+    fn has_free_order_ids(&self, count: usize) -> bool {
+        if !self.assigned_ids.is_empty() {
+            return self.assigned_ids.len() >= count;
+        }
+        (MIN_SEQUENCE_ID..=MAX_SEQUENCE_ID)
+            .filter(|order_id| !self.is_id_in_use(*order_id))
+            .take(count)
+            .count()
+            == count
+    }
+
+    /// Book for simulation of a new market or limit order, with the same
+    /// settings but only with the orders at price levels the order may reach
+    /// and the best displayed orders of both sides.
+    fn scratch_book(&self, request: &OrderRequest<Asset, Price, Qty>) -> Option<Self>
+    where
+        Policy: Clone,
+    {
+        let (side, limit_price, qty) = match *request {
+            OrderRequest::NewMarketOrder { side, qty, .. } => (side, None, qty),
+            OrderRequest::NewLimitOrder {
+                side,
+                price,
+                qty,
+                peg: None,
+                ..
+            } => (side, Some(price), qty),
+            _ => return None,
+        };
+
+        let mut book = Orderbook {
+            order_asset: self.order_asset,
+            price_asset: self.price_asset,
+            bid_queue: OrderQueue::new(OrderSide::Bid, 0),
+            ask_queue: OrderQueue::new(OrderSide::Ask, 0),
+            seq: self.seq.clone(),
+            order_validator: self.order_validator.clone(),
+            price_band: self.price_band,
+            fees: self.fees,
+            last_trade_price: self.last_trade_price,
+            halted_until: self.halted_until,
+            trading_phase: self.trading_phase,
+            matching_policy: self.matching_policy.clone(),
+            max_fills: self.max_fills,
+            pending_orders: vec![],
+            client_order_ids: HashMap::new(),
+            book_seq: self.book_seq,
+            book_deltas: vec![],
+            pegged_orders: vec![],
+            peg_references: self.peg_references,
+            trailing_stops: vec![],
+            oco_links: HashMap::new(),
+            assigned_ids: vec![self.peek_order_id()?],
+        };
+        if let Some(key) = client_order_key(request) {
+            if let Some(taken) = self.client_order_ids.get(&key) {
+                book.client_order_ids.insert(key, *taken);
+            }
+        }
+
+        let (own_queue, opposite_queue) = match side {
+            OrderSide::Bid => (&self.bid_queue, &self.ask_queue),
+            OrderSide::Ask => (&self.ask_queue, &self.bid_queue),
+        };
+
+        // whole levels, until the order quantity is covered as in `executable_qty`
+        let mut orders: Vec<&Order<Asset, Price, Qty>> = vec![];
+        let mut left = qty;
+        for order in opposite_queue.iter() {
+            let crosses = match (side, limit_price) {
+                (_, None) => true,
+                (OrderSide::Bid, Some(price)) => price >= order.price,
+                (OrderSide::Ask, Some(price)) => price <= order.price,
+            };
+            let next_level = match orders.last() {
+                Some(last) => last.price != order.price,
+                None => true,
+            };
+            if !crosses || (left.is_zero() && next_level) {
+                break;
+            }
+            if order.min_fill() <= left {
+                left = numeric::left_after(left, left.min(order.qty));
+            }
+            orders.push(order);
+        }
+
+        // band reference may be the mid price
+        for order_queue in &[own_queue, opposite_queue] {
+            if let Some(best) = order_queue.iter().find(|order| !order.hidden) {
+                if orders.iter().all(|order| order.order_id != best.order_id) {
+                    orders.push(best);
+                }
+            }
+        }
+
+        // insertion order keeps time priority
+        for (ts, order) in orders.into_iter().enumerate() {
+            let order_queue = match order.side {
+                OrderSide::Bid => &mut book.bid_queue,
+                OrderSide::Ask => &mut book.ask_queue,
+            };
+            let (id, price, qty, ts) = (order.order_id, order.price, order.qty, ts as u64);
+            if order.hidden {
+                order_queue.insert_hidden(id, price, qty, ts, order.clone());
+            } else {
+                order_queue.insert(id, price, qty, ts, order.clone());
+            }
+        }
+        Some(book)
+    }
+
+    /// Amended order is not matched, so it must stay on its side of the book
+    fn amend_crosses_book(&self, side: OrderSide, price: Price) -> bool {
+        if self.trading_phase == TradingPhase::Auction {
+            return false;
+        }
+        match side {
+            OrderSide::Bid => match self.ask_queue.iter().next() {
+                Some(ask) => price >= ask.price,
+                None => false,
+            },
+            OrderSide::Ask => match self.bid_queue.iter().next() {
+                Some(bid) => price <= bid.price,
+                None => false,
+            },
+        }
+    }
+
+    /// Best opposite price with an order accepting a fill of `qty`, hidden
+    /// orders included.
+    ///
+    /// Constrained orders which don't accept it are skipped, so they don't
+    /// block matching of orders behind them.
+    fn best_matchable_price(&self, side: OrderSide, qty: Qty) -> Option<Price> {
+        let opposite_queue = match side {
+            OrderSide::Bid => &self.ask_queue,
+            OrderSide::Ask => &self.bid_queue,
+        };
+        opposite_queue
+            .iter()
+            .find(|order| order.min_fill() <= qty)
+            .map(|order| order.price)
+    }
+
+    /// Quantity of a new order which could be executed at once at `price` or
+    /// better, within the price band
+    fn executable_qty(
+        &self,
+        side: OrderSide,
+        price: Price,
+        qty: Qty,
+        reference_price: Option<Price>,
+    ) -> Qty {
+        let opposite_queue = match side {
+            OrderSide::Bid => &self.ask_queue,
+            OrderSide::Ask => &self.bid_queue,
+        };
+        let mut left = qty;
+        for order in opposite_queue.iter() {
+            let crosses = match side {
+                OrderSide::Bid => price >= order.price,
+                OrderSide::Ask => price <= order.price,
+            };
+            if !crosses || left.is_zero() || !self.within_price_band(reference_price, order.price) {
+                break;
+            }
+            if order.min_fill() <= left {
+                left = numeric::left_after(left, left.min(order.qty));
+            }
+        }
+        numeric::left_after(qty, left)
+    }
+
+    /// Price of the pegged order for the current book
+    fn peg_price(&self, peg: &Peg<Price>, side: OrderSide) -> Option<Price> {
+        peg.price(
+            side,
+            self.peg_reference(OrderSide::Bid),
+            self.peg_reference(OrderSide::Ask),
+        )
+    }
+
+    /// Best displayed price of the side, pegged orders don't follow each other
+    fn peg_reference(&self, side: OrderSide) -> Option<Price> {
+        let order_queue = match side {
+            OrderSide::Bid => &self.bid_queue,
+            OrderSide::Ask => &self.ask_queue,
+        };
+        order_queue
+            .iter()
+            .find(|order| !order.hidden && order.peg.is_none())
+            .map(|order| order.price)
+    }
+
+    fn suspend_matching(
+        &mut self,
+        results: &mut OrderProcessingResult<Price, Qty>,
+        pending: PendingOrder<Price, Qty>,
+    ) {
+        results.push(Ok(Success::MatchingSuspended {
+            order_id: pending.order_id,
+            qty: pending.order.qty,
+            ts: get_current_time(),
+        }));
+        self.pending_orders.push(pending);
+    }
+
+    fn trailing_stop_index(&self, order_id: u64, side: OrderSide) -> Option<usize> {
+        self.trailing_stops
+            .iter()
+            .position(|stop| stop.order_id == order_id && stop.side == side)
+    }
+
+    /// Check if active order belongs to the creator
+    pub fn is_owned_by(&self, order_id: u64, side: OrderSide, order_creator: &str) -> bool {
+        let order_queue = match side {
+            OrderSide::Bid => &self.bid_queue,
+            OrderSide::Ask => &self.ask_queue,
+        };
+        match order_queue.get(order_id) {
+            Some(order) => order.order_creator == order_creator,
+            None => false,
+        }
+    }
+
+    /// Fill resting order by the auction
+    fn fill_resting_order(
+        &mut self,
+        results: &mut OrderProcessingResult<Price, Qty>,
+        order: &Order<Asset, Price, Qty>,
+        price: Price,
+        qty: Qty,
+        deal_time: u64,
+    ) {
+        // auction fills have no aggressor, both sides are makers, and there
+        // is no taker fee to pay rebates from
+        let fee = self.fees.maker_fee(qty.into()).max(0);
+        let fee_asset = FeeAsset::received_by(order.side);
+        let order_queue = match order.side {
+            OrderSide::Bid => &mut self.bid_queue,
+            OrderSide::Ask => &mut self.ask_queue,
+        };
+
+        if qty < order.qty {
+            results.push(Ok(Success::PartiallyFilled {
+                order_id: order.order_id,
+                side: order.side,
+                order_type: OrderType::Limit,
+                price,
                 qty,
-                order_creator: order_creator.to_string(),
+                fee,
+                fee_asset,
+                order_creator: order.order_creator.clone(),
                 ts: deal_time,
             }));
-            // report filled opposite limit order
+            let left = numeric::left_after(order.qty, qty);
+            order_queue.modify_order(
+                order.order_id,
+                left,
+                Order {
+                    qty: left,
+                    ..order.clone()
+                },
+            );
+        } else {
             results.push(Ok(Success::Filled {
-                order_id: opposite_order.order_id,
-                side: opposite_order.side,
+                order_id: order.order_id,
+                side: order.side,
                 order_type: OrderType::Limit,
-                price: opposite_order.price,
+                price,
                 qty,
-                order_creator: order_creator.to_string(),
+                fee,
+                fee_asset,
+                order_creator: order.order_creator.clone(),
                 ts: deal_time,
             }));
+            order_queue.cancel(order.order_id);
+        }
+    }
 
-            // remove filled limit order from the queue
-            {
-                let opposite_queue = match side {
-                    OrderSide::Bid => &mut self.ask_queue,
-                    OrderSide::Ask => &mut self.bid_queue,
-                };
-                opposite_queue.pop();
+    /// Report trading halt, or resume trading if cooldown has passed
+    fn is_halted(&mut self, results: &mut OrderProcessingResult<Price, Qty>) -> bool {
+        if let Some(until) = self.halted_until {
+            let now = get_current_time();
+            if now < until {
+                results.push(Err(Failed::TradingHalted(until)));
+                return true;
             }
+
+            self.halted_until = None;
+            results.push(Ok(Success::TradingResumed { ts: now }));
         }
+        false
+    }
 
-        // complete matching
-        true
+    /// Reference price is fixed before matching, so an order can't walk the book out of the band
+    fn band_reference_price(&self) -> Option<Price> {
+        let reference = self.price_band?.reference;
+        match (reference, self.last_trade_price) {
+            (ReferencePrice::LastTrade, Some(price)) => Some(price),
+            _ => {
+                let (bid, ask) = self.current_spread()?;
+                Some(bid.midpoint(ask))
+            }
+        }
     }
-}
 
-#[cfg(test)]
-mod test {
+    /// Check price against the band, any price is within it without a reference
+    fn within_price_band(&self, reference_price: Option<Price>, price: Price) -> bool {
+        match (self.price_band, reference_price) {
+            (Some(band), Some(reference_price)) => band.within_band(reference_price, price),
+            _ => true,
+        }
+    }
 
-    use super::super::orders;
-    use super::*;
+    /// Verify execution price, reject (and halt trading if configured) when out of band
+    fn check_price_band(
+        &mut self,
+        results: &mut OrderProcessingResult<Price, Qty>,
+        order_id: u64,
+        reference_price: Option<Price>,
+        price: Price,
+    ) -> bool {
+        let (band, reference_price) = match (self.price_band, reference_price) {
+            (Some(band), Some(reference_price)) => (band, reference_price),
+            _ => return true,
+        };
 
-    #[derive(PartialEq, Eq, Debug, Copy, Clone)]
-    pub enum Asset {
-        USD,
-        BTC,
+        if band.within_band(reference_price, price) {
+            return true;
+        }
+
+        results.push(Err(Failed::PriceBandViolation(order_id)));
+
+        if band.action == BandAction::Halt {
+            let now = get_current_time();
+            let until = now + band.cooldown;
+            self.halted_until = Some(until);
+            results.push(Ok(Success::TradingHalted {
+                reference_price,
+                price,
+                until,
+                ts: now,
+            }));
+        }
+        false
+    }
+
+    fn store_new_limit_order(
+        &mut self,
+        results: &mut OrderProcessingResult<Price, Qty>,
+        order_id: u64,
+        order: NewLimitOrder<Price, Qty>,
+    ) {
+        let NewLimitOrder {
+            side,
+            price,
+            qty,
+            order_creator,
+            client_order_id,
+            hidden,
+            peg,
+            constraint,
+            ts,
+        } = order;
+        let order_queue = match side {
+            OrderSide::Bid => &mut self.bid_queue,
+            OrderSide::Ask => &mut self.ask_queue,
+        };
+        let order = Order {
+            order_id,
+            order_asset: self.order_asset,
+            price_asset: self.price_asset,
+            side,
+            price,
+            qty,
+            order_creator,
+            client_order_id,
+            hidden,
+            peg,
+            constraint,
+        };
+        let inserted = if hidden {
+            order_queue.insert_hidden(order_id, price, qty, ts, order)
+        } else {
+            order_queue.insert(order_id, price, qty, ts, order)
+        };
+        if !inserted {
+            results.push(Err(Failed::DuplicateOrderID(order_id)))
+        } else if peg.is_some() && !self.pegged_orders.contains(&(side, order_id)) {
+            // the ID may still be listed for a previous order
+            self.pegged_orders.push((side, order_id));
+        }
+    }
+
+    /// Allocate `qty` between orders of the level by matching policy.
+    ///
+    /// Hidden orders go after displayed ones and share what is left. If an
+    /// order doesn't accept its allocation, the last such order gets nothing
+    /// and the quantity is allocated again between the others.
+    fn allocate(&self, level: &[Order<Asset, Price, Qty>], qty: Qty) -> Vec<Qty> {
+        let mut eligible: Vec<bool> = level.iter().map(|order| order.min_fill() <= qty).collect();
+        loop {
+            let mut allocations = vec![Qty::zero(); level.len()];
+            let mut left = qty;
+            for hidden in &[false, true] {
+                let tier: Vec<usize> = (0..level.len())
+                    .filter(|&i| eligible[i] && level[i].hidden == *hidden)
+                    .collect();
+                let resting: Vec<Qty> = tier.iter().map(|&i| level[i].qty).collect();
+                let tier_qty = match Qty::from_u128(numeric::total(resting.iter().cloned())) {
+                    Some(tier_qty) => left.min(tier_qty),
+                    None => left,
+                };
+                let tier_allocations = self.matching_policy.allocate(tier_qty, &resting);
+                for (&i, allocation) in tier.iter().zip(tier_allocations) {
+                    allocations[i] = allocation;
+                }
+                left = numeric::left_after(left, tier_qty);
+            }
+
+            // a single order left always accepts its allocation
+            let rejected = (0..level.len())
+                .rev()
+                .find(|&i| !allocations[i].is_zero() && allocations[i] < level[i].min_fill());
+            match rejected {
+                Some(i) => eligible[i] = false,
+                None => return allocations,
+            }
+        }
+    }
+
+    /// Match new order against the opposite price level at `price`.
+    ///
+    /// Level quantity is allocated between resting orders by matching policy,
+    /// respecting their fill constraints, at most `max_fills` of them are filled.
+    /// Returns quantity left unmatched and number of filled opposite orders.
+    fn order_matching(
+        &mut self,
+        results: &mut OrderProcessingResult<Price, Qty>,
+        order_id: u64,
+        order_type: OrderType,
+        side: OrderSide,
+        price: Price,
+        qty: Qty,
+        order_creator: &str,
+        max_fills: u64,
+    ) -> (Qty, u64) {
+        // real processing time
+        let deal_time = get_current_time();
+
+        let level: Vec<Order<Asset, Price, Qty>> = match side {
+            OrderSide::Bid => self.ask_queue.level(price),
+            OrderSide::Ask => self.bid_queue.level(price),
+        }
+        .into_iter()
+        .cloned()
+        .collect();
+        let allocations = self.allocate(&level, qty);
+
+        let opposite_queue = match side {
+            OrderSide::Bid => &mut self.ask_queue,
+            OrderSide::Ask => &mut self.bid_queue,
+        };
+
+        let mut remaining = qty;
+        let mut fills = 0;
+        for (opposite_order, fill_qty) in level.iter().zip(allocations) {
+            if fill_qty.is_zero() {
+                continue;
+            }
+            if fills == max_fills {
+                break;
+            }
+            fills += 1;
+            remaining = numeric::left_after(remaining, fill_qty);
+            self.last_trade_price = Some(opposite_order.price);
+            for stop in &mut self.trailing_stops {
+                stop.track(opposite_order.price);
+            }
+            let taker_fee = self.fees.taker_fee(fill_qty.into());
+            let maker_fee = self.fees.maker_fee(fill_qty.into());
+            let maker_fee_asset = FeeAsset::of_maker_fee(opposite_order.side, maker_fee);
+
+            // report new order
+            if remaining.is_zero() {
+                results.push(Ok(Success::Filled {
+                    order_id,
+                    side,
+                    order_type,
+                    price: opposite_order.price,
+                    qty: fill_qty,
+                    fee: taker_fee,
+                    fee_asset: FeeAsset::received_by(side),
+                    order_creator: order_creator.to_string(),
+                    ts: deal_time,
+                }));
+            } else {
+                results.push(Ok(Success::PartiallyFilled {
+                    order_id,
+                    side,
+                    order_type,
+                    price: opposite_order.price,
+                    qty: fill_qty,
+                    fee: taker_fee,
+                    fee_asset: FeeAsset::received_by(side),
+                    order_creator: order_creator.to_string(),
+                    ts: deal_time,
+                }));
+            }
+
+            // report opposite limit order
+            if fill_qty < opposite_order.qty {
+                results.push(Ok(Success::PartiallyFilled {
+                    order_id: opposite_order.order_id,
+                    side: opposite_order.side,
+                    order_type: OrderType::Limit,
+                    price: opposite_order.price,
+                    qty: fill_qty,
+                    fee: maker_fee,
+                    fee_asset: maker_fee_asset,
+                    order_creator: opposite_order.order_creator.clone(),
+                    ts: deal_time,
+                }));
+
+                // modify unmatched part of the opposite limit order
+                let left = numeric::left_after(opposite_order.qty, fill_qty);
+                opposite_queue.modify_order(
+                    opposite_order.order_id,
+                    left,
+                    Order {
+                        qty: left,
+                        ..opposite_order.clone()
+                    },
+                );
+            } else {
+                results.push(Ok(Success::Filled {
+                    order_id: opposite_order.order_id,
+                    side: opposite_order.side,
+                    order_type: OrderType::Limit,
+                    price: opposite_order.price,
+                    qty: fill_qty,
+                    fee: maker_fee,
+                    fee_asset: maker_fee_asset,
+                    order_creator: opposite_order.order_creator.clone(),
+                    ts: deal_time,
+                }));
+
+                // remove filled limit order from the queue
+                opposite_queue.cancel(opposite_order.order_id);
+            }
+        }
+
+        (remaining, fills)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::super::matching::{ProRata, Rounding};
+    use super::super::orders;
+    use super::super::pegging::PegReference;
+    use super::super::stops::Trail;
+    use super::super::test_utils::set_block_timestamp;
+    use super::near_sdk::serde_json;
+    use super::*;
+
+    #[derive(
+        PartialEq, Eq, Debug, Copy, Clone, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+    )]
+    pub enum Asset {
+        USD,
+        BTC,
     }
 
     #[test]
     fn cancel_nonexisting() {
+        set_block_timestamp(0);
         let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
         let request = orders::limit_order_cancel_request(1, OrderSide::Bid);
         let mut result = orderbook.process_order(request);
@@ -601,4 +2573,1386 @@ mod test {
             _ => panic!("unexpected events"),
         }
     }
+
+    fn limit_order(side: OrderSide, price: f64, qty: u128) -> OrderRequest<Asset> {
+        orders::new_limit_order_request(
+            Asset::BTC,
+            Asset::USD,
+            side,
+            price,
+            qty,
+            String::from("trader"),
+            0,
+        )
+    }
+
+    fn get_orderbook_with_band(action: BandAction) -> Orderbook<Asset> {
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Bid, 0.99, 1));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 1));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.50, 1));
+        orderbook.set_price_band(Some(PriceBand::new(
+            10.0,
+            ReferencePrice::LastTrade,
+            action,
+            1_000,
+        )));
+        orderbook
+    }
+
+    #[test]
+    fn price_band_reject() {
+        set_block_timestamp(0);
+        let mut orderbook = get_orderbook_with_band(BandAction::Reject);
+
+        // first level is within the band, second one is not
+        let result = orderbook.process_order(orders::new_market_order_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Bid,
+            2,
+            String::from("trader"),
+            0,
+        ));
+
+        assert_eq!(result.len(), 4);
+        match result.last().unwrap() {
+            Err(Failed::PriceBandViolation(_)) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.last_trade_price(), Some(1.01));
+        assert_eq!(orderbook.current_spread(), Some((0.99, 1.50)));
+        assert_eq!(orderbook.halted_until(), None);
+    }
+
+    #[test]
+    fn price_band_halt() {
+        set_block_timestamp(0);
+        let mut orderbook = get_orderbook_with_band(BandAction::Halt);
+
+        let result = orderbook.process_order(limit_order(OrderSide::Bid, 1.50, 2));
+        match result[result.len() - 2] {
+            Ok(Success::TradingHalted { price, .. }) => assert_eq!(price, 1.50),
+            _ => panic!("unexpected events"),
+        }
+        // the rest of the order which isn't stored is given back
+        match result.last().unwrap() {
+            Ok(Success::Unfilled { qty, .. }) => assert_eq!(*qty, 1),
+            _ => panic!("unexpected events"),
+        }
+        assert!(orderbook.halted_until().is_some());
+
+        // new orders are rejected until cooldown has passed
+        let mut result = orderbook.process_order(limit_order(OrderSide::Ask, 1.02, 1));
+        assert_eq!(result.len(), 1);
+        match result.pop().unwrap() {
+            Err(Failed::TradingHalted(_)) => (),
+            _ => panic!("unexpected events"),
+        }
+
+        // cancel is still possible
+        let mut result =
+            orderbook.process_order(orders::limit_order_cancel_request(1, OrderSide::Bid));
+        match result.pop().unwrap() {
+            Ok(Success::Cancelled { .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+    }
+
+    #[test]
+    fn auction_uncross() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.start_auction();
+
+        // crossing orders are collected without matching
+        orderbook.process_order(limit_order(OrderSide::Bid, 1.03, 2));
+        orderbook.process_order(limit_order(OrderSide::Bid, 1.02, 3));
+        orderbook.process_order(limit_order(OrderSide::Bid, 1.00, 5));
+        orderbook.process_order(limit_order(OrderSide::Ask, 0.99, 1));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 4));
+        let result = orderbook.process_order(limit_order(OrderSide::Ask, 1.02, 4));
+        assert_eq!(result.len(), 1);
+
+        // no market orders during auction
+        let mut result = orderbook.process_order(orders::new_market_order_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Bid,
+            1,
+            String::from("trader"),
+            0,
+        ));
+        match result.pop().unwrap() {
+            Err(Failed::AuctionInProgress) => (),
+            _ => panic!("unexpected events"),
+        }
+
+        assert_eq!(orderbook.indicative_uncross(), Some((1.01, 5)));
+
+        let result = orderbook.uncross();
+        match result.first().unwrap() {
+            Ok(Success::AuctionUncrossed { price, qty, .. }) => {
+                assert_eq!(*price, 1.01);
+                assert_eq!(*qty, 5);
+            }
+            _ => panic!("unexpected events"),
+        }
+        for event in result.iter().skip(1) {
+            match event {
+                Ok(Success::Filled { price, .. }) | Ok(Success::PartiallyFilled { price, .. }) => {
+                    assert_eq!(*price, 1.01)
+                }
+                _ => panic!("unexpected events"),
+            }
+        }
+
+        assert_eq!(orderbook.trading_phase(), TradingPhase::Continuous);
+        assert_eq!(orderbook.last_trade_price(), Some(1.01));
+        assert_eq!(orderbook.current_spread(), Some((1.00, 1.02)));
+    }
+
+    #[test]
+    fn batch_all_or_nothing() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Bid, 0.99, 1));
+
+        // cancel of unknown order rejects the whole batch
+        let result = orderbook.process_batch(
+            vec![
+                limit_order(OrderSide::Ask, 1.01, 1),
+                orders::limit_order_cancel_request(7, OrderSide::Bid),
+            ],
+            BatchMode::AllOrNothing,
+        );
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].len(), 1);
+        assert_eq!(result[1].len(), 2);
+        match result[1][0] {
+            Err(Failed::OrderNotFound(7)) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.current_spread(), None);
+
+        // the same batch in best effort mode
+        let result = orderbook.process_batch(
+            vec![
+                limit_order(OrderSide::Ask, 1.01, 1),
+                orders::limit_order_cancel_request(7, OrderSide::Bid),
+            ],
+            BatchMode::BestEffort,
+        );
+        match result[0][0] {
+            Ok(Success::Accepted { .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        match result[1][0] {
+            Err(Failed::OrderNotFound(7)) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.current_spread(), Some((0.99, 1.01)));
+    }
+
+    #[test]
+    fn batch_rejected_undo() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 2));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 2));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.02, 1));
+        orderbook.process_order(orders::new_trailing_stop_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Bid,
+            1,
+            Trail::Fixed(1.01),
+            String::from("trader"),
+            0,
+        ));
+        orderbook.take_book_deltas();
+        let indices = orderbook.ask_queue.indices();
+        let book_sequence = orderbook.book_sequence();
+
+        // fills, triggered stop and new orders are undone
+        let result = orderbook.process_batch(
+            vec![
+                limit_order(OrderSide::Bid, 1.01, 3).with_client_order_id(String::from("b1")),
+                limit_order(OrderSide::Bid, 1.0, 1),
+                orders::limit_order_cancel_request(9, OrderSide::Bid),
+            ],
+            BatchMode::AllOrNothing,
+        );
+        match result[2][0] {
+            Err(Failed::OrderNotFound(9)) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.ask_queue.indices(), indices);
+        assert_eq!(orderbook.current_spread(), None);
+        assert_eq!(orderbook.last_trade_price(), None);
+        assert_eq!(orderbook.trailing_stops().len(), 1);
+        assert_eq!(orderbook.book_sequence(), book_sequence);
+        assert!(orderbook.take_book_deltas().is_empty());
+        assert!(!orderbook.is_client_order_id_used("trader", "b1"));
+        assert!(orderbook.check_invariants().is_ok());
+
+        // order IDs are taken again
+        let result = orderbook.process_order(limit_order(OrderSide::Bid, 1.0, 1));
+        match result[0] {
+            Ok(Success::Accepted { id: 5, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+    }
+
+    #[test]
+    fn batch_dependent_requests() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Bid, 0.99, 1));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 1));
+
+        // amend of an order cancelled earlier in the batch
+        let result = orderbook.process_batch(
+            vec![
+                orders::limit_order_cancel_request(1, OrderSide::Bid),
+                orders::amend_order_request(1, OrderSide::Bid, 0.98, 1, 0),
+            ],
+            BatchMode::AllOrNothing,
+        );
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].len(), 1);
+        match result[1][0] {
+            Err(Failed::OrderNotFound(1)) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.current_spread(), Some((0.99, 1.01)));
+
+        // amend of an order filled earlier in the batch
+        let result = orderbook.process_batch(
+            vec![
+                limit_order(OrderSide::Ask, 0.99, 1),
+                orders::amend_order_request(1, OrderSide::Bid, 0.98, 1, 0),
+            ],
+            BatchMode::AllOrNothing,
+        );
+        match result[0][0] {
+            Err(Failed::BatchRejected) => (),
+            _ => panic!("unexpected events"),
+        }
+        match result[1][0] {
+            Err(Failed::OrderNotFound(1)) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.current_spread(), Some((0.99, 1.01)));
+        assert_eq!(orderbook.bid_queue.peek().unwrap().qty, 1);
+
+        // amend which crosses the book only before the cancel of the ask
+        let result = orderbook.process_batch(
+            vec![
+                orders::limit_order_cancel_request(2, OrderSide::Ask),
+                orders::amend_order_request(1, OrderSide::Bid, 1.01, 1, 0),
+            ],
+            BatchMode::AllOrNothing,
+        );
+        match result[0][0] {
+            Ok(Success::Cancelled { id: 2, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        match result[1][0] {
+            Ok(Success::Amended { id: 1, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.bid_queue.peek().unwrap().price, 1.01);
+        assert_eq!(orderbook.ask_queue.peek(), None);
+        assert_eq!(orderbook.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn mass_cancel_by_creator() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Bid, 0.98, 1));
+        orderbook.process_order(limit_order(OrderSide::Bid, 0.99, 2));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 3));
+        orderbook.process_order(orders::new_limit_order_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Bid,
+            0.99,
+            4,
+            String::from("another trader"),
+            0,
+        ));
+
+        // only bids within the range
+        let result = orderbook.process_order(orders::mass_cancel_request(
+            String::from("trader"),
+            Some(OrderSide::Bid),
+            Some((0.985, 1.0)),
+        ));
+        assert_eq!(result.len(), 1);
+        match result[0] {
+            Ok(Success::Cancelled { id: 2, qty: 2, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+
+        // everything else of the creator
+        let result = orderbook.process_order(orders::mass_cancel_request(
+            String::from("trader"),
+            None,
+            None,
+        ));
+        assert_eq!(result.len(), 2);
+        assert_eq!(orderbook.current_spread(), None);
+
+        let order = orderbook.bid_queue.peek().unwrap();
+        assert_eq!(order.order_creator, "another trader");
+    }
+
+    #[test]
+    fn cancel_replace() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.02, 3));
+
+        let result = orderbook.process_order(orders::cancel_replace_request(
+            1,
+            OrderSide::Ask,
+            1.01,
+            2,
+            String::from("trader"),
+            0,
+        ));
+        assert_eq!(result.len(), 2);
+        match result[0] {
+            Ok(Success::Cancelled { id: 1, qty: 3, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        match result[1] {
+            Ok(Success::Accepted { id: 2, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+
+        // replaced order is filled, so replacement fails
+        orderbook.process_order(limit_order(OrderSide::Bid, 1.01, 2));
+        let mut result = orderbook.process_order(orders::cancel_replace_request(
+            2,
+            OrderSide::Ask,
+            1.03,
+            2,
+            String::from("trader"),
+            0,
+        ));
+        assert_eq!(result.len(), 1);
+        match result.pop().unwrap() {
+            Err(Failed::OrderNotFound(2)) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.current_spread(), None);
+    }
+
+    #[test]
+    fn cancel_replace_pegged() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Bid, 1.0, 1));
+        let primary = Peg::new(PegReference::Primary, 0.25, None);
+        orderbook.process_order(limit_order(OrderSide::Bid, 0.5, 1).with_peg(primary));
+
+        // replacement follows the same peg instead of the request price
+        orderbook.process_order(orders::cancel_replace_request(
+            2,
+            OrderSide::Bid,
+            0.5,
+            3,
+            String::from("trader"),
+            0,
+        ));
+        let order = orderbook.bid_queue.get(3).unwrap();
+        assert_eq!(order.peg, Some(primary));
+        assert_eq!(order.price, 0.75);
+
+        orderbook.process_order(limit_order(OrderSide::Bid, 1.5, 1));
+        assert_eq!(orderbook.bid_queue.get(3).unwrap().price, 1.25);
+        assert!(orderbook.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn pro_rata_matching() {
+        set_block_timestamp(0);
+        let mut orderbook =
+            Orderbook::with_policy(Asset::BTC, Asset::USD, ProRata::new(0, Rounding::Down));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 6));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 4));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 2));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.02, 5));
+
+        let result = orderbook.process_order(limit_order(OrderSide::Bid, 1.01, 10));
+        let fills: Vec<(u64, u128)> = result
+            .iter()
+            .filter_map(|event| match event {
+                Ok(Success::Filled { order_id, qty, .. })
+                | Ok(Success::PartiallyFilled { order_id, qty, .. }) => Some((*order_id, *qty)),
+                _ => None,
+            })
+            .collect();
+
+        // same event shape: new order fill followed by opposite order fill
+        assert_eq!(fills, vec![(5, 6), (1, 6), (5, 3), (2, 3), (5, 1), (3, 1)]);
+        match result.last().unwrap() {
+            Ok(Success::PartiallyFilled { order_id: 3, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.current_spread(), None);
+        assert_eq!(orderbook.ask_queue.peek().unwrap().qty, 1);
+    }
+
+    #[test]
+    fn fills_limit() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.set_max_fills(2);
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 1));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.02, 1));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.03, 1));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.04, 1));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.05, 1));
+
+        // the rest doesn't cross the book, so it is stored
+        let result = orderbook.process_order(limit_order(OrderSide::Bid, 1.02, 3));
+        assert_eq!(result.len(), 5);
+        assert_eq!(orderbook.current_spread(), Some((1.02, 1.03)));
+        assert_eq!(orderbook.pending_orders(), 0);
+
+        // the rest still crosses the book, matching is suspended
+        let result = orderbook.process_order(orders::new_market_order_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Bid,
+            3,
+            String::from("trader"),
+            0,
+        ));
+        match result.last().unwrap() {
+            Ok(Success::MatchingSuspended { qty: 1, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.pending_orders(), 1);
+
+        let result = orderbook.resume_matching();
+        assert_eq!(result.len(), 2);
+        match result[0] {
+            Ok(Success::Filled { price, qty: 1, .. }) => assert_eq!(price, 1.05),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.pending_orders(), 0);
+    }
+
+    #[test]
+    fn serialization_round_trip() {
+        set_block_timestamp(0);
+        let requests = vec![
+            limit_order(OrderSide::Ask, 1.01, 2),
+            orders::new_market_order_request(
+                Asset::BTC,
+                Asset::USD,
+                OrderSide::Bid,
+                3,
+                String::from("trader"),
+                0,
+            ),
+            orders::mass_cancel_request(String::from("trader"), None, Some((0.5, 1.5))),
+            orders::limit_order_cancel_request(2000, OrderSide::Bid),
+        ];
+
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        for request in requests {
+            let json = serde_json::to_string(&request).unwrap();
+            let decoded: OrderRequest<Asset> = serde_json::from_str(&json).unwrap();
+            assert_eq!(decoded, request);
+            let bytes = request.try_to_vec().unwrap();
+            let decoded = OrderRequest::<Asset>::try_from_slice(&bytes).unwrap();
+            assert_eq!(decoded, request);
+
+            for result in orderbook.process_order(request) {
+                let json = serde_json::to_string(&result).unwrap();
+                let decoded: Result<Success, Failed> = serde_json::from_str(&json).unwrap();
+                assert_eq!(decoded, result);
+                let bytes = result.try_to_vec().unwrap();
+                let decoded = Result::<Success, Failed>::try_from_slice(&bytes).unwrap();
+                assert_eq!(decoded, result);
+            }
+        }
+
+        // quantities beyond u64 survive JSON
+        let order = Order {
+            order_id: 1,
+            order_asset: Asset::BTC,
+            price_asset: Asset::USD,
+            side: OrderSide::Bid,
+            price: 1.5,
+            qty: u128::MAX,
+            order_creator: String::from("trader"),
+            client_order_id: Some(String::from("order-1")),
+            hidden: true,
+            peg: Some(Peg::new(PegReference::Mid, 0.5, Some(2.0))),
+            constraint: Some(FillConstraint::MinQty(u128::MAX)),
+        };
+        let json = serde_json::to_string(&order).unwrap();
+        assert_eq!(serde_json::from_str::<Order<Asset>>(&json).unwrap(), order);
+    }
+
+    #[test]
+    fn amend_crossing_rejected() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Bid, 0.99, 1));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 1));
+
+        let request = orders::amend_order_request(1, OrderSide::Bid, 1.01, 1, 0);
+        let result = orderbook.process_order(request);
+        match result[0] {
+            Err(Failed::AmendCrossesBook(1)) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.current_spread(), Some((0.99, 1.01)));
+        assert_eq!(orderbook.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn integer_ticks() {
+        set_block_timestamp(0);
+        let mut orderbook: Orderbook<Asset, Fifo, u64, u64> =
+            Orderbook::with_policy(Asset::BTC, Asset::USD, Fifo);
+        let tick_order = |side, price, qty| {
+            orders::new_limit_order_request(
+                Asset::BTC,
+                Asset::USD,
+                side,
+                price,
+                qty,
+                String::from("trader"),
+                0,
+            )
+        };
+        orderbook.process_order(tick_order(OrderSide::Bid, 99, 5));
+        orderbook.process_order(tick_order(OrderSide::Ask, 101, 3));
+        orderbook.process_order(tick_order(OrderSide::Ask, 102, 4));
+
+        // orders survive storage with the rebuilt price levels
+        let bytes = orderbook.try_to_vec().unwrap();
+        let mut orderbook = Orderbook::<Asset, Fifo, u64, u64>::try_from_slice(&bytes).unwrap();
+        assert_eq!(orderbook.current_spread(), Some((99, 101)));
+
+        let result = orderbook.process_order(tick_order(OrderSide::Bid, 102, 5));
+        match result[4] {
+            Ok(Success::PartiallyFilled {
+                price: 102, qty: 2, ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.last_trade_price(), Some(102));
+        assert_eq!(orderbook.current_spread(), Some((99, 102)));
+        assert_eq!(orderbook.check_invariants(), Ok(()));
+
+        let result = orderbook.process_order(tick_order(OrderSide::Ask, 101, 0));
+        match result[0] {
+            Err(Failed::ValidationFailed(ValidationError::BadQuantityValue { qty: 0 })) => (),
+            _ => panic!("unexpected events"),
+        }
+    }
+
+    #[test]
+    fn maker_taker_fees() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.set_fee_schedule(FeeSchedule::new(-2, 5).unwrap());
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 20_000));
+
+        let result = orderbook.process_order(limit_order(OrderSide::Bid, 1.01, 10_000));
+        match result[1] {
+            Ok(Success::Filled {
+                side: OrderSide::Bid,
+                fee: 5,
+                fee_asset: FeeAsset::OrderAsset,
+                ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+        match result[2] {
+            Ok(Success::PartiallyFilled {
+                side: OrderSide::Ask,
+                fee: -2,
+                fee_asset: FeeAsset::OrderAsset,
+                ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+
+        // no rebates in auction
+        orderbook.start_auction();
+        orderbook.process_order(limit_order(OrderSide::Bid, 1.01, 10_000));
+        let result = orderbook.uncross();
+        match result[2] {
+            Ok(Success::Filled { fee: 0, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+    }
+
+    #[test]
+    fn client_order_ids() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        let request = limit_order(OrderSide::Ask, 1.01, 2).with_client_order_id(String::from("a1"));
+        orderbook.process_order(request.clone());
+        assert_eq!(orderbook.client_order("trader", "a1").unwrap().order_id, 1);
+
+        // retry is rejected, other creators may use the same ID
+        let result = orderbook.process_order(request);
+        assert_eq!(
+            result,
+            vec![Err(Failed::DuplicateClientOrderID(String::from("a1")))]
+        );
+        assert!(orderbook.client_order("bob", "a1").is_none());
+
+        let result = orderbook.amend_client_order("trader", "a1", 1.02, 3, 0);
+        match result[0] {
+            Ok(Success::Amended { id: 1, qty: 3, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(
+            orderbook
+                .client_order("trader", "a1")
+                .unwrap()
+                .client_order_id,
+            Some(String::from("a1"))
+        );
+
+        let result = orderbook.cancel_client_order("trader", "a1");
+        match result[0] {
+            Ok(Success::Cancelled { id: 1, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert!(orderbook.client_order("trader", "a1").is_none());
+        let result = orderbook.cancel_client_order("trader", "a1");
+        assert_eq!(
+            result,
+            vec![Err(Failed::ClientOrderNotFound(String::from("a1")))]
+        );
+
+        // the ID stays taken after the order is gone
+        let request = limit_order(OrderSide::Ask, 1.01, 2).with_client_order_id(String::from("a1"));
+        match orderbook.process_order(request)[0] {
+            Err(Failed::DuplicateClientOrderID(_)) => (),
+            _ => panic!("unexpected events"),
+        }
+
+        // and is released a day later, unless the order is still active
+        let request = limit_order(OrderSide::Ask, 1.01, 2).with_client_order_id(String::from("a2"));
+        orderbook.process_order(request);
+        set_block_timestamp(CLIENT_ORDER_ID_TTL);
+        let request = limit_order(OrderSide::Ask, 1.01, 2).with_client_order_id(String::from("a3"));
+        orderbook.process_order(request);
+        assert!(!orderbook.is_client_order_id_used("trader", "a1"));
+        assert!(orderbook.is_client_order_id_used("trader", "a2"));
+        assert!(orderbook.is_client_order_id_used("trader", "a3"));
+    }
+
+    #[test]
+    fn book_deltas() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 2));
+        let snapshot = orderbook.snapshot(10);
+        assert_eq!(snapshot.seq, 1);
+        assert_eq!(snapshot.asks, vec![(1.01, 2)]);
+        orderbook.take_book_deltas();
+
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.02, 3));
+        orderbook.process_order(limit_order(OrderSide::Bid, 1.02, 4));
+        orderbook.process_order(limit_order(OrderSide::Bid, 1.0, 1));
+        orderbook.process_order(orders::limit_order_cancel_request(4, OrderSide::Bid));
+        let deltas = orderbook.take_book_deltas();
+        let levels: Vec<(u64, OrderSide, f64, u128)> = deltas
+            .iter()
+            .map(|delta| (delta.seq, delta.side, delta.price, delta.qty))
+            .collect();
+        assert_eq!(
+            levels,
+            vec![
+                (2, OrderSide::Ask, 1.02, 3),
+                (3, OrderSide::Ask, 1.01, 0),
+                (4, OrderSide::Ask, 1.02, 1),
+                (5, OrderSide::Bid, 1.0, 1),
+                (6, OrderSide::Bid, 1.0, 0),
+            ]
+        );
+        assert_eq!(orderbook.book_sequence(), 6);
+        assert!(orderbook.take_book_deltas().is_empty());
+        assert_eq!(orderbook.snapshot(10).asks, vec![(1.02, 1)]);
+
+        // the last delta carries checksum of the resulting book
+        let snapshot = orderbook.snapshot(10);
+        assert_eq!(deltas[4].checksum, snapshot.checksum);
+        assert_eq!(
+            snapshot.checksum,
+            market_data::book_checksum(&snapshot.bids, &snapshot.asks)
+        );
+        assert_ne!(deltas[3].checksum, snapshot.checksum);
+    }
+
+    #[test]
+    fn read_only_queries() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.02, 1));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 2));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 3));
+        orderbook.process_order(limit_order(OrderSide::Bid, 0.99, 4));
+
+        let orderbook = &orderbook;
+        assert_eq!(orderbook.current_spread(), Some((0.99, 1.01)));
+        assert_eq!(orderbook.best_price(OrderSide::Ask), Some(1.01));
+        assert_eq!(
+            orderbook.depth(OrderSide::Ask, 5),
+            vec![(1.01, 5), (1.02, 1)]
+        );
+        assert_eq!(orderbook.depth(OrderSide::Bid, 0), vec![]);
+    }
+
+    #[test]
+    fn simulate() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.set_fee_schedule(FeeSchedule::new(0, 5_000).unwrap());
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.5, 4));
+        orderbook.process_order(limit_order(OrderSide::Ask, 2.0, 4));
+        let deltas = orderbook.take_book_deltas();
+
+        let request = orders::new_market_order_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Bid,
+            10,
+            String::from("trader"),
+            0,
+        );
+        let quote = orderbook.quote(request.clone());
+        assert_eq!(quote.order_id, Some(3));
+        assert_eq!(quote.filled_qty, 8);
+        assert_eq!(quote.average_price, Some(1.75));
+        assert_eq!(quote.remaining_qty, 2);
+        assert_eq!(quote.fee, 4);
+
+        // the book is left intact, the quoted request is processed the same way
+        assert_eq!(orderbook.depth(OrderSide::Ask, 5), vec![(1.5, 4), (2.0, 4)]);
+        assert_eq!(orderbook.book_sequence(), deltas.last().unwrap().seq);
+        assert!(orderbook.take_book_deltas().is_empty());
+        assert_eq!(orderbook.process_order(request), quote.results);
+
+        // only the reachable levels and the best bid are copied
+        orderbook.process_order(limit_order(OrderSide::Ask, 2.5, 2));
+        orderbook.process_order(limit_order(OrderSide::Ask, 2.5, 2).with_hidden());
+        orderbook.process_order(limit_order(OrderSide::Ask, 3.0, 2));
+        orderbook.process_order(limit_order(OrderSide::Bid, 1.0, 2));
+        let request = limit_order(OrderSide::Bid, 2.5, 5);
+        let book = orderbook.scratch_book(&request).unwrap();
+        assert_eq!(book.ask_queue.iter().count(), 2);
+        assert_eq!(book.bid_queue.iter().count(), 1);
+        let results = orderbook.simulate(request.clone());
+        assert_eq!(orderbook.process_order(request), results);
+
+        let quote = orderbook.quote(limit_order(OrderSide::Ask, 0.0, 1));
+        assert_eq!(quote.order_id, None);
+        assert_eq!(quote.remaining_qty, 0);
+        match quote.results[0] {
+            Err(Failed::ValidationFailed(_)) => (),
+            _ => panic!("unexpected events"),
+        }
+    }
+
+    #[test]
+    fn market_impact() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Ask, 2.0, 4));
+        orderbook.process_order(limit_order(OrderSide::Ask, 2.5, 4));
+        orderbook.process_order(limit_order(OrderSide::Ask, 3.0, 8).with_hidden());
+        orderbook.process_order(limit_order(OrderSide::Bid, 1.5, 2));
+
+        assert_eq!(orderbook.depth_curve(OrderSide::Ask, 5)[1].notional, 18.0);
+        let impact = orderbook.market_impact(OrderSide::Bid, 10);
+        assert_eq!(impact.qty, 8);
+        assert_eq!(impact.average_price, Some(2.25));
+        assert_eq!(impact.slippage_bps, 1250.0);
+        assert_eq!(orderbook.market_impact(OrderSide::Ask, 10).qty, 2);
+        assert_eq!(orderbook.qty_within(OrderSide::Bid, 50.0), 8);
+    }
+
+    #[test]
+    fn hidden_orders() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Bid, 0.99, 1));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 2).with_hidden());
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.02, 1));
+
+        // not displayed anywhere
+        assert_eq!(orderbook.current_spread(), Some((0.99, 1.02)));
+        assert_eq!(orderbook.depth(OrderSide::Ask, 5), vec![(1.02, 1)]);
+        let deltas = orderbook.take_book_deltas();
+        assert!(deltas.iter().all(|delta| delta.price != 1.01));
+
+        // displayed order of the same price is matched first
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.01, 1));
+        assert_eq!(orderbook.current_spread(), Some((0.99, 1.01)));
+        let result = orderbook.process_order(limit_order(OrderSide::Bid, 1.01, 2));
+        let makers: Vec<u64> = result
+            .iter()
+            .filter_map(|event| match event {
+                Ok(Success::Filled {
+                    order_id,
+                    side: OrderSide::Ask,
+                    ..
+                })
+                | Ok(Success::PartiallyFilled {
+                    order_id,
+                    side: OrderSide::Ask,
+                    ..
+                }) => Some(*order_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(makers, vec![4, 2]);
+        assert_eq!(orderbook.depth(OrderSide::Ask, 5), vec![(1.02, 1)]);
+
+        // stays hidden when amended or replaced
+        orderbook.process_order(orders::amend_order_request(2, OrderSide::Ask, 1.015, 1, 0));
+        assert_eq!(orderbook.depth(OrderSide::Ask, 5), vec![(1.02, 1)]);
+        let result = orderbook.process_order(orders::cancel_replace_request(
+            2,
+            OrderSide::Ask,
+            1.005,
+            1,
+            String::from("trader"),
+            0,
+        ));
+        match result[0] {
+            Ok(Success::Cancelled { id: 2, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.depth(OrderSide::Ask, 5), vec![(1.02, 1)]);
+        assert!(orderbook.ask_queue.peek().unwrap().hidden);
+        assert_eq!(orderbook.current_spread(), Some((0.99, 1.02)));
+
+        // still matched at its price
+        let result = orderbook.process_order(limit_order(OrderSide::Bid, 1.01, 1));
+        match result[1] {
+            Ok(Success::Filled { price, .. }) => assert_eq!(price, 1.005),
+            _ => panic!("unexpected events"),
+        }
+        assert!(orderbook.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn pegged_orders() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Bid, 1.0, 1));
+        orderbook.process_order(limit_order(OrderSide::Ask, 2.0, 1));
+
+        // request price is replaced by the peg price
+        let primary = Peg::new(PegReference::Primary, 0.0, None);
+        orderbook.process_order(limit_order(OrderSide::Bid, 0.5, 1).with_peg(primary));
+        assert_eq!(orderbook.bid_queue.get(3).unwrap().price, 1.0);
+        let mid = Peg::new(PegReference::Mid, 0.25, None);
+        orderbook.process_order(limit_order(OrderSide::Ask, 3.0, 1).with_peg(mid));
+        assert_eq!(orderbook.ask_queue.get(4).unwrap().price, 1.75);
+
+        // follow the new best bid, behind it in time priority
+        let result = orderbook.process_order(limit_order(OrderSide::Bid, 1.5, 1));
+        assert_eq!(result.len(), 3);
+        match result[1] {
+            Ok(Success::Repriced {
+                id: 3,
+                side: OrderSide::Bid,
+                price,
+                ..
+            }) => assert_eq!(price, 1.5),
+            _ => panic!("unexpected events"),
+        }
+        match result[2] {
+            Ok(Success::Repriced { id: 4, price, .. }) => assert_eq!(price, 2.0),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.bid_queue.peek().unwrap().order_id, 5);
+
+        // and back
+        orderbook.process_order(orders::limit_order_cancel_request(5, OrderSide::Bid));
+        assert_eq!(orderbook.bid_queue.get(3).unwrap().price, 1.0);
+        assert_eq!(orderbook.ask_queue.get(4).unwrap().price, 1.75);
+
+        // capped by the limit, matched as usual
+        let market = Peg::new(PegReference::Market, 0.0, Some(1.25));
+        orderbook.process_order(limit_order(OrderSide::Bid, 1.0, 1).with_peg(market));
+        assert_eq!(orderbook.bid_queue.get(6).unwrap().price, 1.25);
+        let result = orderbook.process_order(limit_order(OrderSide::Ask, 1.1, 1));
+        match result[2] {
+            Ok(Success::Filled { order_id: 6, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.current_spread(), Some((1.0, 1.75)));
+        assert!(orderbook.check_invariants().is_ok());
+
+        // request price until the reference is available, never crossing the book
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        let market = Peg::new(PegReference::Market, 0.0, None);
+        orderbook.process_order(limit_order(OrderSide::Bid, 0.5, 1).with_peg(market));
+        assert_eq!(orderbook.bid_queue.get(1).unwrap().price, 0.5);
+        let result = orderbook.process_order(limit_order(OrderSide::Ask, 2.0, 1));
+        assert_eq!(result.len(), 1);
+        assert_eq!(orderbook.current_spread(), Some((0.5, 2.0)));
+    }
+
+    #[test]
+    fn trailing_stops() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.0, 1));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.1, 1));
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.2, 1));
+        orderbook.process_order(limit_order(OrderSide::Bid, 0.8, 5));
+
+        let stop = orders::new_trailing_stop_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Ask,
+            1,
+            Trail::Amount(0.25),
+            String::from("trader"),
+            0,
+        );
+        let result = orderbook.process_order(stop);
+        match result[0] {
+            Ok(Success::Accepted {
+                id: 5,
+                order_type: OrderType::TrailingStop,
+                ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+
+        // follows the highest trade price
+        for qty in 1..3 {
+            orderbook.process_order(orders::new_market_order_request(
+                Asset::BTC,
+                Asset::USD,
+                OrderSide::Bid,
+                qty,
+                String::from("trader"),
+                0,
+            ));
+        }
+        assert_eq!(orderbook.trailing_stops()[0].best_price, Some(1.2));
+
+        // triggered by the retrace into a market order
+        let result = orderbook.process_order(limit_order(OrderSide::Ask, 0.8, 1));
+        match result[3] {
+            Ok(Success::StopTriggered {
+                id: 5,
+                side: OrderSide::Ask,
+                order_type: OrderType::Market,
+                price,
+                ..
+            }) => assert_eq!(price, 0.8),
+            _ => panic!("unexpected events"),
+        }
+        match result[4] {
+            Ok(Success::Filled {
+                order_id: 5,
+                qty: 1,
+                ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert!(orderbook.trailing_stops().is_empty());
+        assert_eq!(orderbook.bid_queue.get(4).unwrap().qty, 3);
+
+        // stops are cancelled as usual orders
+        let stop = orders::new_trailing_stop_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Bid,
+            1,
+            Trail::Percent(10.0),
+            String::from("trader"),
+            0,
+        )
+        .with_limit_price(2.0);
+        orderbook.process_order(stop);
+        assert!(orderbook.is_id_in_use(9));
+        let result = orderbook.process_order(orders::limit_order_cancel_request(9, OrderSide::Bid));
+        match result[0] {
+            Ok(Success::Cancelled { id: 9, qty: 1, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert!(orderbook.trailing_stops().is_empty());
+        assert!(orderbook.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn unfilled_stop() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Bid, 0.5, 2));
+        orderbook.process_order(orders::new_trailing_stop_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Ask,
+            3,
+            Trail::Fixed(0.75),
+            String::from("stop trader"),
+            0,
+        ));
+
+        // the rest of the market order is returned to the stop creator
+        let result = orderbook.process_order(limit_order(OrderSide::Ask, 0.5, 1));
+        match result[3] {
+            Ok(Success::StopTriggered { id: 2, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        match result[4] {
+            Ok(Success::PartiallyFilled {
+                order_id: 2,
+                qty: 1,
+                ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+        match result[6] {
+            Err(Failed::NoMatch(2)) => (),
+            _ => panic!("unexpected events"),
+        }
+        match result[7] {
+            Ok(Success::Unfilled {
+                id: 2,
+                side: OrderSide::Ask,
+                qty: 2,
+                ref order_creator,
+                ..
+            }) => assert_eq!(order_creator, "stop trader"),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(result.len(), 8);
+        assert_eq!(orderbook.current_spread(), None);
+    }
+
+    #[test]
+    fn order_ids_exhausted() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        for _ in MIN_SEQUENCE_ID..=MAX_SEQUENCE_ID {
+            orderbook.process_order(limit_order(OrderSide::Ask, 1.0, 1));
+        }
+        assert_eq!(orderbook.ask_queue.iter().count(), 1000);
+
+        // no ID is reused while all of them are taken
+        let result = orderbook.process_order(limit_order(OrderSide::Bid, 0.5, 1));
+        assert_eq!(result, vec![Err(Failed::OrderIDsExhausted)]);
+        assert_eq!(orderbook.current_spread(), None);
+
+        // OCO pair needs two IDs, but only one is left
+        orderbook.process_order(orders::limit_order_cancel_request(7, OrderSide::Ask));
+        let oco = orders::new_oco_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Ask,
+            1,
+            2.0,
+            Trail::Fixed(0.5),
+            String::from("trader"),
+            0,
+        );
+        assert_eq!(
+            orderbook.process_order(oco),
+            vec![Err(Failed::OrderIDsExhausted)]
+        );
+        assert!(orderbook.trailing_stops().is_empty());
+
+        let result = orderbook.process_order(limit_order(OrderSide::Bid, 0.5, 1));
+        match result[0] {
+            Ok(Success::Accepted { id: 7, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert!(orderbook.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn oco_orders() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.0, 1));
+        orderbook.process_order(limit_order(OrderSide::Bid, 0.5, 10));
+
+        // take-profit at 1.5 and stop-loss at 0.75
+        let oco = orders::new_oco_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Ask,
+            4,
+            1.5,
+            Trail::Fixed(0.75),
+            String::from("trader"),
+            0,
+        )
+        .with_partial_fill_action(PartialFillAction::ReduceOther);
+        let result = orderbook.process_order(oco);
+        match result[0] {
+            Ok(Success::Accepted {
+                id: 3,
+                order_type: OrderType::TrailingStop,
+                ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+        match result[1] {
+            Ok(Success::Accepted {
+                id: 4,
+                order_type: OrderType::Limit,
+                ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.linked_order(3), Some(4));
+        assert_eq!(orderbook.linked_order(4), Some(3));
+
+        // partial fill of the take-profit reduces the stop-loss
+        let result = orderbook.process_order(orders::new_market_order_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Bid,
+            2,
+            String::from("trader"),
+            0,
+        ));
+        match result[4] {
+            Ok(Success::PartiallyFilled {
+                order_id: 4,
+                qty: 1,
+                ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+        match result[5] {
+            Ok(Success::LinkedOrderReduced {
+                id: 3,
+                side: OrderSide::Ask,
+                qty: 1,
+                remaining: 3,
+                ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.trailing_stops()[0].qty, 3);
+
+        // stop-loss fill cancels the rest of the take-profit
+        let result = orderbook.process_order(limit_order(OrderSide::Ask, 0.5, 1));
+        match result[3] {
+            Ok(Success::StopTriggered { id: 3, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        match result[4] {
+            Ok(Success::Filled {
+                order_id: 3,
+                qty: 3,
+                ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+        match result[6] {
+            Ok(Success::LinkedOrderReduced {
+                id: 4,
+                qty: 3,
+                remaining: 0,
+                ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert!(orderbook.ask_queue.get(4).is_none());
+        assert_eq!(orderbook.linked_order(3), None);
+
+        // any fill cancels the other leg
+        let oco = orders::new_oco_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Ask,
+            7,
+            0.5,
+            Trail::Fixed(0.25),
+            String::from("trader"),
+            0,
+        );
+        let result = orderbook.process_order(oco);
+        match result[2] {
+            Ok(Success::PartiallyFilled {
+                order_id: 8,
+                qty: 6,
+                ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+        match result[4] {
+            Ok(Success::LinkedOrderReduced {
+                id: 7,
+                qty: 7,
+                remaining: 0,
+                ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert!(orderbook.trailing_stops().is_empty());
+        assert_eq!(orderbook.ask_queue.get(8).unwrap().qty, 1);
+        assert_eq!(orderbook.linked_order(8), None);
+        assert!(orderbook.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn fill_constraints() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.0, 10).with_all_or_none());
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.1, 3));
+
+        // all-or-none ask doesn't block the one behind it
+        let result = orderbook.process_order(limit_order(OrderSide::Bid, 1.2, 3));
+        match result[1] {
+            Ok(Success::Filled {
+                order_id: 3,
+                qty: 3,
+                price,
+                ..
+            }) => assert_eq!(price, 1.1),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.ask_queue.get(1).unwrap().qty, 10);
+        assert!(orderbook.bid_queue.get(3).is_none());
+        assert!(orderbook.check_invariants().is_ok());
+
+        // filled by a single execution
+        let result = orderbook.process_order(orders::new_market_order_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Bid,
+            10,
+            String::from("trader"),
+            0,
+        ));
+        match result[2] {
+            Ok(Success::Filled {
+                order_id: 1,
+                qty: 10,
+                ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+
+        // minimum quantity bid is skipped by a smaller fill
+        orderbook.process_order(limit_order(OrderSide::Bid, 0.5, 6).with_min_qty(4));
+        orderbook.process_order(limit_order(OrderSide::Bid, 0.5, 2));
+        let result = orderbook.process_order(limit_order(OrderSide::Ask, 0.5, 2));
+        match result[2] {
+            Ok(Success::Filled {
+                order_id: 6,
+                qty: 2,
+                price,
+                ..
+            }) => assert_eq!(price, 0.5),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.bid_queue.get(5).unwrap().qty, 6);
+
+        // all-or-none ask which can't be executed completely at once
+        // doesn't rest across the book
+        let result =
+            orderbook.process_order(limit_order(OrderSide::Ask, 0.5, 8).with_all_or_none());
+        assert_eq!(result.len(), 2);
+        match result[1] {
+            Ok(Success::Unfilled { id: 8, qty: 8, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert!(orderbook.ask_queue.get(8).is_none());
+        assert!(orderbook.check_invariants().is_ok());
+
+        // enough for the minimum quantity
+        let result = orderbook.process_order(limit_order(OrderSide::Ask, 0.5, 7).with_min_qty(5));
+        match result[1] {
+            Ok(Success::PartiallyFilled {
+                order_id: 9,
+                qty: 6,
+                ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert!(orderbook.bid_queue.iter().next().is_none());
+        assert_eq!(orderbook.ask_queue.get(9).unwrap().qty, 1);
+
+        let result = orderbook.process_order(limit_order(OrderSide::Bid, 0.5, 3).with_min_qty(5));
+        match result[0] {
+            Err(Failed::ValidationFailed(ValidationError::BadMinQty { min_qty: 5, qty: 3 })) => (),
+            _ => panic!("unexpected events"),
+        }
+
+        // resting all-or-none ask crossed by a stored bid is dropped
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        orderbook.process_order(limit_order(OrderSide::Ask, 1.0, 10).with_all_or_none());
+        let result = orderbook.process_order(limit_order(OrderSide::Bid, 1.2, 5));
+        match result[1] {
+            Ok(Success::Unfilled {
+                id: 1,
+                side: OrderSide::Ask,
+                qty: 10,
+                ..
+            }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert!(orderbook.ask_queue.get(1).is_none());
+        assert_eq!(orderbook.bid_queue.get(2).unwrap().qty, 5);
+        assert!(orderbook.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn constrained_order_execution() {
+        set_block_timestamp(0);
+        let mut orderbook = Orderbook::new(Asset::BTC, Asset::USD);
+        for _ in 0..3 {
+            orderbook.process_order(limit_order(OrderSide::Ask, 1.0, 1));
+        }
+        orderbook.set_max_fills(2);
+
+        // matching isn't suspended by the fills limit
+        let result =
+            orderbook.process_order(limit_order(OrderSide::Bid, 1.0, 3).with_all_or_none());
+        assert!(!result
+            .iter()
+            .any(|result| matches!(*result, Ok(Success::MatchingSuspended { .. }))));
+        assert_eq!(orderbook.pending_orders(), 0);
+        assert!(orderbook.ask_queue.peek().is_none());
+        assert!(orderbook.bid_queue.peek().is_none());
+
+        // only quantity within the price band is executable
+        let mut orderbook = get_orderbook_with_band(BandAction::Reject);
+        let result =
+            orderbook.process_order(limit_order(OrderSide::Bid, 1.5, 2).with_all_or_none());
+        assert_eq!(result.len(), 2);
+        match result[1] {
+            Ok(Success::Unfilled { qty: 2, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(orderbook.ask_queue.get(2).unwrap().qty, 1);
+
+        // and the bid doesn't rest across the book
+        assert_eq!(orderbook.current_spread(), Some((0.99, 1.01)));
+        assert_eq!(orderbook.band_reference_price(), Some(1.0));
+        assert!(orderbook.check_invariants().is_ok());
+    }
 }
@@ -1,9 +1,17 @@
 use std::fmt::Debug;
 
+use super::constraints::FillConstraint;
 use super::domain::OrderSide;
+use super::oco::PartialFillAction;
+use super::pegging::Peg;
+use super::stops::Trail;
 
-#[derive(Debug)]
-pub enum OrderRequest<Asset>
+extern crate near_sdk;
+use self::near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use self::near_sdk::serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+pub enum OrderRequest<Asset, Price = f64, Qty = u128>
 where
     Asset: Debug + Clone,
 {
@@ -11,26 +19,32 @@ where
         order_asset: Asset,
         price_asset: Asset,
         side: OrderSide,
-        qty: u128,
+        qty: Qty,
         order_creator: String,
         ts: u64,
+        client_order_id: Option<String>,
     },
 
     NewLimitOrder {
         order_asset: Asset,
         price_asset: Asset,
         side: OrderSide,
-        price: f64,
-        qty: u128,
+        price: Price,
+        qty: Qty,
         order_creator: String,
         ts: u64,
+        client_order_id: Option<String>,
+        #[serde(default)]
+        hidden: bool,
+        peg: Option<Peg<Price>>,
+        constraint: Option<FillConstraint<Qty>>,
     },
 
     AmendOrder {
         id: u64,
         side: OrderSide,
-        price: f64,
-        qty: u128,
+        price: Price,
+        qty: Qty,
         ts: u64,
     },
 
@@ -39,19 +53,206 @@ where
         side: OrderSide,
         //ts: SystemTime,
     },
+
+    MassCancel {
+        order_creator: String,
+        side: Option<OrderSide>,
+        price_range: Option<(Price, Price)>,
+    },
+
+    CancelReplace {
+        id: u64,
+        side: OrderSide,
+        price: Price,
+        qty: Qty,
+        order_creator: String,
+        ts: u64,
+        client_order_id: Option<String>,
+    },
+
+    NewTrailingStop {
+        order_asset: Asset,
+        price_asset: Asset,
+        side: OrderSide,
+        qty: Qty,
+        trail: Trail<Price>,
+        limit_price: Option<Price>,
+        order_creator: String,
+        ts: u64,
+    },
+
+    NewOco {
+        order_asset: Asset,
+        price_asset: Asset,
+        side: OrderSide,
+        qty: Qty,
+        // take-profit limit order
+        price: Price,
+        // stop-loss order
+        stop: Trail<Price>,
+        stop_limit_price: Option<Price>,
+        on_partial_fill: PartialFillAction,
+        order_creator: String,
+        ts: u64,
+    },
+}
+
+impl<Asset, Price, Qty> OrderRequest<Asset, Price, Qty>
+where
+    Asset: Debug + Clone,
+{
+    /// Attach client order ID, unique per order creator, to the new order.
+    ///
+    /// Requests which don't create an order are not changed.
+    pub fn with_client_order_id(mut self, id: String) -> Self {
+        match self {
+            OrderRequest::NewMarketOrder {
+                ref mut client_order_id,
+                ..
+            }
+            | OrderRequest::NewLimitOrder {
+                ref mut client_order_id,
+                ..
+            }
+            | OrderRequest::CancelReplace {
+                ref mut client_order_id,
+                ..
+            } => *client_order_id = Some(id),
+            _ => {}
+        }
+        self
+    }
+
+    /// Make the new limit order hidden: it is not displayed in the book and
+    /// is matched after displayed orders of the same price.
+    ///
+    /// Other requests are not changed.
+    pub fn with_hidden(mut self) -> Self {
+        if let OrderRequest::NewLimitOrder { ref mut hidden, .. } = self {
+            *hidden = true;
+        }
+        self
+    }
+
+    /// Peg the new limit order: its price follows the reference price of the
+    /// book, the request price is used until the reference is available.
+    ///
+    /// Other requests are not changed.
+    pub fn with_peg(mut self, order_peg: Peg<Price>) -> Self {
+        if let OrderRequest::NewLimitOrder { ref mut peg, .. } = self {
+            *peg = Some(order_peg);
+        }
+        self
+    }
+
+    /// Make the new limit order all-or-none: it is filled completely at
+    /// once or not at all.
+    ///
+    /// Other requests are not changed.
+    pub fn with_all_or_none(self) -> Self {
+        self.with_constraint(FillConstraint::AllOrNone)
+    }
+
+    /// Set minimum quantity executed at once for the new limit order, the
+    /// last execution may be smaller if less is left.
+    ///
+    /// Other requests are not changed.
+    pub fn with_min_qty(self, min_qty: Qty) -> Self {
+        self.with_constraint(FillConstraint::MinQty(min_qty))
+    }
+
+    /// Constrain fills of the new limit order.
+    ///
+    /// Other requests are not changed.
+    pub fn with_constraint(mut self, order_constraint: FillConstraint<Qty>) -> Self {
+        if let OrderRequest::NewLimitOrder {
+            ref mut constraint, ..
+        } = self
+        {
+            *constraint = Some(order_constraint);
+        }
+        self
+    }
+
+    /// Make the new trailing stop, or the stop-loss leg of the new OCO pair,
+    /// become a limit order at `price` once triggered.
+    ///
+    /// Other requests are not changed.
+    pub fn with_limit_price(mut self, price: Price) -> Self {
+        match self {
+            OrderRequest::NewTrailingStop {
+                ref mut limit_price,
+                ..
+            }
+            | OrderRequest::NewOco {
+                stop_limit_price: ref mut limit_price,
+                ..
+            } => *limit_price = Some(price),
+            _ => {}
+        }
+        self
+    }
+
+    /// Set what a partial fill of one leg of the new OCO pair does to the
+    /// other one, `PartialFillAction::CancelOther` by default.
+    ///
+    /// Other requests are not changed.
+    pub fn with_partial_fill_action(mut self, action: PartialFillAction) -> Self {
+        if let OrderRequest::NewOco {
+            ref mut on_partial_fill,
+            ..
+        } = self
+        {
+            *on_partial_fill = action;
+        }
+        self
+    }
+
+    pub fn client_order_id(&self) -> Option<&String> {
+        match *self {
+            OrderRequest::NewMarketOrder {
+                ref client_order_id,
+                ..
+            }
+            | OrderRequest::NewLimitOrder {
+                ref client_order_id,
+                ..
+            }
+            | OrderRequest::CancelReplace {
+                ref client_order_id,
+                ..
+            } => client_order_id.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Quantity of the new order, `None` for requests which don't create one
+    pub fn new_order_qty(&self) -> Option<Qty>
+    where
+        Qty: Copy,
+    {
+        match *self {
+            OrderRequest::NewMarketOrder { qty, .. }
+            | OrderRequest::NewLimitOrder { qty, .. }
+            | OrderRequest::CancelReplace { qty, .. }
+            | OrderRequest::NewTrailingStop { qty, .. }
+            | OrderRequest::NewOco { qty, .. } => Some(qty),
+            _ => None,
+        }
+    }
 }
 
 /* Constructors */
 
 /// Create request for the new market order
-pub fn new_market_order_request<Asset>(
+pub fn new_market_order_request<Asset, Price, Qty>(
     order_asset: Asset,
     price_asset: Asset,
     side: OrderSide,
-    qty: u128,
+    qty: Qty,
     order_creator: String,
     ts: u64,
-) -> OrderRequest<Asset>
+) -> OrderRequest<Asset, Price, Qty>
 where
     Asset: Debug + Clone,
 {
@@ -62,19 +263,20 @@ where
         side,
         order_creator,
         ts,
+        client_order_id: None,
     }
 }
 
 /// Create request for the new limit order
-pub fn new_limit_order_request<Asset>(
+pub fn new_limit_order_request<Asset, Price, Qty>(
     order_asset: Asset,
     price_asset: Asset,
     side: OrderSide,
-    price: f64,
-    qty: u128,
+    price: Price,
+    qty: Qty,
     order_creator: String,
     ts: u64,
-) -> OrderRequest<Asset>
+) -> OrderRequest<Asset, Price, Qty>
 where
     Asset: Debug + Clone,
 {
@@ -86,6 +288,10 @@ where
         qty,
         order_creator,
         ts,
+        client_order_id: None,
+        hidden: false,
+        peg: None,
+        constraint: None,
     }
 }
 
@@ -93,13 +299,13 @@ where
 ///
 /// Note: do not change order side!
 /// Instead cancel existing order and create a new one.
-pub fn amend_order_request<Asset>(
+pub fn amend_order_request<Asset, Price, Qty>(
     id: u64,
     side: OrderSide,
-    price: f64,
-    qty: u128,
+    price: Price,
+    qty: Qty,
     ts: u64,
-) -> OrderRequest<Asset>
+) -> OrderRequest<Asset, Price, Qty>
 where
     Asset: Debug + Clone,
 {
@@ -113,9 +319,115 @@ where
 }
 
 /// Create request for cancelling active limit order
-pub fn limit_order_cancel_request<Asset>(order_id: u64, side: OrderSide) -> OrderRequest<Asset>
+pub fn limit_order_cancel_request<Asset, Price, Qty>(
+    order_id: u64,
+    side: OrderSide,
+) -> OrderRequest<Asset, Price, Qty>
 where
     Asset: Debug + Clone,
 {
     OrderRequest::CancelOrder { id: order_id, side }
 }
+
+/// Create request for replacing active limit order with a new one atomically.
+///
+/// Replacement fails if the order is already filled or cancelled.
+pub fn cancel_replace_request<Asset, Price, Qty>(
+    id: u64,
+    side: OrderSide,
+    price: Price,
+    qty: Qty,
+    order_creator: String,
+    ts: u64,
+) -> OrderRequest<Asset, Price, Qty>
+where
+    Asset: Debug + Clone,
+{
+    OrderRequest::CancelReplace {
+        id,
+        side,
+        price,
+        qty,
+        order_creator,
+        ts,
+        client_order_id: None,
+    }
+}
+
+/// Create request for the new trailing stop order.
+///
+/// Once triggered, it becomes a market order, or a limit order if its price
+/// is set with `with_limit_price`.
+pub fn new_trailing_stop_request<Asset, Price, Qty>(
+    order_asset: Asset,
+    price_asset: Asset,
+    side: OrderSide,
+    qty: Qty,
+    trail: Trail<Price>,
+    order_creator: String,
+    ts: u64,
+) -> OrderRequest<Asset, Price, Qty>
+where
+    Asset: Debug + Clone,
+{
+    OrderRequest::NewTrailingStop {
+        order_asset,
+        price_asset,
+        side,
+        qty,
+        trail,
+        limit_price: None,
+        order_creator,
+        ts,
+    }
+}
+
+/// Create request for a one-cancels-other pair: a take-profit limit order at
+/// `price` and a stop-loss order, both for `qty` on the same side.
+///
+/// A fill of one leg cancels or reduces the other one. The stop-loss leg
+/// becomes a market order once triggered, unless `with_limit_price` is set.
+pub fn new_oco_request<Asset, Price, Qty>(
+    order_asset: Asset,
+    price_asset: Asset,
+    side: OrderSide,
+    qty: Qty,
+    price: Price,
+    stop: Trail<Price>,
+    order_creator: String,
+    ts: u64,
+) -> OrderRequest<Asset, Price, Qty>
+where
+    Asset: Debug + Clone,
+{
+    OrderRequest::NewOco {
+        order_asset,
+        price_asset,
+        side,
+        qty,
+        price,
+        stop,
+        stop_limit_price: None,
+        on_partial_fill: PartialFillAction::CancelOther,
+        order_creator,
+        ts,
+    }
+}
+
+/// Create request for cancelling all active limit orders of the creator.
+///
+/// Optionally only orders on one side and within inclusive price range (min, max).
+pub fn mass_cancel_request<Asset, Price, Qty>(
+    order_creator: String,
+    side: Option<OrderSide>,
+    price_range: Option<(Price, Price)>,
+) -> OrderRequest<Asset, Price, Qty>
+where
+    Asset: Debug + Clone,
+{
+    OrderRequest::MassCancel {
+        order_creator,
+        side,
+        price_range,
+    }
+}
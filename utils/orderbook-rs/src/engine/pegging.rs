@@ -0,0 +1,111 @@
+use super::domain::OrderSide;
+use super::numeric::Price;
+
+extern crate near_sdk;
+use self::near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use self::near_sdk::serde::{Deserialize, Serialize};
+
+/// Book price followed by a pegged order
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
+pub enum PegReference {
+    /// Best price of the order's own side
+    Primary,
+    /// Best price of the opposite side
+    Market,
+    /// Middle of the spread
+    Mid,
+}
+
+/// Price instruction of a pegged limit order
+#[derive(
+    Debug, Copy, Clone, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
+pub struct Peg<Price = f64> {
+    pub reference: PegReference,
+    /// Non-negative distance from the reference price away from the opposite
+    /// side: subtracted for bids, added for asks
+    pub offset: Price,
+    /// Bids are never priced above the limit, asks never below it
+    pub limit: Option<Price>,
+}
+
+impl<P: Price> Peg<P> {
+    pub fn new(reference: PegReference, offset: P, limit: Option<P>) -> Self {
+        Peg {
+            reference,
+            offset,
+            limit,
+        }
+    }
+
+    /// Order price for the best bid and ask of the book, `None` if the
+    /// reference price is not available
+    pub fn price(&self, side: OrderSide, bid: Option<P>, ask: Option<P>) -> Option<P> {
+        let (primary, market) = match side {
+            OrderSide::Bid => (bid, ask),
+            OrderSide::Ask => (ask, bid),
+        };
+        let reference = match self.reference {
+            PegReference::Primary => primary?,
+            PegReference::Market => market?,
+            PegReference::Mid => bid?.midpoint(ask?),
+        };
+
+        let price = match side {
+            OrderSide::Bid => reference.checked_sub(self.offset)?,
+            OrderSide::Ask => reference.checked_add(self.offset)?,
+        };
+        let price = match (side, self.limit) {
+            (OrderSide::Bid, Some(limit)) if price > limit => limit,
+            (OrderSide::Ask, Some(limit)) if price < limit => limit,
+            _ => price,
+        };
+
+        if price.is_positive() {
+            Some(price)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peg_prices() {
+        let bid = Some(0.5);
+        let ask = Some(1.5);
+
+        let primary = Peg::new(PegReference::Primary, 0.25, None);
+        assert_eq!(primary.price(OrderSide::Bid, bid, ask), Some(0.25));
+        assert_eq!(primary.price(OrderSide::Ask, bid, ask), Some(1.75));
+        assert_eq!(primary.price(OrderSide::Bid, None, ask), None);
+
+        let market = Peg::new(PegReference::Market, 0.0, None);
+        assert_eq!(market.price(OrderSide::Bid, bid, ask), Some(1.5));
+        assert_eq!(market.price(OrderSide::Ask, bid, None), Some(0.5));
+        assert_eq!(market.price(OrderSide::Ask, None, ask), None);
+
+        let mid = Peg::new(PegReference::Mid, 0.0, None);
+        assert_eq!(mid.price(OrderSide::Ask, bid, ask), Some(1.0));
+        assert_eq!(mid.price(OrderSide::Ask, bid, None), None);
+
+        // capped by the limit
+        let capped = Peg::new(PegReference::Mid, 0.0, Some(0.75));
+        assert_eq!(capped.price(OrderSide::Bid, bid, ask), Some(0.75));
+        assert_eq!(capped.price(OrderSide::Ask, bid, ask), Some(1.0));
+
+        // not priced at zero or below
+        assert_eq!(
+            Peg::new(PegReference::Primary, 0.5, None).price(OrderSide::Bid, bid, ask),
+            None
+        );
+        let ticks = Peg::new(PegReference::Primary, 5u64, None);
+        assert_eq!(ticks.price(OrderSide::Bid, Some(3), Some(10)), None);
+        assert_eq!(ticks.price(OrderSide::Ask, Some(3), Some(10)), Some(15));
+    }
+}
@@ -0,0 +1,75 @@
+use super::numeric::Price;
+
+extern crate near_sdk;
+use self::near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use self::near_sdk::serde::Serialize;
+
+/// Price used as the center of the band
+#[derive(Debug, Copy, Clone, PartialEq, BorshDeserialize, BorshSerialize, Serialize)]
+pub enum ReferencePrice {
+    /// Last trade price, falls back to mid price before the first trade
+    LastTrade,
+    /// Middle of the current spread
+    Mid,
+}
+
+/// What to do with an order which would execute outside of the band
+#[derive(Debug, Copy, Clone, PartialEq, BorshDeserialize, BorshSerialize, Serialize)]
+pub enum BandAction {
+    /// Reject the rest of the order, keep trading
+    Reject,
+    /// Reject the rest of the order and halt trading for the cooldown period
+    Halt,
+}
+
+/// Price band (circuit breaker) configuration
+#[derive(Debug, Copy, Clone, BorshDeserialize, BorshSerialize, Serialize)]
+pub struct PriceBand {
+    /// Max allowed deviation from the reference price, in percents
+    pub max_deviation_pct: f64,
+    pub reference: ReferencePrice,
+    pub action: BandAction,
+    /// Trading halt duration (nanoseconds, same units as block timestamp)
+    pub cooldown: u64,
+}
+
+impl PriceBand {
+    pub fn new(
+        max_deviation_pct: f64,
+        reference: ReferencePrice,
+        action: BandAction,
+        cooldown: u64,
+    ) -> Self {
+        PriceBand {
+            max_deviation_pct,
+            reference,
+            action,
+            cooldown,
+        }
+    }
+
+    /// Check if execution price stays within the band around reference price
+    pub fn within_band<P: Price>(&self, reference_price: P, price: P) -> bool {
+        if !reference_price.is_positive() {
+            return true;
+        }
+        let reference_price = reference_price.to_f64();
+        let deviation = (price.to_f64() - reference_price).abs() / reference_price * 100.0;
+        deviation <= self.max_deviation_pct
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn band_limits() {
+        let band = PriceBand::new(10.0, ReferencePrice::Mid, BandAction::Reject, 0);
+
+        assert!(band.within_band(1.0, 1.09));
+        assert!(band.within_band(1.0, 0.91));
+        assert!(!band.within_band(1.0, 1.11));
+        assert!(!band.within_band(1.0, 0.89));
+    }
+}
@@ -0,0 +1,82 @@
+use super::numeric::{self, Quantity};
+use super::orderbook::{OrderProcessingResult, Success};
+
+extern crate near_sdk;
+use self::near_sdk::serde::{Deserialize, Serialize};
+
+/// Would-be outcome of a new order, see `Orderbook::quote`
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Quote<Price = f64, Qty = u128> {
+    // none when the request is rejected
+    pub order_id: Option<u64>,
+    pub filled_qty: u128,
+    // volume weighted price of the order fills
+    pub average_price: Option<f64>,
+    // left resting in the book, or not executed for market orders
+    pub remaining_qty: u128,
+    // in the asset received by the order side
+    pub fee: i128,
+    pub results: OrderProcessingResult<Price, Qty>,
+}
+
+impl<Price, Qty> Quote<Price, Qty>
+where
+    Price: numeric::Price,
+    Qty: Quantity,
+{
+    /// Summarize fills of the order of `qty` accepted first in `results`.
+    ///
+    /// Fills of other orders, e.g. makers or triggered stops, are ignored.
+    pub fn new(qty: Qty, results: OrderProcessingResult<Price, Qty>) -> Self {
+        let order_id = results
+            .iter()
+            .filter_map(|result| match *result {
+                Ok(Success::Accepted { id, .. }) => Some(id),
+                _ => None,
+            })
+            .next();
+
+        let (mut filled_qty, mut volume, mut fee) = (0u128, 0f64, 0i128);
+        for result in &results {
+            match *result {
+                Ok(Success::Filled {
+                    order_id: fill_order_id,
+                    price,
+                    qty: fill_qty,
+                    fee: fill_fee,
+                    ..
+                })
+                | Ok(Success::PartiallyFilled {
+                    order_id: fill_order_id,
+                    price,
+                    qty: fill_qty,
+                    fee: fill_fee,
+                    ..
+                }) if Some(fill_order_id) == order_id => {
+                    let fill_qty: u128 = fill_qty.into();
+                    filled_qty += fill_qty;
+                    volume += price.to_f64() * fill_qty as f64;
+                    fee += fill_fee;
+                }
+                _ => {}
+            }
+        }
+
+        Quote {
+            order_id,
+            filled_qty,
+            average_price: if filled_qty > 0 {
+                Some(volume / filled_qty as f64)
+            } else {
+                None
+            },
+            remaining_qty: if order_id.is_some() {
+                qty.into() - filled_qty
+            } else {
+                0
+            },
+            fee,
+            results,
+        }
+    }
+}
@@ -0,0 +1,477 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+
+use super::domain::Order;
+use super::invariants::InvariantViolation;
+use super::matching::{Fifo, MatchingPolicy};
+use super::numeric::{self, Quantity};
+use super::orderbook::{
+    Failed, OrderProcessingResult, Orderbook, MAX_SEQUENCE_ID, MIN_SEQUENCE_ID,
+};
+use super::orders::OrderRequest;
+use super::sequence;
+
+extern crate near_sdk;
+use self::near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+
+/// Order book with its asset pair
+type Market<Asset, Policy, Price, Qty> = ((Asset, Asset), Orderbook<Asset, Policy, Price, Qty>);
+
+/// Order books of several asset pairs in one engine instance.
+///
+/// New orders are routed by their pair, order IDs are unique across all
+/// markets, so amends and cancels are routed by ID.
+#[derive(Default, BorshDeserialize, BorshSerialize, Clone)]
+pub struct MarketRegistry<Asset, Policy = Fifo, Price = f64, Qty = u128>
+where
+    Price: numeric::Price,
+    Qty: Quantity,
+{
+    // there are few markets, so a list is enough and pairs need no hashing
+    markets: Vec<Market<Asset, Policy, Price, Qty>>,
+    seq: sequence::TradeSequence,
+}
+
+impl<Asset, Policy, Price, Qty> MarketRegistry<Asset, Policy, Price, Qty>
+where
+    Asset: Debug + Clone + Copy + Eq + PartialEq,
+    Policy: MatchingPolicy,
+    Price: numeric::Price,
+    Qty: Quantity,
+{
+    /// Create registry without markets
+    pub fn new() -> Self {
+        MarketRegistry {
+            markets: vec![],
+            seq: sequence::new_sequence_gen(MIN_SEQUENCE_ID, MAX_SEQUENCE_ID),
+        }
+    }
+
+    /// Open new market for the pair, returns false if it is already listed
+    pub fn list_market(
+        &mut self,
+        order_asset: Asset,
+        price_asset: Asset,
+        matching_policy: Policy,
+    ) -> bool {
+        if self.market(order_asset, price_asset).is_some() {
+            return false;
+        }
+        let orderbook = Orderbook::with_policy(order_asset, price_asset, matching_policy);
+        self.markets.push(((order_asset, price_asset), orderbook));
+        true
+    }
+
+    /// Close the market, returning its book with orders left to settle
+    pub fn delist_market(
+        &mut self,
+        order_asset: Asset,
+        price_asset: Asset,
+    ) -> Option<Orderbook<Asset, Policy, Price, Qty>> {
+        let position = self
+            .markets
+            .iter()
+            .position(|market| market.0 == (order_asset, price_asset))?;
+        Some(self.markets.remove(position).1)
+    }
+
+    /// Pairs of listed markets, in listing order
+    pub fn markets(&self) -> Vec<(Asset, Asset)> {
+        self.markets.iter().map(|market| market.0).collect()
+    }
+
+    pub fn market(
+        &self,
+        order_asset: Asset,
+        price_asset: Asset,
+    ) -> Option<&Orderbook<Asset, Policy, Price, Qty>> {
+        self.markets
+            .iter()
+            .find(|market| market.0 == (order_asset, price_asset))
+            .map(|market| &market.1)
+    }
+
+    /// Access the book to configure it (price band, fills limit, auction)
+    pub fn market_mut(
+        &mut self,
+        order_asset: Asset,
+        price_asset: Asset,
+    ) -> Option<&mut Orderbook<Asset, Policy, Price, Qty>> {
+        self.markets
+            .iter_mut()
+            .find(|market| market.0 == (order_asset, price_asset))
+            .map(|market| &mut market.1)
+    }
+
+    /// Route request to its market.
+    ///
+    /// Mass cancel is applied to every market.
+    pub fn process_order(
+        &mut self,
+        order: OrderRequest<Asset, Price, Qty>,
+    ) -> OrderProcessingResult<Price, Qty> {
+        let (order_asset, price_asset) = match order {
+            OrderRequest::NewMarketOrder {
+                order_asset,
+                price_asset,
+                ..
+            }
+            | OrderRequest::NewLimitOrder {
+                order_asset,
+                price_asset,
+                ..
+            }
+            | OrderRequest::NewTrailingStop {
+                order_asset,
+                price_asset,
+                ..
+            }
+            | OrderRequest::NewOco {
+                order_asset,
+                price_asset,
+                ..
+            } => (order_asset, price_asset),
+
+            OrderRequest::AmendOrder { id, .. } | OrderRequest::CancelOrder { id, .. } => {
+                return match self.market_of_order(id) {
+                    Some(orderbook) => orderbook.process_order(order),
+                    None => vec![Err(Failed::OrderNotFound(id))],
+                };
+            }
+
+            OrderRequest::CancelReplace { id, .. } => {
+                if !self.is_id_in_use(id) {
+                    return vec![Err(Failed::OrderNotFound(id))];
+                }
+                if let Some(client_order_id) = order.client_order_id() {
+                    if self.is_client_order_id_used(&order, client_order_id) {
+                        return vec![Err(Failed::DuplicateClientOrderID(client_order_id.clone()))];
+                    }
+                }
+                let order_id = match self.next_order_ids(1) {
+                    Some(order_ids) => order_ids[0],
+                    None => return vec![Err(Failed::OrderIDsExhausted)],
+                };
+                let orderbook = self.market_of_order(id).unwrap();
+                return orderbook.process_order_with_id(order, order_id);
+            }
+
+            OrderRequest::MassCancel { .. } => {
+                let mut proc_result = vec![];
+                for market in &mut self.markets {
+                    proc_result.extend(market.1.process_order(order.clone()));
+                }
+                return proc_result;
+            }
+        };
+
+        if let Some(client_order_id) = order.client_order_id() {
+            if self.is_client_order_id_used(&order, client_order_id) {
+                return vec![Err(Failed::DuplicateClientOrderID(client_order_id.clone()))];
+            }
+        }
+
+        // OCO pair places two orders
+        let count = match order {
+            OrderRequest::NewOco { .. } => 2,
+            _ => 1,
+        };
+        let order_ids = match self.next_order_ids(count) {
+            Some(order_ids) => order_ids,
+            None => return vec![Err(Failed::OrderIDsExhausted)],
+        };
+        match self.market_mut(order_asset, price_asset) {
+            Some(orderbook) => orderbook.process_order_with_ids(order, &order_ids),
+            None => vec![Err(Failed::UnknownMarket)],
+        }
+    }
+
+    /// Active order by its creator and client order ID, in any market
+    pub fn client_order(
+        &self,
+        order_creator: &str,
+        client_order_id: &str,
+    ) -> Option<&Order<Asset, Price, Qty>> {
+        self.markets
+            .iter()
+            .filter_map(|market| market.1.client_order(order_creator, client_order_id))
+            .next()
+    }
+
+    /// Verify consistency of every book and uniqueness of order IDs across markets
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        let mut ids = HashSet::new();
+
+        for market in &self.markets {
+            let orderbook = &market.1;
+            orderbook.check_invariants()?;
+
+            let orders = orderbook.bid_queue.iter().chain(orderbook.ask_queue.iter());
+            for order in orders {
+                if !ids.insert(order.order_id) {
+                    return Err(InvariantViolation::DuplicateOrderID(order.order_id));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /* Internal methods */
+
+    /// Next `count` sequence IDs which are not used in any market, `None`
+    /// if there are not enough of them
+    fn next_order_ids(&mut self, count: usize) -> Option<Vec<u64>> {
+        let mut order_ids = vec![];
+        // a single round of the sequence gives distinct IDs
+        for _ in MIN_SEQUENCE_ID..=MAX_SEQUENCE_ID {
+            if order_ids.len() == count {
+                break;
+            }
+            let order_id = self.seq.next_id();
+            if !self.is_id_in_use(order_id) {
+                order_ids.push(order_id);
+            }
+        }
+        if order_ids.len() == count {
+            Some(order_ids)
+        } else {
+            None
+        }
+    }
+
+    fn is_id_in_use(&self, order_id: u64) -> bool {
+        self.markets
+            .iter()
+            .any(|market| market.1.is_id_in_use(order_id))
+    }
+
+    /// Client order IDs are unique per creator across all markets
+    fn is_client_order_id_used(
+        &self,
+        order: &OrderRequest<Asset, Price, Qty>,
+        client_order_id: &str,
+    ) -> bool {
+        let order_creator = match *order {
+            OrderRequest::NewMarketOrder {
+                ref order_creator, ..
+            }
+            | OrderRequest::NewLimitOrder {
+                ref order_creator, ..
+            }
+            | OrderRequest::CancelReplace {
+                ref order_creator, ..
+            } => order_creator,
+            _ => return false,
+        };
+        self.markets.iter().any(|market| {
+            market
+                .1
+                .is_client_order_id_used(order_creator, client_order_id)
+        })
+    }
+
+    fn market_of_order(
+        &mut self,
+        order_id: u64,
+    ) -> Option<&mut Orderbook<Asset, Policy, Price, Qty>> {
+        self.markets
+            .iter_mut()
+            .find(|market| market.1.is_id_in_use(order_id))
+            .map(|market| &mut market.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::domain::OrderSide;
+    use super::super::orderbook::Success;
+    use super::super::orders;
+    use super::super::stops::Trail;
+    use super::super::test_utils::set_block_timestamp;
+    use super::*;
+
+    #[derive(PartialEq, Eq, Debug, Copy, Clone, BorshDeserialize, BorshSerialize)]
+    pub enum Asset {
+        USD,
+        BTC,
+        ETH,
+    }
+
+    fn limit_order(
+        order_asset: Asset,
+        side: OrderSide,
+        price: f64,
+        qty: u128,
+    ) -> OrderRequest<Asset> {
+        orders::new_limit_order_request(
+            order_asset,
+            Asset::USD,
+            side,
+            price,
+            qty,
+            String::from("trader"),
+            0,
+        )
+    }
+
+    fn get_registry() -> MarketRegistry<Asset> {
+        let mut registry = MarketRegistry::new();
+        assert!(registry.list_market(Asset::BTC, Asset::USD, Fifo));
+        assert!(registry.list_market(Asset::ETH, Asset::USD, Fifo));
+        assert!(!registry.list_market(Asset::ETH, Asset::USD, Fifo));
+        registry
+    }
+
+    #[test]
+    fn routing_by_pair() {
+        set_block_timestamp(0);
+        let mut registry = get_registry();
+        assert_eq!(
+            registry.markets(),
+            vec![(Asset::BTC, Asset::USD), (Asset::ETH, Asset::USD)]
+        );
+
+        registry.process_order(limit_order(Asset::BTC, OrderSide::Ask, 100.0, 1));
+        registry.process_order(limit_order(Asset::ETH, OrderSide::Ask, 10.0, 1));
+        let result = registry.process_order(limit_order(Asset::ETH, OrderSide::Bid, 10.0, 1));
+        match result[1] {
+            Ok(Success::Filled { order_id: 3, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+
+        let btc = registry.market(Asset::BTC, Asset::USD).unwrap();
+        assert_eq!(btc.ask_queue.peek().unwrap().order_id, 1);
+        assert!(btc.bid_queue.peek().is_none());
+
+        let result = registry.process_order(orders::new_market_order_request(
+            Asset::USD,
+            Asset::BTC,
+            OrderSide::Bid,
+            1,
+            String::from("trader"),
+            0,
+        ));
+        assert_eq!(result, vec![Err(Failed::UnknownMarket)]);
+        assert_eq!(registry.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn global_order_ids() {
+        set_block_timestamp(0);
+        let mut registry = get_registry();
+        registry.process_order(limit_order(Asset::BTC, OrderSide::Ask, 100.0, 1));
+        registry.process_order(limit_order(Asset::ETH, OrderSide::Ask, 10.0, 1));
+
+        // cancel is routed by ID to the market of the order
+        let result = registry.process_order(orders::limit_order_cancel_request(2, OrderSide::Ask));
+        match result[0] {
+            Ok(Success::Cancelled { id: 2, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert!(registry
+            .market(Asset::ETH, Asset::USD)
+            .unwrap()
+            .ask_queue
+            .peek()
+            .is_none());
+
+        let result = registry.process_order(orders::limit_order_cancel_request(2, OrderSide::Ask));
+        assert_eq!(result, vec![Err(Failed::OrderNotFound(2))]);
+
+        // replacement gets a new global ID
+        let result = registry.process_order(orders::cancel_replace_request(
+            1,
+            OrderSide::Ask,
+            101.0,
+            1,
+            String::from("trader"),
+            0,
+        ));
+        match result[1] {
+            Ok(Success::Accepted { id: 3, .. }) => (),
+            _ => panic!("unexpected events"),
+        }
+        assert_eq!(registry.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn delisting() {
+        set_block_timestamp(0);
+        let mut registry = get_registry();
+        registry.process_order(limit_order(Asset::BTC, OrderSide::Ask, 100.0, 1));
+        registry.process_order(limit_order(Asset::ETH, OrderSide::Ask, 10.0, 1));
+
+        let result = registry.process_order(orders::mass_cancel_request(
+            String::from("trader"),
+            None,
+            None,
+        ));
+        assert_eq!(result.len(), 2);
+
+        let orderbook = registry.delist_market(Asset::BTC, Asset::USD).unwrap();
+        assert!(orderbook.ask_queue.peek().is_none());
+        assert!(registry.delist_market(Asset::BTC, Asset::USD).is_none());
+        assert_eq!(registry.markets(), vec![(Asset::ETH, Asset::USD)]);
+
+        let result = registry.process_order(limit_order(Asset::BTC, OrderSide::Bid, 99.0, 1));
+        assert_eq!(result, vec![Err(Failed::UnknownMarket)]);
+    }
+
+    #[test]
+    fn client_order_ids_across_markets() {
+        set_block_timestamp(0);
+        let mut registry = get_registry();
+        let request = limit_order(Asset::BTC, OrderSide::Ask, 100.0, 1)
+            .with_client_order_id(String::from("a1"));
+        registry.process_order(request);
+        assert_eq!(registry.client_order("trader", "a1").unwrap().order_id, 1);
+
+        let request = limit_order(Asset::ETH, OrderSide::Ask, 10.0, 1)
+            .with_client_order_id(String::from("a1"));
+        assert_eq!(
+            registry.process_order(request),
+            vec![Err(Failed::DuplicateClientOrderID(String::from("a1")))]
+        );
+        assert!(registry
+            .market(Asset::ETH, Asset::USD)
+            .unwrap()
+            .ask_queue
+            .peek()
+            .is_none());
+    }
+
+    #[test]
+    fn order_ids_exhausted() {
+        set_block_timestamp(0);
+        let mut registry = get_registry();
+        for _ in MIN_SEQUENCE_ID..MAX_SEQUENCE_ID {
+            registry.process_order(limit_order(Asset::BTC, OrderSide::Ask, 100.0, 1));
+        }
+
+        // OCO pair needs two IDs, but only one is left
+        let oco = orders::new_oco_request(
+            Asset::ETH,
+            Asset::USD,
+            OrderSide::Ask,
+            1,
+            20.0,
+            Trail::Fixed(5.0),
+            String::from("trader"),
+            0,
+        );
+        assert_eq!(
+            registry.process_order(oco),
+            vec![Err(Failed::OrderIDsExhausted)]
+        );
+
+        let result = registry.process_order(limit_order(Asset::ETH, OrderSide::Ask, 10.0, 1));
+        match result[0] {
+            Ok(Success::Accepted { id, .. }) => assert_eq!(id, MAX_SEQUENCE_ID),
+            _ => panic!("unexpected events"),
+        }
+        let result = registry.process_order(limit_order(Asset::ETH, OrderSide::Ask, 10.0, 1));
+        assert_eq!(result, vec![Err(Failed::OrderIDsExhausted)]);
+        assert_eq!(registry.check_invariants(), Ok(()));
+    }
+}
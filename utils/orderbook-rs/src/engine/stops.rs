@@ -0,0 +1,129 @@
+use super::domain::OrderSide;
+use super::numeric::Price;
+
+extern crate near_sdk;
+use self::near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use self::near_sdk::serde::{Deserialize, Serialize};
+
+/// Distance of a trailing stop from the best trade price
+#[derive(
+    Debug, Copy, Clone, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize,
+)]
+pub enum Trail<Price = f64> {
+    /// Fixed price amount
+    Amount(Price),
+    /// Percents of the best trade price
+    Percent(f64),
+    /// Fixed stop price, the stop doesn't trail
+    Fixed(Price),
+}
+
+/// Stop order waiting for the market to retrace.
+///
+/// Ask (sell) stops follow the highest trade price and trigger when the price
+/// falls by the trail, bid (buy) stops follow the lowest one and trigger when
+/// the price rises by the trail.
+#[derive(Debug, Clone, PartialEq, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+pub struct TrailingStop<Price = f64, Qty = u128> {
+    pub order_id: u64,
+    pub side: OrderSide,
+    pub qty: Qty,
+    pub trail: Trail<Price>,
+    /// Price of the triggered limit order, market order if `None`
+    pub limit_price: Option<Price>,
+    pub order_creator: String,
+    /// Best trade price since the stop was placed
+    pub best_price: Option<Price>,
+    pub ts: u64,
+}
+
+impl<P: Price, Qty> TrailingStop<P, Qty> {
+    /// Move the best price after a trade
+    pub fn track(&mut self, price: P) {
+        let better = match (self.side, self.best_price) {
+            (_, None) => true,
+            (OrderSide::Ask, Some(best_price)) => price > best_price,
+            (OrderSide::Bid, Some(best_price)) => price < best_price,
+        };
+        if better {
+            self.best_price = Some(price);
+        }
+    }
+
+    /// Check if the trade price has retraced from the best one by the trail
+    pub fn is_triggered(&self, price: P) -> bool {
+        match (self.side, self.trail, self.best_price) {
+            (OrderSide::Ask, Trail::Fixed(stop_price), _) => price <= stop_price,
+            (OrderSide::Bid, Trail::Fixed(stop_price), _) => price >= stop_price,
+            // nothing to follow before the first trade
+            (_, _, None) => false,
+            (OrderSide::Ask, Trail::Amount(amount), Some(best_price)) => {
+                match best_price.checked_sub(amount) {
+                    Some(stop_price) => price <= stop_price,
+                    None => false,
+                }
+            }
+            (OrderSide::Bid, Trail::Amount(amount), Some(best_price)) => {
+                match best_price.checked_add(amount) {
+                    Some(stop_price) => price >= stop_price,
+                    None => false,
+                }
+            }
+            (OrderSide::Ask, Trail::Percent(percent), Some(best_price)) => {
+                price.to_f64() <= best_price.to_f64() * (1.0 - percent / 100.0)
+            }
+            (OrderSide::Bid, Trail::Percent(percent), Some(best_price)) => {
+                price.to_f64() >= best_price.to_f64() * (1.0 + percent / 100.0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop(side: OrderSide, trail: Trail) -> TrailingStop {
+        TrailingStop {
+            order_id: 1,
+            side,
+            qty: 1,
+            trail,
+            limit_price: None,
+            order_creator: String::from("trader"),
+            best_price: Some(10.0),
+            ts: 0,
+        }
+    }
+
+    #[test]
+    fn trailing() {
+        let mut sell = stop(OrderSide::Ask, Trail::Amount(1.0));
+        sell.track(12.0);
+        sell.track(11.0);
+        assert_eq!(sell.best_price, Some(12.0));
+        assert!(!sell.is_triggered(11.5));
+        assert!(sell.is_triggered(11.0));
+
+        let mut buy = stop(OrderSide::Bid, Trail::Percent(10.0));
+        buy.track(8.0);
+        buy.track(9.0);
+        assert_eq!(buy.best_price, Some(8.0));
+        assert!(!buy.is_triggered(8.5));
+        assert!(buy.is_triggered(9.0));
+
+        // nothing to follow before the first trade
+        let mut idle = stop(OrderSide::Ask, Trail::Amount(1.0));
+        idle.best_price = None;
+        assert!(!idle.is_triggered(1.0));
+        idle.track(5.0);
+        assert!(idle.is_triggered(4.0));
+
+        // plain stop
+        let mut fixed = stop(OrderSide::Ask, Trail::Fixed(9.0));
+        fixed.best_price = None;
+        fixed.track(12.0);
+        assert!(!fixed.is_triggered(9.5));
+        assert!(fixed.is_triggered(9.0));
+    }
+}
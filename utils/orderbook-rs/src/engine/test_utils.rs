@@ -0,0 +1,25 @@
+extern crate near_sdk;
+use self::near_sdk::{testing_env, MockedBlockchain, VMContext};
+
+/// Set up mocked blockchain, the engine takes event times from the block timestamp
+pub fn set_block_timestamp(block_timestamp: u64) {
+    let context = VMContext {
+        current_account_id: String::from("market"),
+        signer_account_id: String::from("trader"),
+        signer_account_pk: vec![0, 1, 2],
+        predecessor_account_id: String::from("trader"),
+        input: vec![],
+        block_index: 0,
+        block_timestamp,
+        epoch_height: 0,
+        account_balance: 0,
+        account_locked_balance: 0,
+        storage_usage: 0,
+        attached_deposit: 0,
+        prepaid_gas: 10u64.pow(18),
+        random_seed: vec![0, 1, 2],
+        is_view: false,
+        output_data_receivers: vec![],
+    };
+    testing_env!(context);
+}
@@ -1,17 +1,203 @@
+use std::fmt;
 use std::fmt::Debug;
 
+use super::constraints::FillConstraint;
+use super::numeric::{self, Quantity};
 use super::orders::OrderRequest;
+use super::pegging::Peg;
+use super::stops::Trail;
+
+const MAX_CLIENT_ORDER_ID_LEN: usize = 64;
 
 extern crate near_sdk;
 use self::near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use self::near_sdk::serde::ser::{Serialize, SerializeStruct, Serializer};
+use self::near_sdk::serde::Deserialize;
 
 /// Validation errors
-const ERR_BAD_ORDER_ASSET: &str = "bad order asset";
-const ERR_BAD_PRICE_ASSET: &str = "bad price asset";
-const ERR_BAD_PRICE_VALUE: &str = "price must be non-negative";
-const ERR_BAD_QUANTITY_VALUE: &str = "quantity must be non-negative";
-const ERR_BAD_SEQ_ID: &str = "order ID out of range";
-const ERR_BAD_ORDER_CREATOR: &str = "order_creator cam't be empty";
+///
+/// Codes returned by `code()` and `name()` are stable and safe to match on.
+/// Deserialized by the `error` field, `code` and `message` are ignored.
+#[derive(Debug, Clone, PartialEq, BorshDeserialize, BorshSerialize, Deserialize)]
+#[serde(tag = "error", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ValidationError {
+    BadOrderAsset { expected: String, actual: String },
+    BadPriceAsset { expected: String, actual: String },
+    BadPriceValue { price: f64 },
+    BadQuantityValue { qty: u128 },
+    BadSequenceId { id: u64, min: u64, max: u64 },
+    EmptyOrderCreator,
+    BadPriceRange { min_price: f64, max_price: f64 },
+    BadClientOrderId { client_order_id: String },
+    BadPeg { offset: f64, limit: Option<f64> },
+    BadTrail { trail: f64 },
+    BadMinQty { min_qty: u128, qty: u128 },
+    BadFeeSchedule { maker_bps: i32, taker_bps: i32 },
+}
+
+impl ValidationError {
+    /// Stable numeric code
+    pub fn code(&self) -> u16 {
+        match self {
+            ValidationError::BadOrderAsset { .. } => 1001,
+            ValidationError::BadPriceAsset { .. } => 1002,
+            ValidationError::BadPriceValue { .. } => 1003,
+            ValidationError::BadQuantityValue { .. } => 1004,
+            ValidationError::BadSequenceId { .. } => 1005,
+            ValidationError::EmptyOrderCreator => 1006,
+            ValidationError::BadPriceRange { .. } => 1007,
+            ValidationError::BadClientOrderId { .. } => 1008,
+            ValidationError::BadPeg { .. } => 1009,
+            ValidationError::BadTrail { .. } => 1010,
+            ValidationError::BadMinQty { .. } => 1011,
+            ValidationError::BadFeeSchedule { .. } => 1012,
+        }
+    }
+
+    /// Stable string code
+    pub fn name(&self) -> &'static str {
+        match self {
+            ValidationError::BadOrderAsset { .. } => "BAD_ORDER_ASSET",
+            ValidationError::BadPriceAsset { .. } => "BAD_PRICE_ASSET",
+            ValidationError::BadPriceValue { .. } => "BAD_PRICE_VALUE",
+            ValidationError::BadQuantityValue { .. } => "BAD_QUANTITY_VALUE",
+            ValidationError::BadSequenceId { .. } => "BAD_SEQUENCE_ID",
+            ValidationError::EmptyOrderCreator => "EMPTY_ORDER_CREATOR",
+            ValidationError::BadPriceRange { .. } => "BAD_PRICE_RANGE",
+            ValidationError::BadClientOrderId { .. } => "BAD_CLIENT_ORDER_ID",
+            ValidationError::BadPeg { .. } => "BAD_PEG",
+            ValidationError::BadTrail { .. } => "BAD_TRAIL",
+            ValidationError::BadMinQty { .. } => "BAD_MIN_QTY",
+            ValidationError::BadFeeSchedule { .. } => "BAD_FEE_SCHEDULE",
+        }
+    }
+
+    fn bad_order_asset<Asset: Debug>(expected: Asset, actual: Asset) -> Self {
+        ValidationError::BadOrderAsset {
+            expected: format!("{:?}", expected),
+            actual: format!("{:?}", actual),
+        }
+    }
+
+    fn bad_price_asset<Asset: Debug>(expected: Asset, actual: Asset) -> Self {
+        ValidationError::BadPriceAsset {
+            expected: format!("{:?}", expected),
+            actual: format!("{:?}", actual),
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::BadOrderAsset { expected, actual } => {
+                write!(f, "bad order asset: expected {}, got {}", expected, actual)
+            }
+            ValidationError::BadPriceAsset { expected, actual } => {
+                write!(f, "bad price asset: expected {}, got {}", expected, actual)
+            }
+            ValidationError::BadPriceValue { price } => {
+                write!(f, "price must be positive, got {}", price)
+            }
+            ValidationError::BadQuantityValue { qty } => {
+                write!(f, "quantity must be positive, got {}", qty)
+            }
+            ValidationError::BadSequenceId { id, min, max } => {
+                write!(f, "order ID {} out of range [{}, {}]", id, min, max)
+            }
+            ValidationError::EmptyOrderCreator => write!(f, "order_creator can't be empty"),
+            ValidationError::BadPriceRange {
+                min_price,
+                max_price,
+            } => write!(
+                f,
+                "price range must be non-negative and ordered, got [{}, {}]",
+                min_price, max_price
+            ),
+            ValidationError::BadClientOrderId { client_order_id } => write!(
+                f,
+                "client order ID must be 1 to {} bytes long, got {:?}",
+                MAX_CLIENT_ORDER_ID_LEN, client_order_id
+            ),
+            ValidationError::BadPeg { offset, limit } => write!(
+                f,
+                "peg offset must be non-negative and limit positive, got {}, {:?}",
+                offset, limit
+            ),
+            ValidationError::BadTrail { trail } => write!(
+                f,
+                "trail must be positive, percents below 100, got {}",
+                trail
+            ),
+            ValidationError::BadMinQty { min_qty, qty } => write!(
+                f,
+                "minimum quantity must be positive and not above quantity {}, got {}",
+                qty, min_qty
+            ),
+            ValidationError::BadFeeSchedule {
+                maker_bps,
+                taker_bps,
+            } => write!(
+                f,
+                "fee rates must be within 10000 bps and a rebate within the taker fee, got {}, {}",
+                maker_bps, taker_bps
+            ),
+        }
+    }
+}
+
+/// Serialized as a flat object: code, error name, message and details, e.g.
+/// `{"code":1004,"error":"BAD_QUANTITY_VALUE","message":"...","qty":0}`
+impl Serialize for ValidationError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("ValidationError", 6)?;
+        state.serialize_field("code", &self.code())?;
+        state.serialize_field("error", self.name())?;
+        state.serialize_field("message", &self.to_string())?;
+        match self {
+            ValidationError::BadOrderAsset { expected, actual }
+            | ValidationError::BadPriceAsset { expected, actual } => {
+                state.serialize_field("expected", expected)?;
+                state.serialize_field("actual", actual)?;
+            }
+            ValidationError::BadPriceValue { price } => state.serialize_field("price", price)?,
+            ValidationError::BadQuantityValue { qty } => state.serialize_field("qty", qty)?,
+            ValidationError::BadSequenceId { id, min, max } => {
+                state.serialize_field("id", id)?;
+                state.serialize_field("min", min)?;
+                state.serialize_field("max", max)?;
+            }
+            ValidationError::EmptyOrderCreator => {}
+            ValidationError::BadPriceRange {
+                min_price,
+                max_price,
+            } => {
+                state.serialize_field("min_price", min_price)?;
+                state.serialize_field("max_price", max_price)?;
+            }
+            ValidationError::BadClientOrderId { client_order_id } => {
+                state.serialize_field("client_order_id", client_order_id)?;
+            }
+            ValidationError::BadPeg { offset, limit } => {
+                state.serialize_field("offset", offset)?;
+                state.serialize_field("limit", limit)?;
+            }
+            ValidationError::BadTrail { trail } => state.serialize_field("trail", trail)?,
+            ValidationError::BadMinQty { min_qty, qty } => {
+                state.serialize_field("min_qty", min_qty)?;
+                state.serialize_field("qty", qty)?;
+            }
+            ValidationError::BadFeeSchedule {
+                maker_bps,
+                taker_bps,
+            } => {
+                state.serialize_field("maker_bps", maker_bps)?;
+                state.serialize_field("taker_bps", taker_bps)?;
+            }
+        }
+        state.end()
+    }
+}
 
 /* Validators */
 
@@ -41,8 +227,44 @@ where
         }
     }
 
-    pub fn validate(&self, request: &OrderRequest<Asset>) -> Result<(), &str> {
-        match &*request {
+    pub fn validate<Price, Qty>(
+        &self,
+        request: &OrderRequest<Asset, Price, Qty>,
+    ) -> Result<(), ValidationError>
+    where
+        Price: numeric::Price,
+        Qty: Quantity,
+    {
+        if let Some(client_order_id) = request.client_order_id() {
+            if client_order_id.is_empty() || client_order_id.len() > MAX_CLIENT_ORDER_ID_LEN {
+                return Err(ValidationError::BadClientOrderId {
+                    client_order_id: client_order_id.clone(),
+                });
+            }
+        }
+
+        if let OrderRequest::NewLimitOrder {
+            peg: Some(ref peg), ..
+        } = *request
+        {
+            self.validate_peg(peg)?;
+        }
+
+        if let OrderRequest::NewLimitOrder {
+            qty,
+            constraint: Some(FillConstraint::MinQty(min_qty)),
+            ..
+        } = *request
+        {
+            if min_qty.is_zero() || min_qty > qty {
+                return Err(ValidationError::BadMinQty {
+                    min_qty: min_qty.into(),
+                    qty: qty.into(),
+                });
+            }
+        }
+
+        match request {
             OrderRequest::NewMarketOrder {
                 order_asset,
                 price_asset,
@@ -50,6 +272,7 @@ where
                 qty,
                 order_creator,
                 ts: _ts,
+                client_order_id: _,
             } => self.validate_market(*order_asset, *price_asset, *qty, order_creator.clone()),
 
             OrderRequest::NewLimitOrder {
@@ -60,6 +283,10 @@ where
                 qty,
                 order_creator,
                 ts: _ts,
+                client_order_id: _,
+                hidden: _,
+                peg: _,
+                constraint: _,
             } => self.validate_limit(*order_asset, *price_asset, *price, *qty, order_creator.clone()),
 
             OrderRequest::AmendOrder {
@@ -71,89 +298,365 @@ where
             } => self.validate_amend(*id, *price, *qty),
 
             OrderRequest::CancelOrder { id, side: _side } => self.validate_cancel(*id),
+
+            OrderRequest::MassCancel {
+                order_creator,
+                side: _side,
+                price_range,
+            } => self.validate_mass_cancel(order_creator.clone(), *price_range),
+
+            OrderRequest::CancelReplace {
+                id,
+                side: _side,
+                price,
+                qty,
+                order_creator,
+                ts: _ts,
+                client_order_id: _,
+            } => self.validate_cancel_replace(*id, *price, *qty, order_creator.clone()),
+
+            OrderRequest::NewTrailingStop {
+                order_asset,
+                price_asset,
+                side: _side,
+                qty,
+                trail,
+                limit_price,
+                order_creator,
+                ts: _ts,
+            } => self.validate_trailing_stop(
+                *order_asset,
+                *price_asset,
+                *qty,
+                *trail,
+                *limit_price,
+                order_creator.clone(),
+            ),
+
+            OrderRequest::NewOco {
+                order_asset,
+                price_asset,
+                side: _side,
+                qty,
+                price,
+                stop,
+                stop_limit_price,
+                on_partial_fill: _,
+                order_creator,
+                ts: _ts,
+            } => {
+                self.validate_limit(
+                    *order_asset,
+                    *price_asset,
+                    *price,
+                    *qty,
+                    order_creator.clone(),
+                )?;
+                self.validate_stop(*stop, *stop_limit_price)
+            }
         }
     }
 
     /* Internal validators */
 
-    fn validate_market(
+    fn validate_market<Qty: Quantity>(
         &self,
         order_asset: Asset,
         price_asset: Asset,
-        qty: u128,
+        qty: Qty,
         order_creator: String,
-    ) -> Result<(), &str> {
+    ) -> Result<(), ValidationError> {
         if self.orderbook_order_asset != order_asset {
-            return Err(ERR_BAD_ORDER_ASSET);
+            return Err(ValidationError::bad_order_asset(
+                self.orderbook_order_asset,
+                order_asset,
+            ));
         }
 
         if self.orderbook_price_asset != price_asset {
-            return Err(ERR_BAD_PRICE_ASSET);
+            return Err(ValidationError::bad_price_asset(
+                self.orderbook_price_asset,
+                price_asset,
+            ));
         }
 
-        if qty <= 0 {
-            return Err(ERR_BAD_QUANTITY_VALUE);
+        if qty.is_zero() {
+            return Err(ValidationError::BadQuantityValue { qty: qty.into() });
         }
 
-        if order_creator == "" {
-            return Err(ERR_BAD_ORDER_CREATOR);
+        if order_creator.is_empty() {
+            return Err(ValidationError::EmptyOrderCreator);
         }
 
         Ok(())
     }
 
-    fn validate_limit(
+    fn validate_limit<Price: numeric::Price, Qty: Quantity>(
         &self,
         order_asset: Asset,
         price_asset: Asset,
-        price: f64,
-        qty: u128,
+        price: Price,
+        qty: Qty,
         order_creator: String,
-    ) -> Result<(), &str> {
+    ) -> Result<(), ValidationError> {
         if self.orderbook_order_asset != order_asset {
-            return Err(ERR_BAD_ORDER_ASSET);
+            return Err(ValidationError::bad_order_asset(
+                self.orderbook_order_asset,
+                order_asset,
+            ));
         }
 
         if self.orderbook_price_asset != price_asset {
-            return Err(ERR_BAD_PRICE_ASSET);
+            return Err(ValidationError::bad_price_asset(
+                self.orderbook_price_asset,
+                price_asset,
+            ));
+        }
+
+        if !price.is_positive() {
+            return Err(ValidationError::BadPriceValue {
+                price: price.to_f64(),
+            });
         }
 
-        if price <= 0.0 {
-            return Err(ERR_BAD_PRICE_VALUE);
+        if qty.is_zero() {
+            return Err(ValidationError::BadQuantityValue { qty: qty.into() });
         }
 
-        if qty <= 0 {
-            return Err(ERR_BAD_QUANTITY_VALUE);
+        if order_creator.is_empty() {
+            return Err(ValidationError::EmptyOrderCreator);
         }
 
-        if order_creator == "" {
-            return Err(ERR_BAD_ORDER_CREATOR);
+        Ok(())
+    }
+
+    fn validate_peg<Price: numeric::Price>(&self, peg: &Peg<Price>) -> Result<(), ValidationError> {
+        let offset = peg.offset.to_f64();
+        let bad_limit = peg.limit.is_some_and(|limit| !limit.is_positive());
+        if offset.is_nan() || offset < 0.0 || bad_limit {
+            return Err(ValidationError::BadPeg {
+                offset,
+                limit: peg.limit.map(|limit| limit.to_f64()),
+            });
         }
 
         Ok(())
     }
 
-    fn validate_amend(&self, id: u64, price: f64, qty: u128) -> Result<(), &str> {
+    fn validate_amend<Price: numeric::Price, Qty: Quantity>(
+        &self,
+        id: u64,
+        price: Price,
+        qty: Qty,
+    ) -> Result<(), ValidationError> {
         if self.min_sequence_id > id || self.max_sequence_id < id {
-            return Err(ERR_BAD_SEQ_ID);
+            return Err(ValidationError::BadSequenceId {
+                id,
+                min: self.min_sequence_id,
+                max: self.max_sequence_id,
+            });
         }
 
-        if price <= 0.0 {
-            return Err(ERR_BAD_PRICE_VALUE);
+        if !price.is_positive() {
+            return Err(ValidationError::BadPriceValue {
+                price: price.to_f64(),
+            });
         }
 
-        if qty <= 0{
-            return Err(ERR_BAD_QUANTITY_VALUE);
+        if qty.is_zero() {
+            return Err(ValidationError::BadQuantityValue { qty: qty.into() });
         }
 
         Ok(())
     }
 
-    fn validate_cancel(&self, id: u64) -> Result<(), &str> {
+    fn validate_cancel(&self, id: u64) -> Result<(), ValidationError> {
         if self.min_sequence_id > id || self.max_sequence_id < id {
-            return Err(ERR_BAD_SEQ_ID);
+            return Err(ValidationError::BadSequenceId {
+                id,
+                min: self.min_sequence_id,
+                max: self.max_sequence_id,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn validate_cancel_replace<Price: numeric::Price, Qty: Quantity>(
+        &self,
+        id: u64,
+        price: Price,
+        qty: Qty,
+        order_creator: String,
+    ) -> Result<(), ValidationError> {
+        self.validate_cancel(id)?;
+        self.validate_limit(
+            self.orderbook_order_asset,
+            self.orderbook_price_asset,
+            price,
+            qty,
+            order_creator,
+        )
+    }
+
+    fn validate_trailing_stop<Price: numeric::Price, Qty: Quantity>(
+        &self,
+        order_asset: Asset,
+        price_asset: Asset,
+        qty: Qty,
+        trail: Trail<Price>,
+        limit_price: Option<Price>,
+        order_creator: String,
+    ) -> Result<(), ValidationError> {
+        self.validate_market(order_asset, price_asset, qty, order_creator)?;
+        self.validate_stop(trail, limit_price)
+    }
+
+    fn validate_stop<Price: numeric::Price>(
+        &self,
+        trail: Trail<Price>,
+        limit_price: Option<Price>,
+    ) -> Result<(), ValidationError> {
+        let valid_trail = match trail {
+            Trail::Amount(price) | Trail::Fixed(price) => price.is_positive(),
+            Trail::Percent(percent) => percent > 0.0 && percent < 100.0,
+        };
+        if !valid_trail {
+            return Err(ValidationError::BadTrail {
+                trail: match trail {
+                    Trail::Amount(price) | Trail::Fixed(price) => price.to_f64(),
+                    Trail::Percent(percent) => percent,
+                },
+            });
+        }
+
+        if let Some(limit_price) = limit_price {
+            if !limit_price.is_positive() {
+                return Err(ValidationError::BadPriceValue {
+                    price: limit_price.to_f64(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_mass_cancel<Price: numeric::Price>(
+        &self,
+        order_creator: String,
+        price_range: Option<(Price, Price)>,
+    ) -> Result<(), ValidationError> {
+        if order_creator.is_empty() {
+            return Err(ValidationError::EmptyOrderCreator);
+        }
+
+        if let Some((min_price, max_price)) = price_range {
+            if min_price.to_f64() < 0.0 || min_price > max_price {
+                return Err(ValidationError::BadPriceRange {
+                    min_price: min_price.to_f64(),
+                    max_price: max_price.to_f64(),
+                });
+            }
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::domain::OrderSide;
+    use super::super::orders;
+    use super::super::pegging::PegReference;
+    use super::*;
+
+    #[derive(PartialEq, Eq, Debug, Copy, Clone)]
+    pub enum Asset {
+        USD,
+        BTC,
+    }
+
+    #[test]
+    fn typed_errors() {
+        let validator = OrderRequestValidator::new(Asset::BTC, Asset::USD, 1, 1000);
+
+        let request: OrderRequest<Asset> = orders::new_limit_order_request(
+            Asset::USD,
+            Asset::USD,
+            OrderSide::Bid,
+            1.0,
+            1,
+            String::from("trader"),
+            0,
+        );
+        assert_eq!(
+            validator.validate(&request),
+            Err(ValidationError::BadOrderAsset {
+                expected: String::from("BTC"),
+                actual: String::from("USD"),
+            })
+        );
+
+        let request: OrderRequest<Asset> = orders::limit_order_cancel_request(1001, OrderSide::Ask);
+        let err = validator.validate(&request).unwrap_err();
+        assert_eq!(err.code(), 1005);
+        assert_eq!(err.name(), "BAD_SEQUENCE_ID");
+
+        let request: OrderRequest<Asset> = orders::new_limit_order_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Bid,
+            1.0,
+            1,
+            String::from("trader"),
+            0,
+        );
+        let err = validator
+            .validate(&request.with_client_order_id(String::new()))
+            .unwrap_err();
+        assert_eq!(err.code(), 1008);
+
+        let request: OrderRequest<Asset> = orders::new_limit_order_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Bid,
+            1.0,
+            1,
+            String::from("trader"),
+            0,
+        );
+        let peg = Peg::new(PegReference::Mid, -0.01, None);
+        assert_eq!(
+            validator.validate(&request.with_peg(peg)),
+            Err(ValidationError::BadPeg {
+                offset: -0.01,
+                limit: None,
+            })
+        );
+
+        let request: OrderRequest<Asset> = orders::new_trailing_stop_request(
+            Asset::BTC,
+            Asset::USD,
+            OrderSide::Ask,
+            1,
+            Trail::Percent(100.0),
+            String::from("trader"),
+            0,
+        );
+        assert_eq!(validator.validate(&request).unwrap_err().code(), 1010);
+    }
+
+    #[test]
+    fn json_codes() {
+        let err = ValidationError::BadQuantityValue { qty: 0 };
+        assert_eq!(
+            near_sdk::serde_json::to_string(&err).unwrap(),
+            "{\"code\":1004,\"error\":\"BAD_QUANTITY_VALUE\",\
+             \"message\":\"quantity must be positive, got 0\",\"qty\":0}"
+        );
+
+        let json = near_sdk::serde_json::to_string(&ValidationError::EmptyOrderCreator).unwrap();
+        let decoded: ValidationError = near_sdk::serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, ValidationError::EmptyOrderCreator);
+    }
+}
@@ -1,7 +1,23 @@
 mod engine;
 extern crate near_sdk;
 
-pub use engine::domain::{OrderSide};
+pub use engine::constraints::FillConstraint;
+pub use engine::domain::{Order, OrderSide, TradingPhase};
+pub use engine::fees::{FeeAsset, FeeSchedule};
+pub use engine::impact::{DepthPoint, Impact};
+pub use engine::invariants::{check_fills, InvariantViolation};
+pub use engine::market_data::{book_checksum, BookDelta, BookSnapshot, CHECKSUM_LEVELS};
+pub use engine::matching::{Fifo, MatchingPolicy, ProRata, Rounding, TopOrderProRata};
+pub use engine::numeric::{Price, Quantity};
+pub use engine::oco::PartialFillAction;
 pub use engine::order_queues::{OrderQueue, OrderIndex};
-pub use engine::orderbook::{Failed, OrderProcessingResult, Orderbook, Success};
+pub use engine::orderbook::{
+    BatchMode, Failed, OrderProcessingResult, Orderbook, Success, SCHEMA_VERSION,
+};
+pub use engine::pegging::{Peg, PegReference};
+pub use engine::price_band::{BandAction, PriceBand, ReferencePrice};
+pub use engine::quote::Quote;
+pub use engine::registry::MarketRegistry;
+pub use engine::stops::{Trail, TrailingStop};
+pub use engine::validation::ValidationError;
 pub use engine::orders;